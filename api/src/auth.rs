@@ -0,0 +1,173 @@
+use std::sync::Arc;
+
+use common::{
+    auth::{ApiKey, find_api_key},
+    error::StringError,
+};
+use deadpool_postgres::GenericClient;
+use poem::{
+    Endpoint, Middleware, Request, Result,
+    error::{InternalServerError, TooManyRequests, Unauthorized},
+    http::header,
+};
+use tokio_postgres::types::ToSql;
+use uuid::Uuid;
+
+use crate::AppData;
+
+/// Header carrying the API key, as an alternative to `Authorization: Bearer`.
+const API_KEY_HEADER: &str = "x-api-key";
+
+/// Rejects requests without a valid API key and makes the key available to
+/// handlers as `Data<&ApiKey>`.
+pub struct ApiKeyAuth;
+
+impl<E: Endpoint> Middleware<E> for ApiKeyAuth {
+    type Output = ApiKeyAuthEndpoint<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        ApiKeyAuthEndpoint(ep)
+    }
+}
+
+pub struct ApiKeyAuthEndpoint<E>(E);
+
+impl<E: Endpoint> Endpoint for ApiKeyAuthEndpoint<E> {
+    type Output = E::Output;
+
+    async fn call(&self, mut req: Request) -> Result<Self::Output> {
+        let data = req
+            .data::<Arc<AppData>>()
+            .cloned()
+            .expect("app data is added outside of authentication");
+        // Already looked up by the rate limiter, if it runs.
+        if req.extensions().get::<ApiKey>().is_some() {
+            return self.0.call(req).await;
+        }
+        let Some(key) = presented_key(&req) else {
+            return Err(Unauthorized(StringError::new("an API key is required")));
+        };
+        let api_key = find_api_key(&data.db, key)
+            .await
+            .map_err(|_| InternalServerError(StringError::new("could not able to query")))?
+            .ok_or(Unauthorized(StringError::new("invalid API key")))?;
+
+        req.extensions_mut().insert(api_key);
+        self.0.call(req).await
+    }
+}
+
+/// The API key sent with a request, if any.
+pub fn presented_key(req: &Request) -> Option<&str> {
+    let headers = req.headers();
+    if let Some(key) = headers.get(API_KEY_HEADER) {
+        return key.to_str().ok();
+    }
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::trim)
+}
+
+/// Checks that `key` may create `count` more submissions under its per-minute
+/// and concurrency quotas. Call it in the transaction that inserts them: the
+/// key's row stays locked until then, so concurrent requests of the same key
+/// are counted one after another.
+pub async fn reserve_submissions(
+    client: &impl GenericClient,
+    key: &ApiKey,
+    count: usize,
+) -> Result<()> {
+    let quotas = &key.quotas;
+    if quotas.submissions_per_minute.is_none() && quotas.max_concurrent_submissions.is_none() {
+        return Ok(());
+    }
+
+    let id = key.uuid();
+    let params: &[&(dyn ToSql + Sync)] = &[&id];
+    // Locked by a statement of its own, so that the counts are read after
+    // the requests that held the lock before have committed.
+    client
+        .execute("SELECT 1 FROM api_keys WHERE id = $1 FOR UPDATE", params)
+        .await
+        .map_err(|_| InternalServerError(StringError::new("could not able to query")))?;
+    let row = client
+        .query_one(
+            r#"
+            SELECT
+                (SELECT count(*) FROM submissions
+                    WHERE api_key_id = $1
+                    AND created_at > now() - interval '1 minute')
+                + (SELECT count(*) FROM api_key_sessions
+                    WHERE api_key_id = $1
+                    AND started_at > now() - interval '1 minute') AS recent,
+                (SELECT count(*) FROM submissions
+                    WHERE api_key_id = $1
+                    AND status IN ('inqueue', 'processing'))
+                + (SELECT count(*) FROM api_key_sessions
+                    WHERE api_key_id = $1
+                    AND finished_at IS NULL AND expires_at > now()) AS unfinished
+            "#,
+            params,
+        )
+        .await
+        .map_err(|_| InternalServerError(StringError::new("could not able to query")))?;
+
+    let count = count as i64;
+    if let Some(per_minute) = quotas.submissions_per_minute
+        && row.get::<_, i64>("recent") + count > per_minute as i64
+    {
+        return Err(TooManyRequests(StringError::new(&format!(
+            "the API key may create at most {} submissions per minute",
+            per_minute
+        ))));
+    }
+    if let Some(concurrent) = quotas.max_concurrent_submissions
+        && row.get::<_, i64>("unfinished") + count > concurrent as i64
+    {
+        return Err(TooManyRequests(StringError::new(&format!(
+            "the API key may have at most {} submissions queued or running",
+            concurrent
+        ))));
+    }
+    Ok(())
+}
+
+/// Reserves session `id` of `key` under the same quotas as a submission. It
+/// counts towards them until [`finish_session`] or, should that never be
+/// called, for `duration` seconds.
+pub async fn reserve_session(data: &AppData, key: &ApiKey, id: Uuid, duration: f64) -> Result<()> {
+    let quotas = &key.quotas;
+    if quotas.submissions_per_minute.is_none() && quotas.max_concurrent_submissions.is_none() {
+        return Ok(());
+    }
+
+    let mut client = data.db.pool.get().await.map_err(InternalServerError)?;
+    let tx = client.transaction().await.map_err(InternalServerError)?;
+    reserve_submissions(&tx, key, 1).await?;
+    let params: &[&(dyn ToSql + Sync)] = &[&id, &key.uuid(), &duration];
+    tx.execute(
+        r#"
+        INSERT INTO api_key_sessions (id, api_key_id, expires_at)
+        VALUES ($1, $2, now() + make_interval(secs => $3))
+        "#,
+        params,
+    )
+    .await
+    .map_err(|_| InternalServerError(StringError::new("could not able to query")))?;
+    tx.commit().await.map_err(InternalServerError)?;
+    Ok(())
+}
+
+/// Stops session `id` from counting as running.
+pub async fn finish_session(data: &AppData, id: Uuid) -> Result<()> {
+    data.db
+        .execute(
+            "UPDATE api_key_sessions SET finished_at = now() WHERE id = $1",
+            &[&id],
+        )
+        .await
+        .map_err(|_| InternalServerError(StringError::new("could not able to query")))?;
+    Ok(())
+}
@@ -0,0 +1,90 @@
+use common::{error::StringError, model::SubmissionStatus};
+use deadpool_postgres::GenericClient;
+use poem::{Result, error::InternalServerError};
+use sha2::{Digest, Sha256};
+use tokio_postgres::types::ToSql;
+use uuid::Uuid;
+
+use crate::submissions::{PreparedSubmission, nats_submission};
+
+/// Verdicts that are taken over by identical submissions. Time limits, runtime
+/// errors and internal errors can depend on the machine and its load, so
+/// those submissions always run.
+const CACHEABLE_STATUSES: &[SubmissionStatus] = &[
+    SubmissionStatus::Accepted,
+    SubmissionStatus::WrongAnswer,
+    SubmissionStatus::CompilationError,
+];
+
+/// Hash of everything the worker judges a submission by: language and its
+/// commands, source, options, inputs, expected outputs, limits and the tests
+/// (by the problem version), checker and interactor of its problem, so that
+/// changing any of them misses the cache. `None` for submissions with network access, whose result may depend
+/// on the outside world.
+pub fn content_hash(prepared: &PreparedSubmission) -> Option<Vec<u8>> {
+    if prepared.new_submission.enable_network == Some(true) {
+        return None;
+    }
+    let mut submission = nats_submission(Uuid::nil(), prepared);
+    submission.callback_url = None;
+    let json = serde_json::to_vec(&submission).ok()?;
+    Some(Sha256::digest(&json).to_vec())
+}
+
+/// Finds the latest finished submission of API key `api_key_id` with the same
+/// content hash whose verdict can be reused. Only the key's own submissions
+/// are considered, so that a verdict never tells it about another key's code.
+pub async fn find_cached(
+    client: &impl GenericClient,
+    content_hash: &[u8],
+    api_key_id: Uuid,
+) -> Result<Option<Uuid>> {
+    let params: &[&(dyn ToSql + Sync)] = &[&content_hash, &CACHEABLE_STATUSES, &api_key_id];
+    let row = client
+        .query_opt(
+            r#"
+            SELECT id FROM submissions
+            WHERE content_hash = $1 AND status = ANY($2) AND redacted_at IS NULL
+            AND api_key_id = $3
+            ORDER BY finished_at DESC
+            LIMIT 1
+            "#,
+            params,
+        )
+        .await
+        .map_err(|_| InternalServerError(StringError::new("could not able to query")))?;
+    Ok(row.map(|row| row.get("id")))
+}
+
+/// Finishes submission `id` with the verdict of submission `cached` and links
+/// it there.
+pub async fn copy_verdict(client: &impl GenericClient, id: Uuid, cached: Uuid) -> Result<()> {
+    let params: &[&(dyn ToSql + Sync)] = &[&id, &cached];
+    client
+        .execute(
+            r#"
+            UPDATE submissions SET
+                status = cached.status,
+                stdout = cached.stdout,
+                stderr = cached.stderr,
+                compile_output = cached.compile_output,
+                message = cached.message,
+                exit_code = cached.exit_code,
+                exit_signal = cached.exit_signal,
+                time = cached.time,
+                wall_time = cached.wall_time,
+                memory = cached.memory,
+                score = cached.score,
+                group_results = cached.group_results,
+                stress_result = cached.stress_result,
+                finished_at = now(),
+                cached_from = cached.id
+            FROM submissions cached
+            WHERE submissions.id = $1 AND cached.id = $2
+            "#,
+            params,
+        )
+        .await
+        .map_err(|_| InternalServerError(StringError::new("could not able to query")))?;
+    Ok(())
+}
@@ -1,10 +1,13 @@
 use anyhow::{Context, Result, anyhow};
-use common::model::Language;
+use common::{
+    model::{Language, NewSubmission},
+    nats::is_subject_token,
+};
 use config::{Config, File};
 use serde::Deserialize;
 use std::env;
 use std::fs;
-use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Debug, Deserialize, Clone)]
 #[serde(default)]
@@ -16,6 +19,18 @@ pub struct SubmissionDefaults {
     pub enable_network: bool,
 }
 
+impl SubmissionDefaults {
+    pub fn apply(&self, submission: &mut NewSubmission) {
+        submission.cpu_time_limit.get_or_insert(self.cpu_time_limit);
+        submission
+            .wall_time_limit
+            .get_or_insert(self.wall_time_limit);
+        submission.memory_limit.get_or_insert(self.memory_limit);
+        submission.number_of_runs.get_or_insert(self.number_of_runs);
+        submission.enable_network.get_or_insert(self.enable_network);
+    }
+}
+
 impl Default for SubmissionDefaults {
     fn default() -> Self {
         Self {
@@ -28,7 +43,63 @@ impl Default for SubmissionDefaults {
     }
 }
 
-#[derive(Debug, Deserialize)]
+/// What `DELETE /submissions` does with a submission.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum DeletionPolicy {
+    /// Removes the row.
+    #[default]
+    Delete,
+    /// Keeps the row with its verdict but clears the code, inputs and outputs.
+    Redact,
+}
+
+impl DeletionPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeletionPolicy::Delete => "deleted",
+            DeletionPolicy::Redact => "redacted",
+        }
+    }
+}
+
+/// What the rate limiter does with requests while Redis cannot be reached.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureMode {
+    /// Lets them through unlimited.
+    #[default]
+    Open,
+    /// Rejects them with `503`.
+    Closed,
+}
+
+/// Token bucket limits on requests, per API key or, without one, per client IP.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub enabled: bool,
+    pub redis_url: String,
+    /// Rate at which the bucket refills.
+    pub requests_per_second: f64,
+    /// Size of the bucket, i.e. how many requests may arrive at once.
+    pub burst: u32,
+    pub failure_mode: FailureMode,
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            redis_url: "redis://127.0.0.1/".to_string(),
+            requests_per_second: 10.0,
+            burst: 20,
+            failure_mode: FailureMode::default(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Default)]
 #[serde(default)]
 struct RawLanguage {
     pub name: String,
@@ -41,21 +112,6 @@ struct RawLanguage {
     pub allow_network: bool,
 }
 
-impl Default for RawLanguage {
-    fn default() -> Self {
-        Self {
-            name: String::new(),
-            source_file: String::new(),
-            file_extension: String::new(),
-            compile_cmd: None,
-            compile_cmd_file: None,
-            run_cmd: None,
-            run_cmd_file: None,
-            allow_network: false,
-        }
-    }
-}
-
 impl RawLanguage {
     pub fn into_resolved(self) -> Result<Language> {
         let compile_cmd = match (self.compile_cmd, self.compile_cmd_file) {
@@ -88,17 +144,89 @@ impl RawLanguage {
     }
 }
 
-#[derive(Debug, Deserialize, Default)]
+#[derive(Debug, Deserialize)]
 #[serde(default)]
 struct RawAppConfig {
     pub submission_defaults: SubmissionDefaults,
     pub languages: Vec<RawLanguage>,
+    pub wait_timeout: f64,
+    pub session_wall_time_limit: f64,
+    pub session_output_limit: u64,
+    pub idempotency_ttl: f64,
+    pub idempotency_lease: f64,
+    pub outbox_poll_interval: f64,
+    pub reconcile_interval: f64,
+    pub reconcile_after: f64,
+    pub reap_interval: f64,
+    pub reap_grace: f64,
+    pub heartbeat_timeout: f64,
+    pub max_attempts: u32,
+    pub deletion_policy: DeletionPolicy,
+    pub rate_limit: RateLimitConfig,
+}
+
+impl Default for RawAppConfig {
+    fn default() -> Self {
+        Self {
+            submission_defaults: SubmissionDefaults::default(),
+            languages: Vec::new(),
+            wait_timeout: 10.0,
+            session_wall_time_limit: 60.0,
+            session_output_limit: 1024 * 1024,
+            idempotency_ttl: 24.0 * 60.0 * 60.0,
+            idempotency_lease: 60.0,
+            outbox_poll_interval: 1.0,
+            reconcile_interval: 60.0,
+            reconcile_after: 5.0 * 60.0,
+            reap_interval: 30.0,
+            reap_grace: 60.0,
+            heartbeat_timeout: 60.0,
+            max_attempts: 3,
+            deletion_policy: DeletionPolicy::default(),
+            rate_limit: RateLimitConfig::default(),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct AppConfig {
     pub submission_defaults: SubmissionDefaults,
     pub languages: Vec<Language>,
+    /// Longest time in seconds that `wait=true` holds a request open.
+    pub wait_timeout: Duration,
+    /// Wall time limit in seconds of a session that does not ask for one.
+    /// Sessions wait on their client, so it is well above a submission's.
+    pub session_wall_time_limit: f64,
+    /// Bytes of stdout and stderr together a session may send before its
+    /// program is killed.
+    pub session_output_limit: u64,
+    /// How long in seconds a response is replayed for its `Idempotency-Key`.
+    pub idempotency_ttl: Duration,
+    /// How long in seconds a request holds its `Idempotency-Key` before a
+    /// retry may take it over, in case the request never completed. Longer
+    /// than `wait_timeout`.
+    pub idempotency_lease: Duration,
+    /// Longest time in seconds the outbox relay sleeps when not woken.
+    pub outbox_poll_interval: Duration,
+    /// Time in seconds between checks for queued submissions without a queue
+    /// message.
+    pub reconcile_interval: Duration,
+    /// Time in seconds a submission stays queued without a queue message
+    /// before it is queued again.
+    pub reconcile_after: Duration,
+    /// Time in seconds between checks for submissions abandoned by a worker.
+    pub reap_interval: Duration,
+    /// Time in seconds a submission may run past its wall time limit before it
+    /// counts as abandoned, provided its heartbeat expired too.
+    pub reap_grace: Duration,
+    /// Time in seconds after which the heartbeat of a running submission
+    /// expires. Well above the workers' `heartbeat_interval`.
+    pub heartbeat_timeout: Duration,
+    /// Attempts of a submission before an abandoned one is failed instead of
+    /// queued again.
+    pub max_attempts: u32,
+    pub deletion_policy: DeletionPolicy,
+    pub rate_limit: RateLimitConfig,
 }
 
 impl AppConfig {
@@ -121,14 +249,56 @@ pub fn load_config() -> Result<AppConfig> {
         .try_deserialize()
         .context("Failed to deserialize AppConfig")?;
 
+    let rate_limit = raw.rate_limit;
+    if rate_limit.enabled && !(rate_limit.requests_per_second > 0.0 && rate_limit.burst > 0) {
+        return Err(anyhow!(
+            "rate_limit needs a positive requests_per_second and burst"
+        ));
+    }
+
     let languages = raw
         .languages
         .into_iter()
         .map(|lang| lang.into_resolved())
         .collect::<Result<Vec<_>>>()?;
+    // Submissions are routed to workers on a subject per language.
+    if let Some(lang) = languages.iter().find(|l| !is_subject_token(&l.name)) {
+        return Err(anyhow!(
+            "Language name '{}' may not contain '.', '*', '>' or whitespace",
+            lang.name
+        ));
+    }
 
     Ok(AppConfig {
         submission_defaults: raw.submission_defaults,
         languages,
+        wait_timeout: Duration::try_from_secs_f64(raw.wait_timeout)
+            .context("Invalid wait_timeout")?,
+        session_wall_time_limit: raw.session_wall_time_limit,
+        session_output_limit: raw.session_output_limit,
+        idempotency_ttl: Duration::try_from_secs_f64(raw.idempotency_ttl)
+            .context("Invalid idempotency_ttl")?,
+        idempotency_lease: Duration::try_from_secs_f64(raw.idempotency_lease)
+            .ok()
+            .filter(|lease| lease.as_secs_f64() > raw.wait_timeout)
+            .context("idempotency_lease must be longer than wait_timeout")?,
+        outbox_poll_interval: Duration::try_from_secs_f64(raw.outbox_poll_interval)
+            .context("Invalid outbox_poll_interval")?,
+        reconcile_interval: Duration::try_from_secs_f64(raw.reconcile_interval)
+            .ok()
+            .filter(|interval| !interval.is_zero())
+            .context("Invalid reconcile_interval")?,
+        reconcile_after: Duration::try_from_secs_f64(raw.reconcile_after)
+            .context("Invalid reconcile_after")?,
+        reap_interval: Duration::try_from_secs_f64(raw.reap_interval)
+            .ok()
+            .filter(|interval| !interval.is_zero())
+            .context("Invalid reap_interval")?,
+        reap_grace: Duration::try_from_secs_f64(raw.reap_grace).context("Invalid reap_grace")?,
+        heartbeat_timeout: Duration::try_from_secs_f64(raw.heartbeat_timeout)
+            .context("Invalid heartbeat_timeout")?,
+        max_attempts: raw.max_attempts,
+        deletion_policy: raw.deletion_policy,
+        rate_limit,
     })
 }
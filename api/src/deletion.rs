@@ -0,0 +1,84 @@
+use common::error::StringError;
+use poem::{Result, error::InternalServerError};
+use tokio_postgres::types::ToSql;
+
+use crate::AppData;
+use crate::config::DeletionPolicy;
+
+/// Deletes or redacts, according to the configured policy, every submission
+/// matching `conditions` and records each one in `submission_deletions`.
+/// Returns how many submissions were affected.
+pub async fn delete_submissions(
+    data: &AppData,
+    conditions: &[String],
+    mut params: Vec<&(dyn ToSql + Sync)>,
+    reason: Option<&str>,
+) -> Result<u64> {
+    let policy = data.config.deletion_policy;
+    let action = policy.as_str();
+    params.push(&action);
+    let action_param = params.len();
+    params.push(&reason);
+    let reason_param = params.len();
+
+    let filter = match conditions {
+        [] => "true".to_string(),
+        _ => conditions.join(" and "),
+    };
+    let removed = match policy {
+        DeletionPolicy::Delete => format!("DELETE FROM submissions WHERE {} RETURNING id", filter),
+        // Already redacted submissions are left alone so they are audited once.
+        DeletionPolicy::Redact => format!(
+            r#"
+            UPDATE submissions SET
+                source_code = '',
+                stdin = NULL,
+                expected_output = NULL,
+                test_groups = NULL,
+                stress_options = NULL,
+                stdout = NULL,
+                stderr = NULL,
+                compile_output = NULL,
+                stress_result = NULL,
+                redacted_at = now()
+            WHERE {} AND redacted_at IS NULL
+            RETURNING id
+            "#,
+            filter
+        ),
+    };
+    // Outputs of earlier verdicts and attempts and the queue messages go too;
+    // deleted rows take them along.
+    let sql = format!(
+        r#"
+        WITH removed AS ({}),
+        history AS (
+            UPDATE submission_verdicts SET
+                stdout = NULL,
+                stderr = NULL,
+                compile_output = NULL,
+                stress_result = NULL
+            WHERE submission_id IN (SELECT id FROM removed)
+        ),
+        attempts AS (
+            UPDATE submission_attempts SET
+                stdout = NULL,
+                stderr = NULL,
+                compile_output = NULL
+            WHERE submission_id IN (SELECT id FROM removed)
+        ),
+        outbox AS (
+            DELETE FROM submission_outbox
+            WHERE submission_id IN (SELECT id FROM removed)
+        )
+        INSERT INTO submission_deletions (submission_id, action, reason)
+        SELECT id, ${}, ${} FROM removed
+        "#,
+        removed, action_param, reason_param
+    );
+
+    data.db
+        .execute(&sql, &params)
+        .await
+        .map_err(|_| InternalServerError(StringError::new("could not delete submissions")))
+}
@@ -0,0 +1,58 @@
+use base64::{Engine, engine::general_purpose::STANDARD};
+use common::{
+    error::StringError,
+    model::{NewSubmission, Submission},
+    text::RawText,
+};
+use poem::{Result, error::BadRequest};
+use tokio_postgres::Row;
+
+/// Decodes the text fields of a submission sent with `base64_encoded=true`.
+/// They are stored as raw bytes, so they may decode to anything.
+pub fn decode_submission(new_submission: &mut NewSubmission) -> Result<()> {
+    new_submission.source_code = decode("source_code", &new_submission.source_code)?;
+    if let Some(stdin) = &new_submission.stdin {
+        new_submission.stdin = Some(decode("stdin", stdin)?);
+    }
+    if let Some(expected_output) = &new_submission.expected_output {
+        new_submission.expected_output = Some(decode("expected_output", expected_output)?);
+    }
+    Ok(())
+}
+
+fn decode(field: &str, value: &RawText) -> Result<RawText> {
+    let bytes = STANDARD.decode(value.trim_ascii()).map_err(|_| {
+        BadRequest(StringError::new(&format!(
+            "`{}` is not valid base64",
+            field
+        )))
+    })?;
+    Ok(RawText(bytes))
+}
+
+fn encode(value: &RawText) -> RawText {
+    RawText::from(STANDARD.encode(value))
+}
+
+/// Builds a submission from its row, with the text fields base64 encoded
+/// when `base64_encoded` is set. They are encoded from the raw bytes stored,
+/// so nothing is lost to invalid UTF-8.
+pub fn submission_from_row(row: Row, base64_encoded: bool) -> Submission {
+    if !base64_encoded {
+        return Submission::from(row);
+    }
+
+    let raw = |column: &str| row.try_get::<_, Option<Vec<u8>>>(column).ok().flatten();
+    let stdout = raw("stdout").map(|s| STANDARD.encode(s));
+    let stderr = raw("stderr").map(|s| STANDARD.encode(s));
+    let compile_output = raw("compile_output").map(|s| STANDARD.encode(s));
+
+    let mut submission = Submission::from(row);
+    submission.source_code = encode(&submission.source_code);
+    submission.stdin = submission.stdin.as_ref().map(encode);
+    submission.expected_output = submission.expected_output.as_ref().map(encode);
+    submission.compile_output = compile_output;
+    submission.stdout = stdout;
+    submission.stderr = stderr;
+    submission
+}
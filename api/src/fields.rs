@@ -0,0 +1,343 @@
+use chrono::{DateTime, Utc};
+use common::{
+    error::StringError,
+    model::{
+        Comparator, GroupResult, StressOptions, StressResult, Submission, SubmissionMode,
+        SubmissionPriority, SubmissionStatus, TestGroup,
+    },
+    text::RawText,
+};
+use poem::{
+    Result,
+    error::{BadRequest, InternalServerError},
+};
+use poem_openapi::Object;
+use tokio_postgres::types::ToSql;
+use uuid::Uuid;
+
+use crate::AppData;
+use crate::encoding::submission_from_row;
+
+/// Submission fields that can be requested with `fields=`, with the column
+/// each one is read from.
+const FIELDS: &[(&str, &str)] = &[
+    ("id", "id"),
+    ("problem_id", "problem_id"),
+    ("source_code", "source_code"),
+    ("language", "language"),
+    ("compiler_options", "compiler_options"),
+    ("command_line_arguments", "command_line_arguments"),
+    ("stdin", "stdin"),
+    ("expected_output", "expected_output"),
+    ("cpu_time_limit", "cpu_time_limit"),
+    ("cpu_extra_time", "cpu_extra_time"),
+    ("wall_time_limit", "wall_time_limit"),
+    ("memory_limit", "memory_limit"),
+    ("stack_limit", "stack_limit"),
+    (
+        "max_processes_and_or_threads",
+        "max_processes_and_or_threads",
+    ),
+    (
+        "enable_per_process_and_thread_time_limit",
+        "enable_per_process_and_thread_time_limit",
+    ),
+    (
+        "enable_per_process_and_thread_memory_limit",
+        "enable_per_process_and_thread_memory_limit",
+    ),
+    ("max_file_size", "max_file_size"),
+    ("redirect_stderr_to_stdout", "redirect_stderr_to_stdout"),
+    ("enable_network", "enable_network"),
+    ("number_of_runs", "number_of_runs"),
+    ("additional_files", "additional_files"),
+    ("callback_url", "callback_url"),
+    ("test_groups", "test_groups"),
+    ("comparator", "comparator"),
+    ("mode", "mode"),
+    ("stress", "stress_options"),
+    ("input_file", "input_file"),
+    ("output_file", "output_file"),
+    ("tags", "tags"),
+    ("priority", "priority"),
+    ("stdout", "stdout"),
+    ("stderr", "stderr"),
+    ("compile_output", "compile_output"),
+    ("message", "message"),
+    ("exit_code", "exit_code"),
+    ("exit_signal", "exit_signal"),
+    ("status", "status"),
+    ("created_at", "created_at"),
+    ("finished_at", "finished_at"),
+    ("redacted_at", "redacted_at"),
+    ("cached_from", "cached_from"),
+    ("time", "time"),
+    ("wall_time", "wall_time"),
+    ("memory", "memory"),
+    ("score", "score"),
+    ("max_score", "max_score"),
+    ("group_results", "group_results"),
+    ("stress_result", "stress_result"),
+];
+
+/// Columns that are always read, since a [`Submission`] cannot be built
+/// without them and listing pages by `created_at` and `id`.
+const REQUIRED_COLUMNS: &[&str] = &["id", "language", "status", "created_at"];
+
+/// A submission with only the fields a client asked for; the others are left
+/// out of the response.
+#[derive(Object)]
+pub struct SubmissionFields {
+    #[oai(skip_serializing_if_is_none)]
+    pub id: Option<String>,
+    #[oai(skip_serializing_if_is_none)]
+    pub problem_id: Option<String>,
+    #[oai(skip_serializing_if_is_none)]
+    pub source_code: Option<RawText>,
+    #[oai(skip_serializing_if_is_none)]
+    pub language: Option<String>,
+    #[oai(skip_serializing_if_is_none)]
+    pub compiler_options: Option<String>,
+    #[oai(skip_serializing_if_is_none)]
+    pub command_line_arguments: Option<String>,
+    #[oai(skip_serializing_if_is_none)]
+    pub stdin: Option<RawText>,
+    #[oai(skip_serializing_if_is_none)]
+    pub expected_output: Option<RawText>,
+    #[oai(skip_serializing_if_is_none)]
+    pub cpu_time_limit: Option<f64>,
+    #[oai(skip_serializing_if_is_none)]
+    pub cpu_extra_time: Option<f64>,
+    #[oai(skip_serializing_if_is_none)]
+    pub wall_time_limit: Option<f64>,
+    #[oai(skip_serializing_if_is_none)]
+    pub memory_limit: Option<f64>,
+    #[oai(skip_serializing_if_is_none)]
+    pub stack_limit: Option<i32>,
+    #[oai(skip_serializing_if_is_none)]
+    pub max_processes_and_or_threads: Option<i32>,
+    #[oai(skip_serializing_if_is_none)]
+    pub enable_per_process_and_thread_time_limit: Option<bool>,
+    #[oai(skip_serializing_if_is_none)]
+    pub enable_per_process_and_thread_memory_limit: Option<bool>,
+    #[oai(skip_serializing_if_is_none)]
+    pub max_file_size: Option<i32>,
+    #[oai(skip_serializing_if_is_none)]
+    pub redirect_stderr_to_stdout: Option<bool>,
+    #[oai(skip_serializing_if_is_none)]
+    pub enable_network: Option<bool>,
+    #[oai(skip_serializing_if_is_none)]
+    pub number_of_runs: Option<i32>,
+    #[oai(skip_serializing_if_is_none)]
+    pub additional_files: Option<String>,
+    #[oai(skip_serializing_if_is_none)]
+    pub callback_url: Option<String>,
+    #[oai(skip_serializing_if_is_none)]
+    pub test_groups: Option<Vec<TestGroup>>,
+    #[oai(skip_serializing_if_is_none)]
+    pub comparator: Option<Comparator>,
+    #[oai(skip_serializing_if_is_none)]
+    pub mode: Option<SubmissionMode>,
+    #[oai(skip_serializing_if_is_none)]
+    pub stress: Option<StressOptions>,
+    #[oai(skip_serializing_if_is_none)]
+    pub input_file: Option<String>,
+    #[oai(skip_serializing_if_is_none)]
+    pub output_file: Option<String>,
+    #[oai(skip_serializing_if_is_none)]
+    pub tags: Option<Vec<String>>,
+    #[oai(skip_serializing_if_is_none)]
+    pub priority: Option<SubmissionPriority>,
+    #[oai(skip_serializing_if_is_none)]
+    pub stdout: Option<String>,
+    #[oai(skip_serializing_if_is_none)]
+    pub stderr: Option<String>,
+    #[oai(skip_serializing_if_is_none)]
+    pub compile_output: Option<String>,
+    #[oai(skip_serializing_if_is_none)]
+    pub message: Option<String>,
+    #[oai(skip_serializing_if_is_none)]
+    pub exit_code: Option<i32>,
+    #[oai(skip_serializing_if_is_none)]
+    pub exit_signal: Option<i32>,
+    #[oai(skip_serializing_if_is_none)]
+    pub status: Option<SubmissionStatus>,
+    #[oai(skip_serializing_if_is_none)]
+    pub created_at: Option<DateTime<Utc>>,
+    #[oai(skip_serializing_if_is_none)]
+    pub finished_at: Option<DateTime<Utc>>,
+    #[oai(skip_serializing_if_is_none)]
+    pub redacted_at: Option<DateTime<Utc>>,
+    #[oai(skip_serializing_if_is_none)]
+    pub cached_from: Option<String>,
+    #[oai(skip_serializing_if_is_none)]
+    pub time: Option<f64>,
+    #[oai(skip_serializing_if_is_none)]
+    pub wall_time: Option<f64>,
+    #[oai(skip_serializing_if_is_none)]
+    pub memory: Option<f64>,
+    #[oai(skip_serializing_if_is_none)]
+    pub score: Option<f64>,
+    #[oai(skip_serializing_if_is_none)]
+    pub max_score: Option<f64>,
+    #[oai(skip_serializing_if_is_none)]
+    pub group_results: Option<Vec<GroupResult>>,
+    #[oai(skip_serializing_if_is_none)]
+    pub stress_result: Option<StressResult>,
+}
+
+/// The fields of a submission a client asked for.
+pub struct Fields(Vec<&'static str>);
+
+impl Fields {
+    /// Parses a comma separated `fields=` value; no value selects every field.
+    pub fn parse(fields: Option<&str>) -> Result<Fields> {
+        let Some(fields) = fields else {
+            return Ok(Fields(FIELDS.iter().map(|(field, _)| *field).collect()));
+        };
+        let mut selected = Vec::new();
+        for name in fields
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            let Some((field, _)) = FIELDS.iter().find(|(field, _)| *field == name) else {
+                return Err(BadRequest(StringError::new(&format!(
+                    "unknown field `{}`",
+                    name
+                ))));
+            };
+            if !selected.contains(field) {
+                selected.push(*field);
+            }
+        }
+        Ok(Fields(selected))
+    }
+
+    /// The select list of a query reading these fields.
+    pub fn columns(&self) -> String {
+        let mut columns = REQUIRED_COLUMNS.to_vec();
+        for (field, column) in FIELDS {
+            if self.0.contains(field) && !columns.contains(column) {
+                columns.push(column);
+            }
+        }
+        columns.join(", ")
+    }
+
+    fn has(&self, field: &str) -> bool {
+        self.0.contains(&field)
+    }
+
+    /// Keeps only the selected fields of `submission`.
+    pub fn project(&self, submission: Submission) -> SubmissionFields {
+        SubmissionFields {
+            id: self.has("id").then_some(submission.id),
+            problem_id: submission.problem_id.filter(|_| self.has("problem_id")),
+            source_code: self.has("source_code").then_some(submission.source_code),
+            language: self.has("language").then_some(submission.language),
+            compiler_options: submission
+                .compiler_options
+                .filter(|_| self.has("compiler_options")),
+            command_line_arguments: submission
+                .command_line_arguments
+                .filter(|_| self.has("command_line_arguments")),
+            stdin: submission.stdin.filter(|_| self.has("stdin")),
+            expected_output: submission
+                .expected_output
+                .filter(|_| self.has("expected_output")),
+            cpu_time_limit: submission
+                .cpu_time_limit
+                .filter(|_| self.has("cpu_time_limit")),
+            cpu_extra_time: submission
+                .cpu_extra_time
+                .filter(|_| self.has("cpu_extra_time")),
+            wall_time_limit: submission
+                .wall_time_limit
+                .filter(|_| self.has("wall_time_limit")),
+            memory_limit: submission.memory_limit.filter(|_| self.has("memory_limit")),
+            stack_limit: submission.stack_limit.filter(|_| self.has("stack_limit")),
+            max_processes_and_or_threads: submission
+                .max_processes_and_or_threads
+                .filter(|_| self.has("max_processes_and_or_threads")),
+            enable_per_process_and_thread_time_limit: submission
+                .enable_per_process_and_thread_time_limit
+                .filter(|_| self.has("enable_per_process_and_thread_time_limit")),
+            enable_per_process_and_thread_memory_limit: submission
+                .enable_per_process_and_thread_memory_limit
+                .filter(|_| self.has("enable_per_process_and_thread_memory_limit")),
+            max_file_size: submission
+                .max_file_size
+                .filter(|_| self.has("max_file_size")),
+            redirect_stderr_to_stdout: submission
+                .redirect_stderr_to_stdout
+                .filter(|_| self.has("redirect_stderr_to_stdout")),
+            enable_network: submission
+                .enable_network
+                .filter(|_| self.has("enable_network")),
+            number_of_runs: submission
+                .number_of_runs
+                .filter(|_| self.has("number_of_runs")),
+            additional_files: submission
+                .additional_files
+                .filter(|_| self.has("additional_files")),
+            callback_url: submission.callback_url.filter(|_| self.has("callback_url")),
+            test_groups: submission.test_groups.filter(|_| self.has("test_groups")),
+            comparator: submission.comparator.filter(|_| self.has("comparator")),
+            mode: self.has("mode").then_some(submission.mode),
+            stress: submission.stress.filter(|_| self.has("stress")),
+            input_file: submission.input_file.filter(|_| self.has("input_file")),
+            output_file: submission.output_file.filter(|_| self.has("output_file")),
+            tags: submission.tags.filter(|_| self.has("tags")),
+            priority: self.has("priority").then_some(submission.priority),
+            stdout: submission.stdout.filter(|_| self.has("stdout")),
+            stderr: submission.stderr.filter(|_| self.has("stderr")),
+            compile_output: submission
+                .compile_output
+                .filter(|_| self.has("compile_output")),
+            message: submission.message.filter(|_| self.has("message")),
+            exit_code: submission.exit_code.filter(|_| self.has("exit_code")),
+            exit_signal: submission.exit_signal.filter(|_| self.has("exit_signal")),
+            status: self.has("status").then_some(submission.status),
+            created_at: self.has("created_at").then_some(submission.created_at),
+            finished_at: submission.finished_at.filter(|_| self.has("finished_at")),
+            redacted_at: submission.redacted_at.filter(|_| self.has("redacted_at")),
+            cached_from: submission.cached_from.filter(|_| self.has("cached_from")),
+            time: submission.time.filter(|_| self.has("time")),
+            wall_time: submission.wall_time.filter(|_| self.has("wall_time")),
+            memory: submission.memory.filter(|_| self.has("memory")),
+            score: submission.score.filter(|_| self.has("score")),
+            max_score: submission.max_score.filter(|_| self.has("max_score")),
+            group_results: submission
+                .group_results
+                .filter(|_| self.has("group_results")),
+            stress_result: submission
+                .stress_result
+                .filter(|_| self.has("stress_result")),
+        }
+    }
+}
+
+/// Reads the selected fields of submission `id`, if it belongs to API key
+/// `owner` or no owner is given.
+pub async fn fetch_submission(
+    data: &AppData,
+    id: Uuid,
+    owner: Option<Uuid>,
+    fields: &Fields,
+    base64_encoded: bool,
+) -> Result<Option<SubmissionFields>> {
+    let sql = format!(
+        "select {} from submissions where id = $1 and ($2::uuid is null or api_key_id = $2)",
+        fields.columns()
+    );
+    let params: &[&(dyn ToSql + Sync)] = &[&id, &owner];
+    // Not prepared through the statement cache, since the select list varies
+    // with every combination of fields.
+    let client = data.db.pool.get().await.map_err(InternalServerError)?;
+    let row = client
+        .query_opt(&sql, params)
+        .await
+        .map_err(|_| InternalServerError(StringError::new("could not able to query")))?;
+    Ok(row.map(|row| fields.project(submission_from_row(row, base64_encoded))))
+}
@@ -0,0 +1,170 @@
+use chrono::{DateTime, Utc};
+use common::{auth::ApiKey, error::StringError};
+use deadpool_postgres::GenericClient;
+use poem::{
+    Result,
+    error::{BadRequest, Conflict, InternalServerError, UnprocessableEntity},
+};
+use sha2::{Digest, Sha256};
+use tokio_postgres::types::{Json as PgJson, ToSql};
+use uuid::Uuid;
+
+use crate::AppData;
+
+const MAX_KEY_LENGTH: usize = 255;
+
+/// Hash of the parts of a request that decide what it creates, so that a key
+/// reused for a different request is noticed.
+pub fn request_hash(parts: &[&str]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part.len().to_le_bytes());
+        hasher.update(part.as_bytes());
+    }
+    hasher.finalize().to_vec()
+}
+
+/// Outcome of presenting an `Idempotency-Key`.
+pub enum Claim {
+    /// First request with the key; it runs and its response is stored.
+    New(Idempotency),
+    /// The key was used before for the same request, which returned this.
+    Replay(serde_json::Value),
+}
+
+/// A claimed key whose request is running.
+pub struct Idempotency {
+    api_key_id: Uuid,
+    key: String,
+    /// When the key was claimed, which tells this claim from a later one that
+    /// took the key over after the lease ran out.
+    claimed_at: DateTime<Utc>,
+}
+
+/// Claims `key` for a request of `api_key`. Fails with `409` while an earlier
+/// request with the key is still running and with `422` if the key was used
+/// for a different request. Keys are remembered for the configured TTL, and
+/// one whose request has not completed within the lease, e.g. because its API
+/// instance stopped, can be claimed again.
+pub async fn claim(
+    data: &AppData,
+    api_key: &ApiKey,
+    key: Option<String>,
+    request_hash: Vec<u8>,
+) -> Result<Option<Claim>> {
+    let Some(key) = key else {
+        return Ok(None);
+    };
+    if key.is_empty() || key.len() > MAX_KEY_LENGTH {
+        return Err(BadRequest(StringError::new(&format!(
+            "Idempotency-Key must be 1 to {} characters",
+            MAX_KEY_LENGTH
+        ))));
+    }
+
+    let api_key_id = api_key.uuid();
+    let ttl = data.config.idempotency_ttl.as_secs_f64();
+    let lease = data.config.idempotency_lease.as_secs_f64();
+    let params: &[&(dyn ToSql + Sync)] = &[&api_key_id, &key, &request_hash, &ttl, &lease];
+    let claimed = data
+        .db
+        .query_opt(
+            r#"
+            WITH expired AS (
+                DELETE FROM idempotency_keys
+                WHERE api_key_id = $1 AND key <> $2
+                AND created_at < now() - make_interval(secs => $4)
+            )
+            INSERT INTO idempotency_keys (api_key_id, key, request_hash)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (api_key_id, key) DO UPDATE
+                SET request_hash = EXCLUDED.request_hash, response = NULL, created_at = now()
+                WHERE idempotency_keys.created_at < now() - make_interval(secs => $4)
+                OR (
+                    idempotency_keys.response IS NULL
+                    AND idempotency_keys.created_at < now() - make_interval(secs => $5)
+                )
+            RETURNING created_at
+            "#,
+            params,
+        )
+        .await
+        .map_err(|_| InternalServerError(StringError::new("could not able to query")))?;
+    if let Some(claimed) = claimed {
+        return Ok(Some(Claim::New(Idempotency {
+            api_key_id,
+            key,
+            claimed_at: claimed.get("created_at"),
+        })));
+    }
+
+    let params: &[&(dyn ToSql + Sync)] = &[&api_key_id, &key];
+    let row = data
+        .db
+        .query_opt(
+            "select request_hash, response from idempotency_keys where api_key_id = $1 and key = $2",
+            params,
+        )
+        .await
+        .map_err(|_| InternalServerError(StringError::new("could not able to query")))?
+        // Released by a failed request in the meantime.
+        .ok_or(Conflict(StringError::new(
+            "a request with this Idempotency-Key just failed, retry it",
+        )))?;
+
+    if row.get::<_, Vec<u8>>("request_hash") != request_hash {
+        return Err(UnprocessableEntity(StringError::new(
+            "Idempotency-Key was already used for a different request",
+        )));
+    }
+    match row.get::<_, Option<PgJson<serde_json::Value>>>("response") {
+        Some(response) => Ok(Some(Claim::Replay(response.0))),
+        None => Err(Conflict(StringError::new(
+            "a request with this Idempotency-Key is still in progress",
+        ))),
+    }
+}
+
+impl Idempotency {
+    /// Stores the response of a successful request for replays. Call it in the
+    /// transaction that creates what the request returns, so that a request
+    /// that committed is never run again. Does nothing if another request took
+    /// the key over in the meantime.
+    pub async fn complete(
+        &self,
+        client: &impl GenericClient,
+        response: serde_json::Value,
+    ) -> Result<()> {
+        let response = PgJson(response);
+        let params: &[&(dyn ToSql + Sync)] =
+            &[&self.api_key_id, &self.key, &self.claimed_at, &response];
+        client
+            .execute(
+                r#"
+                UPDATE idempotency_keys SET response = $4
+                WHERE api_key_id = $1 AND key = $2 AND created_at = $3
+                "#,
+                params,
+            )
+            .await
+            .map_err(|_| InternalServerError(StringError::new("could not able to query")))?;
+        Ok(())
+    }
+
+    /// Releases the key after a failed request so that it can be retried,
+    /// unless the request completed before it failed.
+    pub async fn release(self, data: &AppData) -> Result<()> {
+        let params: &[&(dyn ToSql + Sync)] = &[&self.api_key_id, &self.key, &self.claimed_at];
+        data.db
+            .execute(
+                r#"
+                DELETE FROM idempotency_keys
+                WHERE api_key_id = $1 AND key = $2 AND created_at = $3 AND response IS NULL
+                "#,
+                params,
+            )
+            .await
+            .map_err(|_| InternalServerError(StringError::new("could not able to query")))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,198 @@
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, SecondsFormat, Utc};
+use common::{
+    error::StringError,
+    model::{Submission, SubmissionStatus},
+};
+use poem::{
+    Result,
+    error::{BadRequest, InternalServerError},
+};
+use poem_openapi::Object;
+use tokio_postgres::types::ToSql;
+use uuid::Uuid;
+
+use crate::AppData;
+use crate::encoding::submission_from_row;
+use crate::fields::{Fields, SubmissionFields};
+
+/// Position after the last submission of a page, handed out as an opaque
+/// token.
+pub struct Cursor {
+    created_at: DateTime<Utc>,
+    id: Uuid,
+}
+
+impl Cursor {
+    fn encode(&self) -> String {
+        let created_at = self.created_at.to_rfc3339_opts(SecondsFormat::Micros, true);
+        URL_SAFE_NO_PAD.encode(format!("{},{}", created_at, self.id))
+    }
+
+    pub fn decode(cursor: &str) -> Result<Cursor> {
+        let invalid = || BadRequest(StringError::new("invalid cursor"));
+        let decoded = URL_SAFE_NO_PAD.decode(cursor).map_err(|_| invalid())?;
+        let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+        let (created_at, id) = decoded.split_once(',').ok_or_else(invalid)?;
+        Ok(Cursor {
+            created_at: DateTime::parse_from_rfc3339(created_at)
+                .map_err(|_| invalid())?
+                .with_timezone(&Utc),
+            id: Uuid::parse_str(id).map_err(|_| invalid())?,
+        })
+    }
+}
+
+pub const DEFAULT_PAGE_SIZE: i64 = 50;
+pub const MAX_PAGE_SIZE: i64 = 500;
+
+/// Conditions a listed, deleted or rejudged submission has to meet.
+pub struct SubmissionFilter {
+    pub problem_id: Option<Uuid>,
+    pub status: Option<SubmissionStatus>,
+    pub language: Option<String>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    /// The submission must carry every one of these tags.
+    pub tags: Vec<String>,
+    /// Only submissions of this API key; not a filter of its own, so not
+    /// counted by [`SubmissionFilter::is_empty`].
+    pub owner: Option<Uuid>,
+}
+
+impl SubmissionFilter {
+    /// Parses a `problem_id=` value.
+    pub fn parse_problem_id(problem_id: Option<&str>) -> Result<Option<Uuid>> {
+        problem_id
+            .map(Uuid::parse_str)
+            .transpose()
+            .map_err(|_| BadRequest(StringError::new("invalid problem_id")))
+    }
+
+    /// Splits a comma separated `tags=` value.
+    pub fn parse_tags(tags: Option<&str>) -> Vec<String> {
+        tags.iter()
+            .flat_map(|tags| tags.split(','))
+            .map(|tag| tag.trim().to_string())
+            .filter(|tag| !tag.is_empty())
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.problem_id.is_none()
+            && self.status.is_none()
+            && self.language.is_none()
+            && self.created_after.is_none()
+            && self.created_before.is_none()
+            && self.tags.is_empty()
+    }
+
+    /// SQL conditions for the filter, with their values appended to `params`.
+    pub fn conditions<'a>(&'a self, params: &mut Vec<&'a (dyn ToSql + Sync)>) -> Vec<String> {
+        let mut conditions = Vec::new();
+        if let Some(problem_id) = &self.problem_id {
+            params.push(problem_id);
+            conditions.push(format!("problem_id = ${}", params.len()));
+        }
+        if let Some(status) = &self.status {
+            params.push(status);
+            conditions.push(format!("status = ${}", params.len()));
+        }
+        if let Some(language) = &self.language {
+            params.push(language);
+            conditions.push(format!("language = ${}", params.len()));
+        }
+        if let Some(created_after) = &self.created_after {
+            params.push(created_after);
+            conditions.push(format!("created_at >= ${}", params.len()));
+        }
+        if let Some(created_before) = &self.created_before {
+            params.push(created_before);
+            conditions.push(format!("created_at < ${}", params.len()));
+        }
+        if !self.tags.is_empty() {
+            params.push(&self.tags);
+            conditions.push(format!("tags @> ${}", params.len()));
+        }
+        if let Some(owner) = &self.owner {
+            params.push(owner);
+            conditions.push(format!("api_key_id = ${}", params.len()));
+        }
+        conditions
+    }
+}
+
+#[derive(Object)]
+pub struct SubmissionPage {
+    /// The submissions, newest first, with the requested fields.
+    pub submissions: Vec<SubmissionFields>,
+    /// Pass as `cursor` to fetch the next page; absent on the last page.
+    #[oai(skip_serializing_if_is_none)]
+    pub next_cursor: Option<String>,
+}
+
+/// Reads one page of at most `limit` submissions matching `filter`, newest
+/// first, starting after `cursor`.
+pub async fn list_submissions(
+    data: &AppData,
+    filter: &SubmissionFilter,
+    cursor: Option<&Cursor>,
+    limit: i64,
+    fields: &Fields,
+    base64_encoded: bool,
+) -> Result<SubmissionPage> {
+    let mut params: Vec<&(dyn ToSql + Sync)> = Vec::new();
+    let mut conditions = filter.conditions(&mut params);
+    if let Some(cursor) = cursor {
+        params.push(&cursor.created_at);
+        params.push(&cursor.id);
+        conditions.push(format!(
+            "(created_at, id) < (${}, ${})",
+            params.len() - 1,
+            params.len()
+        ));
+    }
+
+    // One extra row tells whether there is a next page.
+    let fetch = limit + 1;
+    params.push(&fetch);
+    let mut sql = format!("select {} from submissions", fields.columns());
+    if !conditions.is_empty() {
+        sql.push_str(" where ");
+        sql.push_str(&conditions.join(" and "));
+    }
+    sql.push_str(&format!(
+        " order by created_at desc, id desc limit ${}",
+        params.len()
+    ));
+
+    // Not prepared through the statement cache, since the select list and
+    // conditions vary with every combination of fields and filters.
+    let client = data.db.pool.get().await.map_err(InternalServerError)?;
+    let rows = client
+        .query(&sql, &params)
+        .await
+        .map_err(|_| InternalServerError(StringError::new("could not able to query")))?;
+
+    let mut submissions: Vec<Submission> = rows
+        .into_iter()
+        .map(|row| submission_from_row(row, base64_encoded))
+        .collect();
+    let next_cursor = if submissions.len() as i64 > limit {
+        submissions.truncate(limit as usize);
+        submissions.last().map(|last| {
+            Cursor {
+                created_at: last.created_at,
+                id: Uuid::parse_str(&last.id).expect("ids are uuids"),
+            }
+            .encode()
+        })
+    } else {
+        None
+    };
+
+    Ok(SubmissionPage {
+        submissions: submissions.into_iter().map(|s| fields.project(s)).collect(),
+        next_cursor,
+    })
+}
@@ -1,169 +1,1099 @@
+use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
+use chrono::{DateTime, Utc};
 use common::{
+    auth::{ApiKey, ApiKeyScope, NewApiKey, create_api_key},
     db,
     error::StringError,
-    model::{NatsSubmission, NewSubmission, Submission, SubmissionStatus},
-    nats::NatsClient,
+    model::{
+        NewSubmission, Submission, SubmissionAttempt, SubmissionEvent, SubmissionPriority,
+        SubmissionStage, SubmissionStatus,
+    },
+    nats::{NatsClient, done_subject, events_subject},
+};
+use futures::{
+    StreamExt,
+    future::ready,
+    stream::{self, BoxStream},
 };
 use poem::{
-    EndpointExt, Result, Route, Server,
+    EndpointExt, IntoEndpoint, Result, Route, Server,
     error::{BadRequest, InternalServerError},
+    get,
     listener::TcpListener,
     middleware::Cors,
     web::{Data, Path},
 };
 use poem_openapi::{
     ApiResponse, Object, OpenApi, OpenApiService,
-    payload::{Json, PlainText},
+    param::{Header, Query},
+    payload::{EventStream, Json, PlainText},
+    types::{ParseFromJSON, ToJSON},
 };
+use tokio::sync::Notify;
 use tokio_postgres::types::ToSql;
+use uuid::Uuid;
 
+use crate::auth::{ApiKeyAuth, reserve_submissions};
+use crate::cache::{copy_verdict, find_cached};
 use crate::config::{AppConfig, load_config};
+use crate::deletion::delete_submissions;
+use crate::encoding::{decode_submission, submission_from_row};
+use crate::fields::{Fields, SubmissionFields, fetch_submission};
+use crate::idempotency::{Claim, claim, request_hash};
+use crate::listing::{
+    Cursor, DEFAULT_PAGE_SIZE, MAX_PAGE_SIZE, SubmissionFilter, SubmissionPage, list_submissions,
+};
+use crate::outbox::{run_reconciler, run_relay};
+use crate::problems::ProblemCache;
+use crate::ratelimit::RateLimit;
+use crate::reaper::run_reaper;
+use crate::rejudge::rejudge_submissions;
+use crate::submissions::{
+    announce_finished, insert_submission, prepare_submission, queue_submission, wait_for_result,
+};
+use crate::workers::LiveWorkers;
 
+mod auth;
+mod cache;
 mod config;
+mod deletion;
+mod encoding;
+mod fields;
+mod idempotency;
+mod listing;
+mod outbox;
+mod problems;
+mod ratelimit;
+mod reaper;
+mod rejudge;
+mod sessions;
+mod submissions;
+mod workers;
 
 #[derive(ApiResponse)]
 enum SubmissionGetResponse {
+    /// The submission with the requested fields.
+    #[oai(status = 200)]
+    Submission(Json<Box<SubmissionFields>>),
+
+    #[oai(status = 404)]
+    NotFound(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+enum SubmissionStatusGetResponse {
     #[oai(status = 200)]
-    Submission(Json<Submission>),
+    Status(Json<SubmissionStatusResponse>),
 
     #[oai(status = 404)]
     NotFound(PlainText<String>),
 }
 
+/// Just enough of a submission to poll until it is finished.
+#[derive(Object)]
+struct SubmissionStatusResponse {
+    id: String,
+    status: SubmissionStatus,
+    #[oai(skip_serializing_if_is_none)]
+    finished_at: Option<DateTime<Utc>>,
+}
+
+#[derive(ApiResponse)]
+enum SubmissionCancelResponse {
+    /// The submission's status: `Cancelled` if it was still queued, otherwise
+    /// its worker stops it shortly.
+    #[oai(status = 200)]
+    Cancelled(Json<SubmissionStatusResponse>),
+
+    #[oai(status = 404)]
+    NotFound(PlainText<String>),
+
+    #[oai(status = 409)]
+    Finished(PlainText<String>),
+}
+
+#[derive(Object)]
+struct RejudgeResponse {
+    /// How many submissions were queued again.
+    count: u64,
+}
+
+#[derive(ApiResponse)]
+enum SubmissionRejudgeResponse {
+    #[oai(status = 200)]
+    Rejudged(Json<RejudgeResponse>),
+
+    #[oai(status = 404)]
+    NotFound(PlainText<String>),
+
+    #[oai(status = 409)]
+    NotFinished(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+enum SubmissionAttemptsResponse {
+    #[oai(status = 200)]
+    Attempts(Json<Vec<SubmissionAttempt>>),
+
+    #[oai(status = 404)]
+    NotFound(PlainText<String>),
+}
+
+#[derive(ApiResponse)]
+enum SubmissionEventsResponse {
+    #[oai(status = 200)]
+    Events(EventStream<BoxStream<'static, SubmissionEvent>>),
+
+    #[oai(status = 404)]
+    NotFound(PlainText<String>),
+}
+
+/// How often an idle event stream sends a keep-alive comment.
+const SSE_KEEP_ALIVE: Duration = Duration::from_secs(15);
+
 #[derive(Object)]
 struct SubmissionResponse {
     id: String,
+    /// The finished submission, when created with `wait=true` and it finished
+    /// within the wait timeout.
+    #[oai(skip_serializing_if_is_none)]
+    submission: Option<Box<Submission>>,
+}
+
+/// Outcome of one item of a batch: its id once stored, or why it was rejected.
+#[derive(Object)]
+struct BatchItemResponse {
+    id: Option<String>,
+    error: Option<String>,
+}
+
+/// Upper bound on the size of a batch, for both creating and fetching.
+const MAX_BATCH_SIZE: usize = 1000;
+
+#[derive(Object)]
+struct DeletionResponse {
+    /// `deleted` or `redacted`, following the configured deletion policy.
+    action: String,
+    /// How many submissions were deleted or redacted.
+    count: u64,
+}
+
+#[derive(ApiResponse)]
+enum SubmissionDeleteResponse {
+    #[oai(status = 200)]
+    Deleted(Json<DeletionResponse>),
+
+    #[oai(status = 404)]
+    NotFound(PlainText<String>),
+}
+
+#[derive(Object)]
+struct CreatedApiKeyResponse {
+    /// The key to send as `Authorization: Bearer <key>`. Only its hash is
+    /// stored, so it cannot be shown again.
+    key: String,
+    api_key: ApiKey,
+}
+
+#[derive(ApiResponse)]
+enum ApiKeyRevokeResponse {
+    #[oai(status = 200)]
+    Revoked(Json<ApiKey>),
+
+    #[oai(status = 404)]
+    NotFound(PlainText<String>),
 }
 
 struct Api;
 
 #[OpenApi]
 impl Api {
+    /// Creates a submission. With `wait=true` the request is held open until
+    /// the submission is judged or the wait timeout passes, whichever is first.
+    /// With `base64_encoded=true` the source, stdin and expected output are
+    /// base64 and so is the text of the returned submission. An identical
+    /// submission with a reusable verdict finishes it right away, unless
+    /// `cache=false`. A request repeated with the same `Idempotency-Key`
+    /// returns the id of the submission the first one created.
+    #[allow(clippy::too_many_arguments)]
     #[oai(path = "/submissions/new", method = "post")]
     async fn create_submission(
         &self,
         data: Data<&Arc<AppData>>,
+        key: Data<&ApiKey>,
         new_submission: Json<NewSubmission>,
+        wait: Query<Option<bool>>,
+        base64_encoded: Query<Option<bool>>,
+        cache: Query<Option<bool>>,
+        #[oai(name = "Idempotency-Key")] idempotency_key: Header<Option<String>>,
     ) -> Result<Json<SubmissionResponse>> {
-        let language = data
-            .config
-            .get_language(&new_submission.language)
-            .ok_or(BadRequest(StringError::new("unregonised language")))?;
+        key.require(ApiKeyScope::Submit)?;
+        let base64_encoded = base64_encoded.0.unwrap_or(false);
+        let mut new_submission = new_submission.0;
+        let request_hash = request_hash(&[
+            &base64_encoded.to_string(),
+            &wait.0.unwrap_or(false).to_string(),
+            &cache.0.unwrap_or(true).to_string(),
+            &new_submission.to_json_string(),
+        ]);
+        let idempotency = match claim(&data, &key, idempotency_key.0, request_hash).await? {
+            Some(Claim::Replay(response)) => {
+                return SubmissionResponse::parse_from_json(Some(response))
+                    .map(Json)
+                    .map_err(|_| {
+                        InternalServerError(StringError::new("could not replay response"))
+                    });
+            }
+            Some(Claim::New(idempotency)) => Some(idempotency),
+            None => None,
+        };
 
-        let params: &[&(dyn ToSql + Sync)] = &[
-            &new_submission.source_code,
-            &language.name,
-            &new_submission.compiler_options,
-            &new_submission.command_line_arguments,
-            &new_submission.stdin,
-            &new_submission.expected_output,
-            &new_submission.cpu_time_limit,
-            &new_submission.cpu_extra_time,
-            &new_submission.wall_time_limit,
-            &new_submission.memory_limit,
-            &new_submission.stack_limit,
-            &new_submission.max_processes_and_or_threads,
-            &new_submission.enable_per_process_and_thread_time_limit,
-            &new_submission.enable_per_process_and_thread_memory_limit,
-            &new_submission.max_file_size,
-            &new_submission.redirect_stderr_to_stdout,
-            &new_submission.enable_network,
-            &new_submission.number_of_runs,
-            &new_submission.additional_files,
-            &new_submission.callback_url,
-            &SubmissionStatus::InQueue,
-        ];
+        let response = async {
+            if base64_encoded {
+                decode_submission(&mut new_submission)?;
+            }
+            let prepared =
+                prepare_submission(&data, &mut ProblemCache::default(), new_submission).await?;
+            key.check_limits(&prepared.new_submission)?;
+            data.workers
+                .require(&data.nats, &prepared.language.name)
+                .await?;
 
-        let row = data
+            let mut client = data.db.pool.get().await.map_err(InternalServerError)?;
+            let tx = client.transaction().await.map_err(InternalServerError)?;
+            reserve_submissions(&tx, &key, 1).await?;
+            let cached = match (cache.0.unwrap_or(true), &prepared.content_hash) {
+                (true, Some(content_hash)) => find_cached(&tx, content_hash, key.uuid()).await?,
+                _ => None,
+            };
+            let id = insert_submission(&tx, &prepared, key.uuid())
+                .await
+                .map_err(|e| {
+                    InternalServerError(StringError::new(&format!("DB insert failed: {}", e)))
+                })?;
+            // Subscribe before queueing so that a fast worker cannot finish unseen.
+            let done = match cached {
+                Some(cached) => {
+                    copy_verdict(&tx, id, cached).await?;
+                    None
+                }
+                None => {
+                    queue_submission(&tx, id, &prepared).await?;
+                    match wait.0.unwrap_or(false) {
+                        true => Some(
+                            data.nats
+                                .subscribe(done_subject(&id.to_string()))
+                                .await
+                                .map_err(|_| {
+                                    InternalServerError(StringError::new(
+                                        "couldnot wait for result",
+                                    ))
+                                })?,
+                        ),
+                        false => None,
+                    }
+                }
+            };
+            // Replays only return the id, since the submission changes once judged.
+            if let Some(idempotency) = &idempotency {
+                idempotency
+                    .complete(&tx, serde_json::json!({ "id": id }))
+                    .await?;
+            }
+            tx.commit().await.map_err(InternalServerError)?;
+            data.outbox.notify_one();
+
+            // Finished already, so nothing is queued and there is nothing to wait for.
+            if cached.is_some() {
+                let submission = match wait.0.unwrap_or(false) {
+                    true => {
+                        let params: &[&(dyn ToSql + Sync)] = &[&id];
+                        let row = data
+                            .db
+                            .query_one("select * from submissions where id= $1", params)
+                            .await
+                            .map_err(|_| {
+                                InternalServerError(StringError::new("could not able to query"))
+                            })?;
+                        Some(Box::new(submission_from_row(row, base64_encoded)))
+                    }
+                    false => None,
+                };
+                return Ok(SubmissionResponse {
+                    id: id.to_string(),
+                    submission,
+                });
+            }
+
+            let submission = match done {
+                Some(done) => wait_for_result(&data, id, done, base64_encoded)
+                    .await?
+                    .map(Box::new),
+                None => None,
+            };
+
+            Ok(SubmissionResponse {
+                id: id.to_string(),
+                submission,
+            })
+        }
+        .await;
+
+        if let (Some(idempotency), Err(_)) = (idempotency, &response) {
+            idempotency.release(&data).await?;
+        }
+        response.map(Json)
+    }
+
+    /// Creates many submissions at once. Every item is validated on its own and
+    /// the valid ones are stored in a single transaction before being queued.
+    /// Those identical to a submission with a reusable verdict finish right
+    /// away, unless `cache=false`. A request repeated with the same
+    /// `Idempotency-Key` gets the first response again.
+    #[allow(clippy::too_many_arguments)]
+    #[oai(path = "/submissions/batch", method = "post")]
+    async fn create_submissions(
+        &self,
+        data: Data<&Arc<AppData>>,
+        key: Data<&ApiKey>,
+        batch: Json<Vec<NewSubmission>>,
+        base64_encoded: Query<Option<bool>>,
+        cache: Query<Option<bool>>,
+        #[oai(name = "Idempotency-Key")] idempotency_key: Header<Option<String>>,
+    ) -> Result<Json<Vec<BatchItemResponse>>> {
+        key.require(ApiKeyScope::Submit)?;
+        if batch.len() > MAX_BATCH_SIZE {
+            return Err(BadRequest(StringError::new(&format!(
+                "a batch holds at most {} submissions",
+                MAX_BATCH_SIZE
+            ))));
+        }
+
+        let base64_encoded = base64_encoded.0.unwrap_or(false);
+        let request_hash = request_hash(&[
+            &base64_encoded.to_string(),
+            &cache.0.unwrap_or(true).to_string(),
+            &batch.to_json_string(),
+        ]);
+        let idempotency = match claim(&data, &key, idempotency_key.0, request_hash).await? {
+            Some(Claim::Replay(response)) => {
+                return Vec::<BatchItemResponse>::parse_from_json(Some(response))
+                    .map(Json)
+                    .map_err(|_| {
+                        InternalServerError(StringError::new("could not replay response"))
+                    });
+            }
+            Some(Claim::New(idempotency)) => Some(idempotency),
+            None => None,
+        };
+
+        let responses = async {
+            // Batches often hold many submissions to the same problem.
+            let mut problems = ProblemCache::default();
+            let mut items = Vec::with_capacity(batch.len());
+            for mut new_submission in batch.0 {
+                let item = match base64_encoded {
+                    true => decode_submission(&mut new_submission),
+                    false => Ok(()),
+                };
+                let item = match item {
+                    Ok(()) => prepare_submission(&data, &mut problems, new_submission).await,
+                    Err(err) => Err(err),
+                };
+                let item = item.and_then(|prepared| {
+                    key.check_limits(&prepared.new_submission)?;
+                    Ok(prepared)
+                });
+                let item = match item {
+                    Ok(prepared) => data
+                        .workers
+                        .require(&data.nats, &prepared.language.name)
+                        .await
+                        .map(|()| prepared),
+                    Err(err) => Err(err),
+                };
+                items.push(item);
+            }
+
+            let mut client = data.db.pool.get().await.map_err(InternalServerError)?;
+            let tx = client.transaction().await.map_err(InternalServerError)?;
+            reserve_submissions(&tx, &key, items.iter().flatten().count()).await?;
+            let mut ids = Vec::with_capacity(items.len());
+            for prepared in items.iter().flatten() {
+                let cached = match (cache.0.unwrap_or(true), &prepared.content_hash) {
+                    (true, Some(content_hash)) => {
+                        find_cached(&tx, content_hash, key.uuid()).await?
+                    }
+                    _ => None,
+                };
+                let id = insert_submission(&tx, prepared, key.uuid())
+                    .await
+                    .map_err(|e| {
+                        InternalServerError(StringError::new(&format!("DB insert failed: {}", e)))
+                    })?;
+                match cached {
+                    Some(cached) => copy_verdict(&tx, id, cached).await?,
+                    None => queue_submission(&tx, id, prepared).await?,
+                }
+                ids.push(id);
+            }
+
+            let mut ids = ids.into_iter();
+            let mut responses = Vec::with_capacity(items.len());
+            for item in items {
+                let response = match item {
+                    Ok(_) => BatchItemResponse {
+                        id: ids.next().map(|id| id.to_string()),
+                        error: None,
+                    },
+                    Err(err) => BatchItemResponse {
+                        id: None,
+                        error: Some(err.to_string()),
+                    },
+                };
+                responses.push(response);
+            }
+
+            if let (Some(idempotency), Some(stored)) = (&idempotency, responses.to_json()) {
+                idempotency.complete(&tx, stored).await?;
+            }
+            tx.commit().await.map_err(InternalServerError)?;
+            data.outbox.notify_one();
+            Ok(responses)
+        }
+        .await;
+
+        if let (Some(idempotency), Err(_)) = (idempotency, &responses) {
+            idempotency.release(&data).await?;
+        }
+        responses.map(Json)
+    }
+
+    /// Lists submissions newest first, one page at a time. `tags` is a comma
+    /// separated list that a submission must all carry, and `fields` a comma
+    /// separated list of the fields to return, e.g. to leave out the source.
+    #[allow(clippy::too_many_arguments)]
+    #[oai(path = "/submissions", method = "get")]
+    async fn list_submissions(
+        &self,
+        data: Data<&Arc<AppData>>,
+        key: Data<&ApiKey>,
+        problem_id: Query<Option<String>>,
+        status: Query<Option<SubmissionStatus>>,
+        language: Query<Option<String>>,
+        created_after: Query<Option<DateTime<Utc>>>,
+        created_before: Query<Option<DateTime<Utc>>>,
+        tags: Query<Option<String>>,
+        cursor: Query<Option<String>>,
+        limit: Query<Option<i64>>,
+        fields: Query<Option<String>>,
+        base64_encoded: Query<Option<bool>>,
+    ) -> Result<Json<SubmissionPage>> {
+        key.require(ApiKeyScope::Read)?;
+        let limit = limit.0.unwrap_or(DEFAULT_PAGE_SIZE);
+        if !(1..=MAX_PAGE_SIZE).contains(&limit) {
+            return Err(BadRequest(StringError::new(&format!(
+                "limit must be between 1 and {}",
+                MAX_PAGE_SIZE
+            ))));
+        }
+        let filter = SubmissionFilter {
+            problem_id: SubmissionFilter::parse_problem_id(problem_id.0.as_deref())?,
+            status: status.0,
+            language: language.0,
+            created_after: created_after.0,
+            created_before: created_before.0,
+            tags: SubmissionFilter::parse_tags(tags.0.as_deref()),
+            owner: key.owner(),
+        };
+        let cursor = cursor.0.as_deref().map(Cursor::decode).transpose()?;
+        let fields = Fields::parse(fields.0.as_deref())?;
+
+        Ok(Json(
+            list_submissions(
+                &data,
+                &filter,
+                cursor.as_ref(),
+                limit,
+                &fields,
+                base64_encoded.0.unwrap_or(false),
+            )
+            .await?,
+        ))
+    }
+
+    /// Fetches several submissions by id, in the order requested. Unknown ids
+    /// are left out.
+    #[oai(path = "/submissions/batch", method = "get")]
+    async fn get_submissions(
+        &self,
+        data: Data<&Arc<AppData>>,
+        key: Data<&ApiKey>,
+        ids: Query<String>,
+        base64_encoded: Query<Option<bool>>,
+    ) -> Result<Json<Vec<Submission>>> {
+        key.require(ApiKeyScope::Read)?;
+        let ids = ids
+            .split(',')
+            .map(|id| Uuid::parse_str(id.trim()))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| BadRequest(StringError::new("invalid submission id")))?;
+        if ids.len() > MAX_BATCH_SIZE {
+            return Err(BadRequest(StringError::new(&format!(
+                "at most {} ids can be fetched at once",
+                MAX_BATCH_SIZE
+            ))));
+        }
+
+        let owner = key.owner();
+        let params: &[&(dyn ToSql + Sync)] = &[&ids, &owner];
+        let rows = data
             .db
-            .query_one(
-                r#"
-        INSERT INTO submissions (
-            source_code,
-            language,
-            compiler_options,
-            command_line_arguments,
-            stdin,
-            expected_output,
-            cpu_time_limit,
-            cpu_extra_time,
-            wall_time_limit,
-            memory_limit,
-            stack_limit,
-            max_processes_and_or_threads,
-            enable_per_process_and_thread_time_limit,
-            enable_per_process_and_thread_memory_limit,
-            max_file_size,
-            redirect_stderr_to_stdout,
-            enable_network,
-            number_of_runs,
-            additional_files,
-            callback_url,
-            status
-        ) VALUES (
-            $1, $2, $3, $4, $5, $6, $7, $8, $9, $10,
-            $11, $12, $13, $14, $15, $16, $17, $18, $19, $20,$21
+            .query(
+                "select * from submissions where id = any($1)
+                and ($2::uuid is null or api_key_id = $2)",
+                params,
+            )
+            .await
+            .map_err(|_| InternalServerError(StringError::new("could not able to query")))?;
+
+        let mut found: HashMap<String, Submission> = rows
+            .into_iter()
+            .map(|row| submission_from_row(row, base64_encoded.0.unwrap_or(false)))
+            .map(|submission| (submission.id.clone(), submission))
+            .collect();
+        let submissions = ids
+            .iter()
+            .filter_map(|id| found.remove(&id.to_string()))
+            .collect();
+        Ok(Json(submissions))
+    }
+
+    /// Fetches a submission. `fields` is a comma separated list of the fields
+    /// to return, e.g. `id,status,score`; by default every field is returned.
+    /// With `base64_encoded=true` the source, stdin and outputs are base64.
+    #[oai(path = "/submissions/:id", method = "get")]
+    async fn get_submission(
+        &self,
+        data: Data<&Arc<AppData>>,
+        key: Data<&ApiKey>,
+        id: Path<String>,
+        fields: Query<Option<String>>,
+        base64_encoded: Query<Option<bool>>,
+    ) -> Result<SubmissionGetResponse> {
+        key.require(ApiKeyScope::Read)?;
+        let fields = Fields::parse(fields.0.as_deref())?;
+        let Ok(uuid) = Uuid::parse_str(&id.0) else {
+            return Ok(SubmissionGetResponse::NotFound(PlainText(format!(
+                "Submission `{}` not found",
+                id.0
+            ))));
+        };
+
+        match fetch_submission(
+            &data,
+            uuid,
+            key.owner(),
+            &fields,
+            base64_encoded.0.unwrap_or(false),
         )
-        
-        RETURNING id
-        "#,
+        .await?
+        {
+            Some(submission) => Ok(SubmissionGetResponse::Submission(Json(Box::new(
+                submission,
+            )))),
+            None => Ok(SubmissionGetResponse::NotFound(PlainText(format!(
+                "Submission `{}` not found",
+                id.0
+            )))),
+        }
+    }
+
+    /// Returns only the status of a submission, for clients polling until it
+    /// is finished.
+    #[oai(path = "/submissions/:id/status", method = "get")]
+    async fn get_submission_status(
+        &self,
+        data: Data<&Arc<AppData>>,
+        key: Data<&ApiKey>,
+        id: Path<String>,
+    ) -> Result<SubmissionStatusGetResponse> {
+        key.require(ApiKeyScope::Read)?;
+        let Ok(uuid) = Uuid::parse_str(&id.0) else {
+            return Ok(SubmissionStatusGetResponse::NotFound(PlainText(format!(
+                "Submission `{}` not found",
+                id.0
+            ))));
+        };
+        let owner = key.owner();
+        let params: &[&(dyn ToSql + Sync)] = &[&uuid, &owner];
+        let row = data
+            .db
+            .query_opt(
+                "select status, finished_at from submissions
+                where id= $1 and ($2::uuid is null or api_key_id = $2)",
                 params,
             )
             .await
-            .map_err(|e| {
-                InternalServerError(StringError::new(&format!("DB insert failed: {}", e)))
-            })?;
+            .map_err(|_| InternalServerError(StringError::new("could not able to query")))?;
+
+        match row {
+            Some(row) => Ok(SubmissionStatusGetResponse::Status(Json(
+                SubmissionStatusResponse {
+                    id: uuid.to_string(),
+                    status: row.get("status"),
+                    finished_at: row.get("finished_at"),
+                },
+            ))),
+            None => Ok(SubmissionStatusGetResponse::NotFound(PlainText(format!(
+                "Submission `{}` not found",
+                id.0
+            )))),
+        }
+    }
 
-        use uuid::Uuid;
+    /// Deletes or redacts a submission, depending on the configured deletion
+    /// policy. A queued submission is then skipped by the workers. An already
+    /// redacted submission counts as not found.
+    #[oai(path = "/submissions/:id", method = "delete")]
+    async fn delete_submission(
+        &self,
+        data: Data<&Arc<AppData>>,
+        key: Data<&ApiKey>,
+        id: Path<String>,
+        reason: Query<Option<String>>,
+    ) -> Result<SubmissionDeleteResponse> {
+        key.require(ApiKeyScope::Admin)?;
+        let Ok(uuid) = Uuid::parse_str(&id.0) else {
+            return Ok(SubmissionDeleteResponse::NotFound(PlainText(format!(
+                "Submission `{}` not found",
+                id.0
+            ))));
+        };
 
-        let id: Uuid = row.get("id");
-        let id_str = id.to_string();
+        let conditions = ["id = $1".to_string()];
+        let count =
+            delete_submissions(&data, &conditions, vec![&uuid], reason.0.as_deref()).await?;
+        if count == 0 {
+            return Ok(SubmissionDeleteResponse::NotFound(PlainText(format!(
+                "Submission `{}` not found",
+                id.0
+            ))));
+        }
+        Ok(SubmissionDeleteResponse::Deleted(Json(DeletionResponse {
+            action: data.config.deletion_policy.as_str().to_string(),
+            count,
+        })))
+    }
 
-        let submission = NatsSubmission::from((id_str.clone(), new_submission.0, language));
-        let json = serde_json::to_vec(&submission)
-            .map_err(|_| InternalServerError(StringError::new("couldnot serialize submission")))?;
+    /// Deletes or redacts every submission matching the filters, which take
+    /// the same form as for listing. At least one filter is required.
+    #[allow(clippy::too_many_arguments)]
+    #[oai(path = "/submissions", method = "delete")]
+    async fn delete_submissions(
+        &self,
+        data: Data<&Arc<AppData>>,
+        key: Data<&ApiKey>,
+        problem_id: Query<Option<String>>,
+        status: Query<Option<SubmissionStatus>>,
+        language: Query<Option<String>>,
+        created_after: Query<Option<DateTime<Utc>>>,
+        created_before: Query<Option<DateTime<Utc>>>,
+        tags: Query<Option<String>>,
+        reason: Query<Option<String>>,
+    ) -> Result<Json<DeletionResponse>> {
+        key.require(ApiKeyScope::Admin)?;
+        let filter = SubmissionFilter {
+            problem_id: SubmissionFilter::parse_problem_id(problem_id.0.as_deref())?,
+            status: status.0,
+            language: language.0,
+            created_after: created_after.0,
+            created_before: created_before.0,
+            tags: SubmissionFilter::parse_tags(tags.0.as_deref()),
+            owner: None,
+        };
+        if filter.is_empty() {
+            return Err(BadRequest(StringError::new(
+                "at least one filter is required to delete submissions",
+            )));
+        }
+
+        let mut params = Vec::new();
+        let conditions = filter.conditions(&mut params);
+        let count = delete_submissions(&data, &conditions, params, reason.0.as_deref()).await?;
+        Ok(Json(DeletionResponse {
+            action: data.config.deletion_policy.as_str().to_string(),
+            count,
+        }))
+    }
+
+    /// Cancels a queued or running submission. Workers skip it if it has not
+    /// started yet and kill its program otherwise.
+    #[oai(path = "/submissions/:id/cancel", method = "post")]
+    async fn cancel_submission(
+        &self,
+        data: Data<&Arc<AppData>>,
+        key: Data<&ApiKey>,
+        id: Path<String>,
+    ) -> Result<SubmissionCancelResponse> {
+        key.require(ApiKeyScope::Submit)?;
+        let Ok(uuid) = Uuid::parse_str(&id.0) else {
+            return Ok(SubmissionCancelResponse::NotFound(PlainText(format!(
+                "Submission `{}` not found",
+                id.0
+            ))));
+        };
+        let owner = key.owner();
+        let params: &[&(dyn ToSql + Sync)] = &[&uuid, &owner];
+        let row = data
+            .db
+            .query_opt(
+                "select status from submissions
+                where id= $1 and ($2::uuid is null or api_key_id = $2)",
+                params,
+            )
+            .await
+            .map_err(|_| InternalServerError(StringError::new("could not able to query")))?;
+        let Some(row) = row else {
+            return Ok(SubmissionCancelResponse::NotFound(PlainText(format!(
+                "Submission `{}` not found",
+                id.0
+            ))));
+        };
+        let status: SubmissionStatus = row.get("status");
+        if status.is_finished() {
+            return Ok(SubmissionCancelResponse::Finished(PlainText(format!(
+                "Submission `{}` is already finished",
+                id.0
+            ))));
+        }
+
+        // Flagged first so that a worker claiming it meanwhile still sees it.
         data.nats
-            .publish("submission.new".to_string(), json)
+            .cancel(&uuid.to_string())
             .await
-            .map_err(|_| InternalServerError(StringError::new("couldnot serialize submission")))?;
+            .map_err(|_| InternalServerError(StringError::new("couldnot cancel submission")))?;
 
-        Ok(Json(SubmissionResponse { id: id_str }))
+        let params: &[&(dyn ToSql + Sync)] = &[
+            &uuid,
+            &SubmissionStatus::Cancelled,
+            &SubmissionStatus::InQueue,
+        ];
+        let cancelled = data
+            .db
+            .query_opt(
+                "UPDATE submissions SET status = $2, finished_at = now()
+                WHERE id = $1 AND status = $3
+                RETURNING finished_at",
+                params,
+            )
+            .await
+            .map_err(|_| InternalServerError(StringError::new("could not able to query")))?;
+
+        let response = match cancelled {
+            // No worker will pick it up, so announce the verdict here.
+            Some(row) => {
+                announce_finished(&data, uuid, SubmissionStatus::Cancelled).await;
+                SubmissionStatusResponse {
+                    id: uuid.to_string(),
+                    status: SubmissionStatus::Cancelled,
+                    finished_at: row.get("finished_at"),
+                }
+            }
+            None => SubmissionStatusResponse {
+                id: uuid.to_string(),
+                status: SubmissionStatus::Processing,
+                finished_at: None,
+            },
+        };
+        Ok(SubmissionCancelResponse::Cancelled(Json(response)))
     }
 
-    #[oai(path = "/submissions/:id", method = "get")]
-    async fn get_submission(
+    /// Judges a finished submission again, e.g. after its problem's tests or
+    /// checker were fixed. The previous verdict is kept in the verdict history.
+    /// It is queued at `priority`, `low` by default.
+    #[oai(path = "/submissions/:id/rejudge", method = "post")]
+    async fn rejudge_submission(
         &self,
         data: Data<&Arc<AppData>>,
+        key: Data<&ApiKey>,
         id: Path<String>,
-    ) -> Result<SubmissionGetResponse> {
-        let params: &[&(dyn ToSql + Sync)] = &[&id.0];
+        priority: Query<Option<SubmissionPriority>>,
+    ) -> Result<SubmissionRejudgeResponse> {
+        key.require(ApiKeyScope::Admin)?;
+        let Ok(uuid) = Uuid::parse_str(&id.0) else {
+            return Ok(SubmissionRejudgeResponse::NotFound(PlainText(format!(
+                "Submission `{}` not found",
+                id.0
+            ))));
+        };
+        let params: &[&(dyn ToSql + Sync)] = &[&uuid];
         let row = data
             .db
-            .query_opt("select * from submissions where id= $1", params)
+            .query_opt(
+                "select status from submissions where id= $1 and redacted_at is null",
+                params,
+            )
             .await
             .map_err(|_| InternalServerError(StringError::new("could not able to query")))?;
+        let Some(row) = row else {
+            return Ok(SubmissionRejudgeResponse::NotFound(PlainText(format!(
+                "Submission `{}` not found",
+                id.0
+            ))));
+        };
+        if !row.get::<_, SubmissionStatus>("status").is_finished() {
+            return Ok(SubmissionRejudgeResponse::NotFinished(PlainText(format!(
+                "Submission `{}` is not finished yet",
+                id.0
+            ))));
+        }
 
-        match row {
-            Some(submission) => Ok(SubmissionGetResponse::Submission(Json(submission.into()))),
-            None => Ok(SubmissionGetResponse::NotFound(PlainText(format!(
+        let conditions = ["id = $1".to_string()];
+        let priority = priority.0.unwrap_or(SubmissionPriority::Low);
+        let count = rejudge_submissions(&data, &conditions, vec![&uuid], priority).await?;
+        Ok(SubmissionRejudgeResponse::Rejudged(Json(RejudgeResponse {
+            count,
+        })))
+    }
+
+    /// Judges every finished submission matching the filters again, which take
+    /// the same form as for listing. At least one filter is required. They are
+    /// queued at `priority`, `low` by default.
+    #[allow(clippy::too_many_arguments)]
+    #[oai(path = "/submissions/rejudge", method = "post")]
+    async fn rejudge_submissions(
+        &self,
+        data: Data<&Arc<AppData>>,
+        key: Data<&ApiKey>,
+        problem_id: Query<Option<String>>,
+        status: Query<Option<SubmissionStatus>>,
+        language: Query<Option<String>>,
+        created_after: Query<Option<DateTime<Utc>>>,
+        created_before: Query<Option<DateTime<Utc>>>,
+        tags: Query<Option<String>>,
+        priority: Query<Option<SubmissionPriority>>,
+    ) -> Result<Json<RejudgeResponse>> {
+        key.require(ApiKeyScope::Admin)?;
+        let filter = SubmissionFilter {
+            problem_id: SubmissionFilter::parse_problem_id(problem_id.0.as_deref())?,
+            status: status.0,
+            language: language.0,
+            created_after: created_after.0,
+            created_before: created_before.0,
+            tags: SubmissionFilter::parse_tags(tags.0.as_deref()),
+            owner: None,
+        };
+        if filter.is_empty() {
+            return Err(BadRequest(StringError::new(
+                "at least one filter is required to rejudge submissions",
+            )));
+        }
+
+        let mut params = Vec::new();
+        let conditions = filter.conditions(&mut params);
+        let priority = priority.0.unwrap_or(SubmissionPriority::Low);
+        let count = rejudge_submissions(&data, &conditions, params, priority).await?;
+        Ok(Json(RejudgeResponse { count }))
+    }
+
+    /// Lists every time a worker ran the submission, oldest first, to debug
+    /// verdicts that differ between runs.
+    #[oai(path = "/submissions/:id/attempts", method = "get")]
+    async fn submission_attempts(
+        &self,
+        data: Data<&Arc<AppData>>,
+        key: Data<&ApiKey>,
+        id: Path<String>,
+    ) -> Result<SubmissionAttemptsResponse> {
+        key.require(ApiKeyScope::Read)?;
+        let Ok(uuid) = Uuid::parse_str(&id.0) else {
+            return Ok(SubmissionAttemptsResponse::NotFound(PlainText(format!(
                 "Submission `{}` not found",
                 id.0
-            )))),
+            ))));
+        };
+        let owner = key.owner();
+        let params: &[&(dyn ToSql + Sync)] = &[&uuid, &owner];
+        let exists = data
+            .db
+            .query_opt(
+                "select id from submissions
+                where id= $1 and ($2::uuid is null or api_key_id = $2)",
+                params,
+            )
+            .await
+            .map_err(|_| InternalServerError(StringError::new("could not able to query")))?;
+        if exists.is_none() {
+            return Ok(SubmissionAttemptsResponse::NotFound(PlainText(format!(
+                "Submission `{}` not found",
+                id.0
+            ))));
         }
+
+        let rows = data
+            .db
+            .query(
+                "select * from submission_attempts where submission_id = $1 order by id",
+                &params[..1],
+            )
+            .await
+            .map_err(|_| InternalServerError(StringError::new("could not able to query")))?;
+        Ok(SubmissionAttemptsResponse::Attempts(Json(
+            rows.into_iter().map(SubmissionAttempt::from).collect(),
+        )))
+    }
+
+    /// Streams the progress of a submission as server-sent events, starting
+    /// from its current status and ending with the final verdict.
+    #[oai(path = "/submissions/:id/events", method = "get")]
+    async fn submission_events(
+        &self,
+        data: Data<&Arc<AppData>>,
+        key: Data<&ApiKey>,
+        id: Path<String>,
+    ) -> Result<SubmissionEventsResponse> {
+        key.require(ApiKeyScope::Read)?;
+        let Ok(uuid) = Uuid::parse_str(&id.0) else {
+            return Ok(SubmissionEventsResponse::NotFound(PlainText(format!(
+                "Submission `{}` not found",
+                id.0
+            ))));
+        };
+
+        // Subscribe before reading the current status so that no transition
+        // between the two is lost.
+        let subscriber = data
+            .nats
+            .subscribe(events_subject(&uuid.to_string()))
+            .await
+            .map_err(|_| InternalServerError(StringError::new("couldnot subscribe to events")))?;
+
+        let owner = key.owner();
+        let params: &[&(dyn ToSql + Sync)] = &[&uuid, &owner];
+        let row = data
+            .db
+            .query_opt(
+                "select status from submissions
+                where id= $1 and ($2::uuid is null or api_key_id = $2)",
+                params,
+            )
+            .await
+            .map_err(|_| InternalServerError(StringError::new("could not able to query")))?;
+        let Some(row) = row else {
+            return Ok(SubmissionEventsResponse::NotFound(PlainText(format!(
+                "Submission `{}` not found",
+                id.0
+            ))));
+        };
+
+        let current = SubmissionEvent::from_status(row.get("status"));
+        let updates = subscriber.filter_map(|message| {
+            ready(serde_json::from_slice::<SubmissionEvent>(&message.payload).ok())
+        });
+        let events = stream::once(ready(current))
+            .chain(updates)
+            .scan(false, |finished, event| {
+                if *finished {
+                    return ready(None);
+                }
+                *finished = event.stage == SubmissionStage::Finished;
+                ready(Some(event))
+            })
+            .boxed();
+
+        Ok(SubmissionEventsResponse::Events(
+            EventStream::new(events).keep_alive(SSE_KEEP_ALIVE),
+        ))
     }
 
     #[oai(path = "/languages/", method = "get")]
     async fn languages(&self, data: Data<&Arc<AppData>>) -> Result<Json<Vec<String>>> {
         Ok(Json(data.config.get_language_names()))
     }
+
+    /// Creates an API key with the given scopes and quotas.
+    #[oai(path = "/api-keys", method = "post")]
+    async fn create_api_key(
+        &self,
+        data: Data<&Arc<AppData>>,
+        key: Data<&ApiKey>,
+        new_key: Json<NewApiKey>,
+    ) -> Result<Json<CreatedApiKeyResponse>> {
+        key.require(ApiKeyScope::Admin)?;
+        if new_key.scopes.is_empty() {
+            return Err(BadRequest(StringError::new(
+                "an API key needs at least one scope",
+            )));
+        }
+        let (api_key, key) = create_api_key(&data.db, &new_key)
+            .await
+            .map_err(|_| InternalServerError(StringError::new("could not create API key")))?;
+        Ok(Json(CreatedApiKeyResponse { key, api_key }))
+    }
+
+    /// Lists every API key, including revoked ones.
+    #[oai(path = "/api-keys", method = "get")]
+    async fn list_api_keys(
+        &self,
+        data: Data<&Arc<AppData>>,
+        key: Data<&ApiKey>,
+    ) -> Result<Json<Vec<ApiKey>>> {
+        key.require(ApiKeyScope::Admin)?;
+        let rows = data
+            .db
+            .query("select * from api_keys order by created_at", &[])
+            .await
+            .map_err(|_| InternalServerError(StringError::new("could not able to query")))?;
+        Ok(Json(rows.into_iter().map(ApiKey::from).collect()))
+    }
+
+    /// Revokes an API key. Its submissions are kept and stay visible to admins.
+    #[oai(path = "/api-keys/:id", method = "delete")]
+    async fn revoke_api_key(
+        &self,
+        data: Data<&Arc<AppData>>,
+        key: Data<&ApiKey>,
+        id: Path<String>,
+    ) -> Result<ApiKeyRevokeResponse> {
+        key.require(ApiKeyScope::Admin)?;
+        let Ok(uuid) = Uuid::parse_str(&id.0) else {
+            return Ok(ApiKeyRevokeResponse::NotFound(PlainText(format!(
+                "API key `{}` not found",
+                id.0
+            ))));
+        };
+        let params: &[&(dyn ToSql + Sync)] = &[&uuid];
+        let row = data
+            .db
+            .query_opt(
+                "UPDATE api_keys SET revoked_at = now()
+                WHERE id = $1 AND revoked_at IS NULL
+                RETURNING *",
+                params,
+            )
+            .await
+            .map_err(|_| InternalServerError(StringError::new("could not able to query")))?;
+        match row {
+            Some(row) => Ok(ApiKeyRevokeResponse::Revoked(Json(ApiKey::from(row)))),
+            None => Ok(ApiKeyRevokeResponse::NotFound(PlainText(format!(
+                "API key `{}` not found",
+                id.0
+            )))),
+        }
+    }
 }
 
 struct AppData {
     db: db::Db,
     config: AppConfig,
     nats: NatsClient,
+    /// Wakes the outbox relay once queue messages were committed.
+    outbox: Notify,
+    workers: LiveWorkers,
 }
 
 #[tokio::main]
@@ -176,18 +1106,29 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         db: db::Db::init(&database_url).await.expect("couldnot init db"),
         config: load_config()?,
         nats: client,
+        outbox: Notify::new(),
+        workers: LiveWorkers::default(),
     });
 
+    tokio::spawn(run_relay(app_data.clone()));
+    tokio::spawn(run_reconciler(app_data.clone()));
+    tokio::spawn(run_reaper(app_data.clone()));
+
+    let rate_limit = RateLimit::new(app_data.config.rate_limit.clone())?;
+    let rate_limited = app_data.config.rate_limit.enabled;
+
     let api_service = OpenApiService::new(Api, "Executor", "0.0.1").server("http://localhost:3000");
     let ui = api_service.swagger_ui();
     let spec = api_service.spec();
     let route = Route::new()
-        .nest("/", api_service)
+        .nest("/", api_service.into_endpoint().with(ApiKeyAuth))
         .nest("/docs", ui)
         .at(
             "/openapi.json",
             poem::endpoint::make_sync(move |_| spec.clone()),
         )
+        .at("/sessions", get(sessions::session).with(ApiKeyAuth))
+        .with_if(rate_limited, rate_limit)
         .with(Cors::new())
         .data(app_data);
 
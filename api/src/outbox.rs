@@ -0,0 +1,206 @@
+use std::sync::Arc;
+
+use common::{
+    error::StringError,
+    model::{NatsSubmission, Submission},
+};
+use deadpool_postgres::GenericClient;
+use poem::{Result, error::InternalServerError};
+use tokio::time::{interval, timeout};
+use tokio_postgres::types::ToSql;
+use uuid::Uuid;
+
+use crate::AppData;
+use crate::problems::ProblemCache;
+use crate::submissions::{fail_submission, requeue_submission};
+
+/// Messages the relay publishes per round.
+const RELAY_BATCH_SIZE: i64 = 100;
+
+/// How long in seconds a relay keeps the messages it took before another may
+/// take them again, well past publishing a batch.
+const RELAY_LEASE: f64 = 300.0;
+
+/// Submissions the reconciler queues again per round.
+const RECONCILE_BATCH_SIZE: i64 = 100;
+
+/// Writes the queue message of submission `id` to the outbox. Call it in the
+/// transaction that queues the submission and wake the relay once committed.
+pub async fn enqueue(
+    client: &impl GenericClient,
+    id: Uuid,
+    subject: &str,
+    submission: &NatsSubmission,
+) -> Result<()> {
+    let payload = serde_json::to_vec(submission)
+        .map_err(|_| InternalServerError(StringError::new("couldnot serialize submission")))?;
+    let params: &[&(dyn ToSql + Sync)] = &[&id, &subject, &payload];
+    client
+        .execute(
+            "INSERT INTO submission_outbox (submission_id, subject, payload) VALUES ($1, $2, $3)",
+            params,
+        )
+        .await
+        .map_err(|_| InternalServerError(StringError::new("could not able to query")))?;
+    Ok(())
+}
+
+/// Publishes pending outbox messages and marks them sent, whenever woken and
+/// at least every poll interval. Every API instance runs a relay and each
+/// message is taken by one of them for a lease. Messages are published at
+/// least once: one whose mark is lost to a crash is published again once the
+/// lease expired, and workers drop the duplicates.
+pub async fn run_relay(data: Arc<AppData>) {
+    loop {
+        match relay(&data).await {
+            // More are probably waiting.
+            Ok(sent) if sent == RELAY_BATCH_SIZE as usize => continue,
+            Ok(_) => {}
+            Err(err) => eprintln!("❌ Outbox relay error: {err}"),
+        }
+        let _ = timeout(data.config.outbox_poll_interval, data.outbox.notified()).await;
+    }
+}
+
+/// Publishes one batch of pending messages in order and returns how many were
+/// sent. The messages are taken for a lease first, so that no transaction is
+/// open while waiting for NATS, and marked once published.
+async fn relay(data: &AppData) -> anyhow::Result<usize> {
+    let mut rows = data
+        .db
+        .query(
+            r#"
+            UPDATE submission_outbox SET claimed_until = now() + make_interval(secs => $2)
+            WHERE id IN (
+                SELECT id FROM submission_outbox
+                WHERE sent_at IS NULL AND (claimed_until IS NULL OR claimed_until < now())
+                ORDER BY id
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, subject, payload
+            "#,
+            &[&RELAY_BATCH_SIZE, &RELAY_LEASE],
+        )
+        .await?;
+    rows.sort_by_key(|row| row.get::<_, i64>("id"));
+
+    let mut sent = Vec::new();
+    let mut failed = None;
+    let mut taken = rows
+        .iter()
+        .map(|row| row.get::<_, i64>("id"))
+        .collect::<Vec<_>>();
+    for row in &rows {
+        let id: i64 = row.get("id");
+        match data
+            .nats
+            .publish(row.get("subject"), row.get("payload"))
+            .await
+        {
+            Ok(()) => sent.push(id),
+            Err(err) => {
+                failed = Some((id, err.to_string()));
+                // NATS is most likely unavailable, the rest waits for the next round.
+                break;
+            }
+        }
+    }
+    taken.retain(|id| !sent.contains(id));
+
+    let mut client = data.db.pool.get().await?;
+    let tx = client.transaction().await?;
+    tx.execute(
+        "UPDATE submission_outbox SET sent_at = now(), attempts = attempts + 1 WHERE id = ANY($1)",
+        &[&sent],
+    )
+    .await?;
+    if let Some((id, err)) = failed {
+        tx.execute(
+            "UPDATE submission_outbox SET attempts = attempts + 1, last_error = $2 WHERE id = $1",
+            &[&id, &err],
+        )
+        .await?;
+    }
+    // The unsent messages are released for the next round.
+    tx.execute(
+        "UPDATE submission_outbox SET claimed_until = NULL WHERE id = ANY($1)",
+        &[&taken],
+    )
+    .await?;
+    tx.commit().await?;
+    Ok(sent.len())
+}
+
+/// Every reconcile interval, queues submissions again that have been queued
+/// for longer than the configured threshold without an outbox message since,
+/// e.g. after a rejudge that stopped between resetting and queueing them.
+/// Pending messages are left to the relay. Also drops the messages of
+/// submissions that are no longer queued.
+pub async fn run_reconciler(data: Arc<AppData>) {
+    let mut ticks = interval(data.config.reconcile_interval);
+    loop {
+        ticks.tick().await;
+        match reconcile(&data).await {
+            Ok(0) => {}
+            Ok(requeued) => {
+                println!("♻️ Requeued {requeued} submissions without a queue message");
+                data.outbox.notify_one();
+            }
+            Err(err) => eprintln!("❌ Outbox reconciler error: {err}"),
+        }
+    }
+}
+
+async fn reconcile(data: &AppData) -> anyhow::Result<usize> {
+    data.db
+        .execute(
+            r#"
+            DELETE FROM submission_outbox o
+            WHERE sent_at IS NOT NULL AND NOT EXISTS (
+                SELECT 1 FROM submissions s
+                WHERE s.id = o.submission_id AND s.status = 'inqueue'
+            )
+            "#,
+            &[],
+        )
+        .await?;
+
+    let stale_after = data.config.reconcile_after.as_secs_f64();
+    let mut client = data.db.pool.get().await?;
+    let tx = client.transaction().await?;
+    let rows = tx
+        .query(
+            r#"
+            SELECT * FROM submissions s
+            WHERE status = 'inqueue'
+            AND queued_at < now() - make_interval(secs => $1)
+            AND NOT EXISTS (
+                SELECT 1 FROM submission_outbox o
+                WHERE o.submission_id = s.id AND o.created_at >= s.queued_at
+            )
+            ORDER BY queued_at
+            LIMIT $2
+            FOR UPDATE SKIP LOCKED
+            "#,
+            &[&stale_after, &RECONCILE_BATCH_SIZE],
+        )
+        .await?;
+
+    let mut problems = ProblemCache::default();
+    let mut requeued = 0;
+    for row in rows {
+        let submission = Submission::from(row);
+        let id = Uuid::parse_str(&submission.id).expect("ids are uuids");
+        if submission.redacted_at.is_some() {
+            fail_submission(&tx, id, "could not requeue: its code was redacted").await?;
+            continue;
+        }
+        match requeue_submission(data, &tx, &mut problems, id, submission.into()).await {
+            Ok(()) => requeued += 1,
+            Err(err) => fail_submission(&tx, id, &format!("could not requeue: {}", err)).await?,
+        }
+    }
+    tx.commit().await?;
+    Ok(requeued)
+}
@@ -0,0 +1,253 @@
+use std::collections::{HashMap, hash_map::Entry};
+
+use anyhow::Result;
+use common::{
+    db::Db,
+    error::StringError,
+    model::{Generator, NewSubmission, Program, Template},
+};
+use poem::error::{BadRequest, InternalServerError};
+use tokio_postgres::{Row, types::ToSql};
+use uuid::Uuid;
+
+use crate::config::AppConfig;
+
+/// Source of a stored jury program; `language` is a configured language name.
+#[derive(Clone)]
+pub struct ProgramSource {
+    pub source_code: String,
+    pub language: String,
+}
+
+impl ProgramSource {
+    fn from_columns(row: &Row, source: &str, language: &str) -> Option<Self> {
+        Some(Self {
+            source_code: row.get::<_, Option<String>>(source)?,
+            language: row.get::<_, Option<String>>(language)?,
+        })
+    }
+
+    fn resolve(&self, config: &AppConfig) -> poem::Result<Program> {
+        let language = config.get_language(&self.language).ok_or(InternalServerError(
+            StringError::new(&format!("language `{}` is not configured", self.language)),
+        ))?;
+        Ok(Program {
+            source_code: self.source_code.clone(),
+            language,
+        })
+    }
+}
+
+/// A stored problem with everything needed to judge a submission against it,
+/// except for its tests, which workers fetch by version themselves.
+#[derive(Clone)]
+pub struct Problem {
+    pub version: i32,
+    pub time_limit: Option<f64>,
+    pub memory_limit: Option<f64>,
+    pub checker: Option<ProgramSource>,
+    pub interactor: Option<ProgramSource>,
+    pub validator: Option<ProgramSource>,
+    pub main_solution: Option<ProgramSource>,
+    pub generators: Vec<(String, ProgramSource)>,
+    pub input_file: Option<String>,
+    pub output_file: Option<String>,
+    /// Sum of the points of all test groups.
+    pub max_score: f64,
+}
+
+pub async fn load_problem(db: &Db, id: Uuid) -> Result<Option<Problem>> {
+    let params: &[&(dyn ToSql + Sync)] = &[&id];
+
+    let Some(row) = db
+        .query_opt(
+            r#"
+        SELECT
+            version,
+            time_limit,
+            memory_limit,
+            checker_source,
+            checker_language,
+            validator_source,
+            validator_language,
+            main_solution_source,
+            main_solution_language,
+            interactor_source,
+            interactor_language,
+            input_file,
+            output_file,
+            (
+                SELECT coalesce(sum(points), 0) FROM problem_test_groups
+                WHERE problem_id = problems.id
+            ) AS max_score
+        FROM problems
+        WHERE id = $1
+        "#,
+            params,
+        )
+        .await?
+    else {
+        return Ok(None);
+    };
+
+    let generators = db
+        .query(
+            "SELECT name, source, language FROM problem_generators WHERE problem_id = $1",
+            params,
+        )
+        .await?
+        .iter()
+        .map(|generator| {
+            (
+                generator.get("name"),
+                ProgramSource {
+                    source_code: generator.get("source"),
+                    language: generator.get("language"),
+                },
+            )
+        })
+        .collect();
+
+    Ok(Some(Problem {
+        version: row.get("version"),
+        time_limit: row.get("time_limit"),
+        memory_limit: row.get("memory_limit"),
+        checker: ProgramSource::from_columns(&row, "checker_source", "checker_language"),
+        interactor: ProgramSource::from_columns(&row, "interactor_source", "interactor_language"),
+        validator: ProgramSource::from_columns(&row, "validator_source", "validator_language"),
+        main_solution: ProgramSource::from_columns(
+            &row,
+            "main_solution_source",
+            "main_solution_language",
+        ),
+        generators,
+        input_file: row.get("input_file"),
+        output_file: row.get("output_file"),
+        max_score: row.get("max_score"),
+    }))
+}
+
+/// The parts of a problem that travel with a submission to the worker.
+pub struct ResolvedProblem {
+    pub id: Uuid,
+    pub version: i32,
+    pub max_score: f64,
+    pub checker: Option<Program>,
+    pub interactor: Option<Program>,
+    pub validator: Option<Program>,
+    pub main_solution: Option<Program>,
+    pub generators: Vec<Generator>,
+    pub template: Option<Template>,
+}
+
+/// Problems and templates loaded while handling one request, so that its
+/// submissions to the same problem load it only once.
+#[derive(Default)]
+pub struct ProblemCache {
+    problems: HashMap<Uuid, Problem>,
+    templates: HashMap<(Uuid, String), Option<Template>>,
+}
+
+/// Looks up the problem referenced by `new_submission`, if any, and fills in
+/// the limits and I/O files the submission left unset from the problem.
+pub async fn resolve_problem(
+    db: &Db,
+    config: &AppConfig,
+    cache: &mut ProblemCache,
+    new_submission: &mut NewSubmission,
+) -> poem::Result<Option<ResolvedProblem>> {
+    let Some(problem_id) = &new_submission.problem_id else {
+        return Ok(None);
+    };
+
+    if new_submission.test_groups.is_some() {
+        return Err(BadRequest(StringError::new(
+            "test_groups cannot be combined with problem_id",
+        )));
+    }
+
+    let id = Uuid::parse_str(problem_id)
+        .map_err(|_| BadRequest(StringError::new("invalid problem_id")))?;
+    let problem = match cache.problems.entry(id) {
+        Entry::Occupied(entry) => entry.into_mut(),
+        Entry::Vacant(entry) => entry.insert(
+            load_problem(db, id)
+                .await
+                .map_err(|_| InternalServerError(StringError::new("could not load problem")))?
+                .ok_or(BadRequest(StringError::new("unknown problem")))?,
+        ),
+    };
+
+    let template = match cache.templates.entry((id, new_submission.language.clone())) {
+        Entry::Occupied(entry) => entry.get().clone(),
+        Entry::Vacant(entry) => entry
+            .insert(
+                load_template(db, id, &new_submission.language)
+                    .await
+                    .map_err(|_| InternalServerError(StringError::new("could not load problem")))?,
+            )
+            .clone(),
+    };
+
+    new_submission.cpu_time_limit = new_submission.cpu_time_limit.or(problem.time_limit);
+    new_submission.memory_limit = new_submission.memory_limit.or(problem.memory_limit);
+    if new_submission.input_file.is_none() {
+        new_submission.input_file = problem.input_file.clone();
+    }
+    if new_submission.output_file.is_none() {
+        new_submission.output_file = problem.output_file.clone();
+    }
+
+    Ok(Some(ResolvedProblem {
+        id,
+        version: problem.version,
+        max_score: problem.max_score,
+        checker: problem
+            .checker
+            .as_ref()
+            .map(|c| c.resolve(config))
+            .transpose()?,
+        interactor: problem
+            .interactor
+            .as_ref()
+            .map(|i| i.resolve(config))
+            .transpose()?,
+        validator: problem
+            .validator
+            .as_ref()
+            .map(|v| v.resolve(config))
+            .transpose()?,
+        main_solution: problem
+            .main_solution
+            .as_ref()
+            .map(|s| s.resolve(config))
+            .transpose()?,
+        generators: problem
+            .generators
+            .iter()
+            .map(|(name, source)| {
+                Ok(Generator {
+                    name: name.clone(),
+                    program: source.resolve(config)?,
+                })
+            })
+            .collect::<poem::Result<_>>()?,
+        template,
+    }))
+}
+
+/// Template of a function-style problem for `language`, if it has one.
+async fn load_template(db: &Db, id: Uuid, language: &str) -> Result<Option<Template>> {
+    let params: &[&(dyn ToSql + Sync)] = &[&id, &language];
+    let row = db
+        .query_opt(
+            "SELECT template, harness FROM problem_templates WHERE problem_id = $1 AND language = $2",
+            params,
+        )
+        .await?;
+
+    Ok(row.map(|row| Template {
+        template: row.get("template"),
+        harness: row.get("harness"),
+    }))
+}
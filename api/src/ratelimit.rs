@@ -0,0 +1,119 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::{
+    auth::{ApiKey, find_api_key},
+    error::StringError,
+    redis::RedisClient,
+};
+use poem::{
+    Endpoint, IntoResponse, Middleware, Request, Response, Result,
+    error::ServiceUnavailable,
+    http::{StatusCode, header},
+};
+use tokio::time::timeout;
+
+use crate::AppData;
+use crate::auth::presented_key;
+use crate::config::{FailureMode, RateLimitConfig};
+
+/// Longest time a request waits for Redis before the failure mode applies.
+const REDIS_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Limits requests with a token bucket in Redis per API key, or per client IP
+/// for requests without one, so that the limit holds across API instances.
+/// Rejected requests get `429` with a `Retry-After` header.
+pub struct RateLimit {
+    redis: Arc<RedisClient>,
+    config: RateLimitConfig,
+}
+
+impl RateLimit {
+    pub fn new(config: RateLimitConfig) -> anyhow::Result<Self> {
+        Ok(Self {
+            redis: Arc::new(RedisClient::new(&config.redis_url)?),
+            config,
+        })
+    }
+}
+
+impl<E: Endpoint> Middleware<E> for RateLimit {
+    type Output = RateLimitEndpoint<E>;
+
+    fn transform(&self, ep: E) -> Self::Output {
+        RateLimitEndpoint {
+            ep,
+            redis: Arc::clone(&self.redis),
+            config: self.config.clone(),
+        }
+    }
+}
+
+pub struct RateLimitEndpoint<E> {
+    ep: E,
+    redis: Arc<RedisClient>,
+    config: RateLimitConfig,
+}
+
+impl<E: Endpoint> Endpoint for RateLimitEndpoint<E> {
+    type Output = Response;
+
+    async fn call(&self, mut req: Request) -> Result<Self::Output> {
+        let api_key = validated_key(&req).await;
+        let bucket = bucket_key(&req, api_key.as_ref());
+        let taken = timeout(
+            REDIS_TIMEOUT,
+            self.redis
+                .take_token(&bucket, self.config.requests_per_second, self.config.burst),
+        )
+        .await;
+
+        match taken {
+            Ok(Ok(None)) => {}
+            Ok(Ok(Some(retry_after))) => {
+                // Whole seconds, rounded up so that a retry is never too early.
+                let seconds = retry_after.as_millis().div_ceil(1000).max(1);
+                return Ok(Response::builder()
+                    .status(StatusCode::TOO_MANY_REQUESTS)
+                    .header(header::RETRY_AFTER, seconds.to_string())
+                    .body("rate limit exceeded"));
+            }
+            Ok(Err(_)) | Err(_) => {
+                if self.config.failure_mode == FailureMode::Closed {
+                    return Err(ServiceUnavailable(StringError::new(
+                        "rate limiter unavailable",
+                    )));
+                }
+            }
+        }
+
+        // Spares authentication from looking the key up again.
+        if let Some(api_key) = api_key {
+            req.extensions_mut().insert(api_key);
+        }
+        self.ep.call(req).await.map(IntoResponse::into_response)
+    }
+}
+
+/// The API key a request presented, if it is valid.
+async fn validated_key(req: &Request) -> Option<ApiKey> {
+    let data = req.data::<Arc<AppData>>()?;
+    find_api_key(&data.db, presented_key(req)?)
+        .await
+        .ok()
+        .flatten()
+}
+
+/// The bucket of a request: its API key if valid, or else the client IP, so
+/// that made up keys do not get buckets of their own.
+fn bucket_key(req: &Request, api_key: Option<&ApiKey>) -> String {
+    if let Some(api_key) = api_key {
+        return format!("ratelimit:key:{}", api_key.id);
+    }
+    let ip = req
+        .remote_addr()
+        .as_socket_addr()
+        .map(|addr| addr.ip().to_string())
+        .unwrap_or_default();
+    format!("ratelimit:ip:{}", ip)
+}
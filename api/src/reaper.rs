@@ -0,0 +1,136 @@
+use std::sync::Arc;
+
+use common::model::{Submission, SubmissionStatus};
+use tokio::time::interval;
+use tokio_postgres::types::ToSql;
+use uuid::Uuid;
+
+use crate::AppData;
+use crate::problems::ProblemCache;
+use crate::submissions::{announce_finished, fail_submission, requeue_submission};
+
+/// Submissions the reaper handles per round.
+const REAP_BATCH_SIZE: i64 = 100;
+
+/// Every reap interval, recovers submissions left `processing` by a worker that
+/// died: ones running for longer than their wall time limit plus the
+/// configured grace period whose latest attempt has not renewed its heartbeat
+/// within the heartbeat timeout. They are queued again, or finished as
+/// `InternalError` once they used up their attempts.
+pub async fn run_reaper(data: Arc<AppData>) {
+    let mut ticks = interval(data.config.reap_interval);
+    loop {
+        ticks.tick().await;
+        match reap(&data).await {
+            Ok((0, 0)) => {}
+            Ok((requeued, failed)) => {
+                println!("🪦 Requeued {requeued} and failed {failed} abandoned submissions");
+                data.outbox.notify_one();
+            }
+            Err(err) => eprintln!("❌ Reaper error: {err}"),
+        }
+    }
+}
+
+/// Handles one batch of abandoned submissions and returns how many were
+/// requeued and how many failed.
+async fn reap(data: &AppData) -> anyhow::Result<(usize, usize)> {
+    // Sessions only matter to the quotas while running or recent.
+    data.db
+        .execute(
+            r#"
+            DELETE FROM api_key_sessions
+            WHERE started_at < now() - interval '1 minute'
+            AND (finished_at IS NOT NULL OR expires_at < now())
+            "#,
+            &[],
+        )
+        .await?;
+
+    let default_wall_time = data.config.submission_defaults.wall_time_limit;
+    let grace = data.config.reap_grace.as_secs_f64();
+    let heartbeat_timeout = data.config.heartbeat_timeout.as_secs_f64();
+    let params: &[&(dyn ToSql + Sync)] = &[
+        &default_wall_time,
+        &grace,
+        &heartbeat_timeout,
+        &REAP_BATCH_SIZE,
+    ];
+
+    let mut client = data.db.pool.get().await?;
+    let tx = client.transaction().await?;
+    // Attempts before the submission was last queued belong to earlier
+    // verdicts.
+    let rows = tx
+        .query(
+            r#"
+            SELECT s.*, a.attempt_id, coalesce(a.attempts, 0) AS attempts
+            FROM submissions s
+            LEFT JOIN LATERAL (
+                SELECT id AS attempt_id, started_at, heartbeat_at, count(*) OVER () AS attempts
+                FROM submission_attempts
+                WHERE submission_id = s.id AND started_at >= s.queued_at
+                ORDER BY started_at DESC
+                LIMIT 1
+            ) a ON true
+            WHERE s.status = 'processing'
+            AND coalesce(a.started_at, s.queued_at)
+                < now() - make_interval(secs => coalesce(s.wall_time_limit, $1) + $2)
+            AND coalesce(a.heartbeat_at, s.queued_at) < now() - make_interval(secs => $3)
+            ORDER BY s.queued_at
+            LIMIT $4
+            FOR UPDATE OF s SKIP LOCKED
+            "#,
+            params,
+        )
+        .await?;
+
+    let mut problems = ProblemCache::default();
+    let mut requeued = 0;
+    let mut failed = Vec::new();
+    for row in rows {
+        let attempt: Option<i64> = row.get("attempt_id");
+        let attempts: i64 = row.get("attempts");
+        let submission = Submission::from(row);
+        let id = Uuid::parse_str(&submission.id).expect("ids are uuids");
+
+        if let Some(attempt) = attempt {
+            let params: &[&(dyn ToSql + Sync)] = &[&attempt, &SubmissionStatus::InternalError];
+            tx.execute(
+                "UPDATE submission_attempts
+                SET status = $2, message = 'worker stopped responding', finished_at = now()
+                WHERE id = $1",
+                params,
+            )
+            .await?;
+        }
+
+        let message = if attempts >= data.config.max_attempts as i64 {
+            format!(
+                "gave up after {} attempts whose worker stopped responding",
+                attempts
+            )
+        } else if submission.redacted_at.is_some() {
+            "could not requeue: its code was redacted".to_string()
+        } else {
+            let params: &[&(dyn ToSql + Sync)] = &[&id, &SubmissionStatus::InQueue];
+            tx.execute("UPDATE submissions SET status = $2 WHERE id = $1", params)
+                .await?;
+            match requeue_submission(data, &tx, &mut problems, id, submission.into()).await {
+                Ok(()) => {
+                    requeued += 1;
+                    continue;
+                }
+                Err(err) => format!("could not requeue: {}", err),
+            }
+        };
+        fail_submission(&tx, id, &message).await?;
+        failed.push(id);
+    }
+    tx.commit().await?;
+
+    for &id in &failed {
+        announce_finished(data, id, SubmissionStatus::InternalError).await;
+    }
+    Ok((requeued, failed.len()))
+}
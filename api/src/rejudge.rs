@@ -0,0 +1,175 @@
+use common::{
+    error::StringError,
+    model::{Submission, SubmissionPriority},
+};
+use poem::{Result, error::InternalServerError};
+use tokio_postgres::types::ToSql;
+use uuid::Uuid;
+
+use crate::AppData;
+use crate::problems::ProblemCache;
+use crate::submissions::{fail_submission, requeue_submission};
+
+/// Submissions a rejudge resets and queues again per transaction.
+const REJUDGE_BATCH_SIZE: i64 = 100;
+
+/// Moves the verdict of every finished submission matching `conditions` into
+/// `submission_verdicts`, resets it to queued and queues it at `priority`.
+/// Submissions are taken in batches by id, each in one transaction so that
+/// none is left reset without a queue message. Redacted submissions are left
+/// alone since their code is gone. Returns how many submissions were requeued.
+pub async fn rejudge_submissions(
+    data: &AppData,
+    conditions: &[String],
+    mut params: Vec<&(dyn ToSql + Sync)>,
+    priority: SubmissionPriority,
+) -> Result<u64> {
+    let priority = priority.as_str();
+    params.push(&priority);
+    let priority_param = params.len();
+    let after_param = params.len() + 1;
+    let limit_param = params.len() + 2;
+
+    let filter = match conditions {
+        [] => "true".to_string(),
+        _ => conditions.join(" and "),
+    };
+    let sql = format!(
+        r#"
+        WITH previous AS (
+            INSERT INTO submission_verdicts (
+                submission_id,
+                status,
+                stdout,
+                stderr,
+                compile_output,
+                message,
+                exit_code,
+                exit_signal,
+                time,
+                wall_time,
+                memory,
+                score,
+                max_score,
+                group_results,
+                stress_result,
+                finished_at
+            )
+            SELECT
+                id,
+                status,
+                stdout,
+                stderr,
+                compile_output,
+                message,
+                exit_code,
+                exit_signal,
+                time,
+                wall_time,
+                memory,
+                score,
+                max_score,
+                group_results,
+                stress_result,
+                finished_at
+            FROM submissions
+            WHERE {} AND status NOT IN ('inqueue', 'processing') AND redacted_at IS NULL
+            AND id > ${}
+            ORDER BY id
+            LIMIT ${}
+            RETURNING submission_id
+        )
+        UPDATE submissions SET
+            status = 'inqueue',
+            stdout = NULL,
+            stderr = NULL,
+            compile_output = NULL,
+            message = NULL,
+            exit_code = NULL,
+            exit_signal = NULL,
+            time = NULL,
+            wall_time = NULL,
+            memory = NULL,
+            score = NULL,
+            max_score = NULL,
+            group_results = NULL,
+            stress_result = NULL,
+            finished_at = NULL,
+            cached_from = NULL,
+            queued_at = now(),
+            priority = ${}
+        FROM previous
+        WHERE submissions.id = previous.submission_id
+        RETURNING submissions.*
+        "#,
+        filter, after_param, limit_param, priority_param
+    );
+
+    let mut after = Uuid::nil();
+    let mut requeued = 0;
+    loop {
+        let mut batch_params = params.clone();
+        batch_params.push(&after);
+        batch_params.push(&REJUDGE_BATCH_SIZE);
+        let batch = rejudge_batch(data, &sql, &batch_params).await?;
+        requeued += batch.requeued;
+        match batch.last {
+            Some(last) if batch.taken == REJUDGE_BATCH_SIZE as usize => after = last,
+            _ => return Ok(requeued),
+        }
+    }
+}
+
+/// What one batch of a rejudge took.
+struct Batch {
+    /// How many submissions were reset.
+    taken: usize,
+    /// How many of them were queued again.
+    requeued: u64,
+    /// The greatest id reset, where the next batch starts.
+    last: Option<Uuid>,
+}
+
+/// Resets and queues the next batch of a rejudge in one transaction. The
+/// cancellations of the queued submissions are withdrawn once committed, before
+/// the relay is woken to publish them.
+async fn rejudge_batch(data: &AppData, sql: &str, params: &[&(dyn ToSql + Sync)]) -> Result<Batch> {
+    let mut client = data.db.pool.get().await.map_err(InternalServerError)?;
+    let tx = client.transaction().await.map_err(InternalServerError)?;
+    let rows = tx
+        .query(sql, params)
+        .await
+        .map_err(|_| InternalServerError(StringError::new("could not reset submissions")))?;
+
+    // Rejudges usually cover many submissions of the same problems.
+    let taken = rows.len();
+    let mut problems = ProblemCache::default();
+    let mut requeued = Vec::new();
+    let mut last = None;
+    for row in rows {
+        let submission = Submission::from(row);
+        let id = Uuid::parse_str(&submission.id).expect("ids are uuids");
+        last = last.max(Some(id));
+        match requeue_submission(data, &tx, &mut problems, id, submission.into()).await {
+            Ok(()) => requeued.push(id),
+            Err(err) => {
+                let message = format!("could not rejudge: {}", err);
+                fail_submission(&tx, id, &message).await?
+            }
+        }
+    }
+    tx.commit().await.map_err(InternalServerError)?;
+
+    // A submission cancelled earlier must not be skipped this time.
+    for id in &requeued {
+        if let Err(err) = data.nats.uncancel(&id.to_string()).await {
+            eprintln!("❌ Could not clear the cancellation of {id}: {err}");
+        }
+    }
+    data.outbox.notify_one();
+    Ok(Batch {
+        taken,
+        requeued: requeued.len() as u64,
+        last,
+    })
+}
@@ -0,0 +1,176 @@
+use std::sync::Arc;
+
+use common::{
+    auth::{ApiKey, ApiKeyScope},
+    model::{NatsSubmission, NewSubmission, SessionInput, SessionOutput},
+    nats::{session_request_subject, session_subject},
+};
+use futures::{SinkExt, StreamExt};
+use poem::{
+    IntoResponse, Result, handler,
+    web::{
+        Data,
+        websocket::{Message, WebSocket, WebSocketStream},
+    },
+};
+use poem_openapi::types::ParseFromJSON;
+use tokio::time::timeout;
+use uuid::Uuid;
+
+use crate::AppData;
+use crate::auth::{finish_session, reserve_session};
+
+/// Upgrades to a WebSocket running an interactive session. The client sends a
+/// submission as its first text message, then program input as text messages
+/// and an empty binary message to close stdin. Every message from the server is
+/// a JSON [`SessionOutput`]; the socket is closed after the last one.
+#[handler]
+pub async fn session(
+    ws: WebSocket,
+    data: Data<&Arc<AppData>>,
+    api_key: Data<&ApiKey>,
+) -> Result<impl IntoResponse> {
+    api_key.require(ApiKeyScope::Submit)?;
+    let data = Arc::clone(&data);
+    let api_key = api_key.0.clone();
+    Ok(ws.on_upgrade(move |mut ws| async move {
+        if let Err(err) = run_session(&data, &api_key, &mut ws).await {
+            let error = SessionOutput::Error {
+                message: err.to_string(),
+            };
+            let _ = send(&mut ws, &error).await;
+        }
+        let _ = ws.close().await;
+    }))
+}
+
+async fn run_session(data: &AppData, key: &ApiKey, ws: &mut WebSocketStream) -> anyhow::Result<()> {
+    let Some(first) = ws.next().await.transpose()? else {
+        return Ok(());
+    };
+    let new_submission = NewSubmission::parse_from_json_string(match &first {
+        Message::Text(text) => text,
+        _ => "",
+    })
+    .map_err(|err| anyhow::anyhow!("invalid submission: {}", err.message()))?;
+    let submission = start_session(data, key, new_submission)?;
+    data.workers
+        .require(&data.nats, &submission.language.name)
+        .await
+        .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+
+    // Counted until it ends, or at the latest once a worker had time to pick
+    // it up and run it to its wall time limit.
+    let id = Uuid::parse_str(&submission.id)?;
+    let duration =
+        data.config.wait_timeout.as_secs_f64() + submission.wall_time_limit.unwrap_or_default();
+    reserve_session(data, key, id, duration)
+        .await
+        .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+    let result = relay_session(data, &submission, ws).await;
+    if let Err(err) = finish_session(data, id).await {
+        eprintln!("❌ Could not finish session {id}: {err}");
+    }
+    result
+}
+
+/// Hands the session to a worker and relays between it and the client until
+/// the program exits or the client goes away.
+async fn relay_session(
+    data: &AppData,
+    submission: &NatsSubmission,
+    ws: &mut WebSocketStream,
+) -> anyhow::Result<()> {
+    let input_subject = session_subject(&submission.id, "input");
+    let mut output = data
+        .nats
+        .subscribe(session_subject(&submission.id, "output"))
+        .await?;
+    data.nats
+        .notify(
+            session_request_subject(&submission.language.name),
+            serde_json::to_vec(submission)?,
+        )
+        .await?;
+
+    // The worker subscribes to the input before its first message, so input is
+    // only read from the client once the session is known to be running.
+    let first_output = timeout(data.config.wait_timeout, output.next())
+        .await
+        .map_err(|_| anyhow::anyhow!("no worker picked up the session"))?;
+    let Some(first_output) = first_output else {
+        return Ok(());
+    };
+    if relay_output(ws, &first_output.payload).await? {
+        return Ok(());
+    }
+
+    loop {
+        tokio::select! {
+            message = output.next() => {
+                let Some(message) = message else { return Ok(()) };
+                if relay_output(ws, &message.payload).await? {
+                    return Ok(());
+                }
+            }
+            message = ws.next() => {
+                let input = match message {
+                    Some(Ok(Message::Binary(payload))) if payload.is_empty() => SessionInput::Eof,
+                    Some(Ok(Message::Text(data))) => SessionInput::Stdin { data },
+                    Some(Ok(Message::Close(_))) => SessionInput::Kill,
+                    Some(Ok(_)) => continue,
+                    // The client went away, so nobody is left to read the output.
+                    Some(Err(_)) | None => SessionInput::Kill,
+                };
+                let kill = matches!(input, SessionInput::Kill);
+                data.nats
+                    .notify(input_subject.clone(), serde_json::to_vec(&input)?)
+                    .await?;
+                if kill {
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
+
+/// Resolves the language and limits of a session the same way as for a
+/// submission, within the limits of the API key, except that the wall time
+/// limit defaults to the session one, capped by the key. Sessions always run standalone code.
+fn start_session(
+    data: &AppData,
+    key: &ApiKey,
+    mut new_submission: NewSubmission,
+) -> anyhow::Result<NatsSubmission> {
+    let language = data
+        .config
+        .get_language(&new_submission.language)
+        .ok_or(anyhow::anyhow!("unregonised language"))?;
+    let wall_time_limit = data.config.session_wall_time_limit;
+    new_submission.wall_time_limit.get_or_insert(
+        key.quotas
+            .max_wall_time_limit
+            .map_or(wall_time_limit, |max| wall_time_limit.min(max)),
+    );
+    data.config.submission_defaults.apply(&mut new_submission);
+    key.check_limits(&new_submission)
+        .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+
+    let id = Uuid::new_v4().to_string();
+    let mut submission = NatsSubmission::from((id, new_submission, language));
+    submission.session_output_limit = Some(data.config.session_output_limit);
+    Ok(submission)
+}
+
+/// Forwards a worker message to the client and reports whether it was the last.
+async fn relay_output(ws: &mut WebSocketStream, payload: &[u8]) -> anyhow::Result<bool> {
+    let message: SessionOutput = serde_json::from_slice(payload)?;
+    send(ws, &message).await?;
+    Ok(message.is_last())
+}
+
+async fn send(ws: &mut WebSocketStream, message: &SessionOutput) -> anyhow::Result<()> {
+    ws.send(Message::text(serde_json::to_string(message)?))
+        .await?;
+    Ok(())
+}
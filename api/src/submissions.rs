@@ -0,0 +1,441 @@
+use async_nats::Subscriber;
+use common::{
+    error::StringError,
+    model::{
+        Language, NatsSubmission, NewSubmission, ProblemVersion, Program, StressTest, Submission,
+        SubmissionEvent, SubmissionMode, SubmissionStatus, TestGroup,
+    },
+    nats::{done_subject, events_subject, submission_subject},
+};
+use deadpool_postgres::GenericClient;
+use futures::StreamExt;
+use poem::{
+    Result,
+    error::{BadRequest, InternalServerError},
+};
+use tokio::time::timeout;
+use tokio_postgres::types::{Json as PgJson, ToSql};
+use uuid::Uuid;
+
+use crate::AppData;
+use crate::cache::content_hash;
+use crate::config::AppConfig;
+use crate::encoding::submission_from_row;
+use crate::outbox::enqueue;
+use crate::problems::{ProblemCache, ResolvedProblem, resolve_problem};
+
+/// A validated submission with its language, problem and stress programs
+/// resolved, ready to be stored and queued.
+pub struct PreparedSubmission {
+    pub new_submission: NewSubmission,
+    pub language: Language,
+    pub problem: Option<ResolvedProblem>,
+    pub stress: Option<StressTest>,
+    /// See [`content_hash`].
+    pub content_hash: Option<Vec<u8>>,
+}
+
+/// Validates `new_submission` and resolves everything the worker needs.
+/// `problems` caches the problems resolved so far by the same request.
+pub async fn prepare_submission(
+    data: &AppData,
+    problems: &mut ProblemCache,
+    mut new_submission: NewSubmission,
+) -> Result<PreparedSubmission> {
+    let language = data
+        .config
+        .get_language(&new_submission.language)
+        .ok_or(BadRequest(StringError::new("unregonised language")))?;
+
+    validate_test_groups(&new_submission.test_groups)?;
+    validate_tags(&new_submission.tags)?;
+    let stress = resolve_stress(&data.config, &new_submission)?;
+    let problem = resolve_problem(&data.db, &data.config, problems, &mut new_submission).await?;
+    validate_io_files(&new_submission, &language)?;
+    data.config.submission_defaults.apply(&mut new_submission);
+
+    let mut prepared = PreparedSubmission {
+        new_submission,
+        language,
+        problem,
+        stress,
+        content_hash: None,
+    };
+    prepared.content_hash = content_hash(&prepared);
+    Ok(prepared)
+}
+
+impl PreparedSubmission {
+    /// Points of the problem or of the inline test groups, if scored.
+    pub fn max_score(&self) -> Option<f64> {
+        match &self.problem {
+            Some(problem) => Some(problem.max_score),
+            None => self
+                .new_submission
+                .test_groups
+                .as_ref()
+                .map(|groups| groups.iter().map(|g| g.points).sum::<f64>()),
+        }
+    }
+}
+
+/// Inserts a prepared submission as queued, owned by API key `api_key_id`,
+/// and returns its id. Takes any client so that batches can insert inside a
+/// single transaction.
+pub async fn insert_submission(
+    client: &impl GenericClient,
+    prepared: &PreparedSubmission,
+    api_key_id: Uuid,
+) -> anyhow::Result<Uuid> {
+    let new_submission = &prepared.new_submission;
+    let problem_id = prepared.problem.as_ref().map(|p| p.id);
+    let test_groups = new_submission.test_groups.as_ref().map(PgJson);
+    let comparator = new_submission.comparator.map(|c| c.as_str());
+    let mode = new_submission.mode.unwrap_or_default().as_str();
+    let stress_options = new_submission.stress.as_ref().map(PgJson);
+    let priority = new_submission.priority.unwrap_or_default().as_str();
+    let max_score = prepared.max_score();
+
+    let stdin = new_submission.stdin.as_deref();
+    let expected_output = new_submission.expected_output.as_deref();
+    let params: &[&(dyn ToSql + Sync)] = &[
+        &problem_id,
+        &new_submission.source_code.0,
+        &prepared.language.name,
+        &new_submission.compiler_options,
+        &new_submission.command_line_arguments,
+        &stdin,
+        &expected_output,
+        &new_submission.cpu_time_limit,
+        &new_submission.cpu_extra_time,
+        &new_submission.wall_time_limit,
+        &new_submission.memory_limit,
+        &new_submission.stack_limit,
+        &new_submission.max_processes_and_or_threads,
+        &new_submission.enable_per_process_and_thread_time_limit,
+        &new_submission.enable_per_process_and_thread_memory_limit,
+        &new_submission.max_file_size,
+        &new_submission.redirect_stderr_to_stdout,
+        &new_submission.enable_network,
+        &new_submission.number_of_runs,
+        &new_submission.additional_files,
+        &new_submission.callback_url,
+        &test_groups,
+        &comparator,
+        &mode,
+        &stress_options,
+        &new_submission.input_file,
+        &new_submission.output_file,
+        &new_submission.tags,
+        &max_score,
+        &SubmissionStatus::InQueue,
+        &api_key_id,
+        &prepared.content_hash,
+        &priority,
+    ];
+
+    let row = client
+        .query_one(
+            r#"
+        INSERT INTO submissions (
+            problem_id,
+            source_code,
+            language,
+            compiler_options,
+            command_line_arguments,
+            stdin,
+            expected_output,
+            cpu_time_limit,
+            cpu_extra_time,
+            wall_time_limit,
+            memory_limit,
+            stack_limit,
+            max_processes_and_or_threads,
+            enable_per_process_and_thread_time_limit,
+            enable_per_process_and_thread_memory_limit,
+            max_file_size,
+            redirect_stderr_to_stdout,
+            enable_network,
+            number_of_runs,
+            additional_files,
+            callback_url,
+            test_groups,
+            comparator,
+            mode,
+            stress_options,
+            input_file,
+            output_file,
+            tags,
+            max_score,
+            status,
+            api_key_id,
+            content_hash,
+            priority
+        ) VALUES (
+            $1, $2, $3, $4, $5, $6, $7, $8, $9, $10,
+            $11, $12, $13, $14, $15, $16, $17, $18, $19, $20,
+            $21, $22, $23, $24, $25, $26, $27, $28, $29, $30,
+            $31, $32, $33
+        )
+        RETURNING id
+        "#,
+            params,
+        )
+        .await?;
+
+    Ok(row.get("id"))
+}
+
+/// Builds the queue message for a stored submission.
+pub fn nats_submission(id: Uuid, prepared: &PreparedSubmission) -> NatsSubmission {
+    let mut submission = NatsSubmission::from((
+        id.to_string(),
+        prepared.new_submission.clone(),
+        prepared.language.clone(),
+    ));
+    if let Some(problem) = &prepared.problem {
+        submission.problem = Some(ProblemVersion {
+            id: problem.id.to_string(),
+            version: problem.version,
+        });
+        submission.checker = problem.checker.clone();
+        submission.interactor = problem.interactor.clone();
+        submission.validator = problem.validator.clone();
+        submission.solution = problem.main_solution.clone();
+        submission.generators = Some(problem.generators.clone());
+        submission.template = problem.template.clone();
+    }
+    submission.stress = prepared.stress.clone();
+    submission
+}
+
+/// Waits for the worker to announce on `done` that submission `id` finished
+/// and returns it, or `None` once the configured wait timeout has passed.
+pub async fn wait_for_result(
+    data: &AppData,
+    id: Uuid,
+    mut done: Subscriber,
+    base64_encoded: bool,
+) -> Result<Option<Submission>> {
+    match timeout(data.config.wait_timeout, done.next()).await {
+        Ok(Some(_)) => {}
+        Ok(None) | Err(_) => return Ok(None),
+    }
+
+    let params: &[&(dyn ToSql + Sync)] = &[&id];
+    let row = data
+        .db
+        .query_opt("select * from submissions where id= $1", params)
+        .await
+        .map_err(|_| InternalServerError(StringError::new("could not able to query")))?;
+    Ok(row.map(|row| submission_from_row(row, base64_encoded)))
+}
+
+/// Tells event streams and waiting requests that submission `id` finished
+/// without a worker, as the worker would have. Best effort, like the worker.
+pub async fn announce_finished(data: &AppData, id: Uuid, status: SubmissionStatus) {
+    let id = id.to_string();
+    if let Ok(event) = serde_json::to_vec(&SubmissionEvent::from_status(status.clone())) {
+        let _ = data.nats.notify(events_subject(&id), event).await;
+    }
+    if let Ok(status) = serde_json::to_vec(&status) {
+        let _ = data.nats.notify(done_subject(&id), status).await;
+    }
+}
+
+/// Resolves a stored submission again, picking up fixed tests or checkers of
+/// its problem, and writes its queue message to the outbox through `client`.
+pub async fn requeue_submission(
+    data: &AppData,
+    client: &impl GenericClient,
+    problems: &mut ProblemCache,
+    id: Uuid,
+    new_submission: NewSubmission,
+) -> Result<()> {
+    let prepared = prepare_submission(data, problems, new_submission).await?;
+    // The content and points change with the problem, so identical
+    // submissions find the new verdict.
+    let params: &[&(dyn ToSql + Sync)] = &[&id, &prepared.content_hash, &prepared.max_score()];
+    client
+        .execute(
+            "UPDATE submissions SET content_hash = $2, max_score = $3 WHERE id = $1",
+            params,
+        )
+        .await
+        .map_err(|_| InternalServerError(StringError::new("could not able to query")))?;
+    queue_submission(client, id, &prepared).await
+}
+
+/// Writes the queue message of stored submission `id` to the outbox, on the
+/// subject of its priority and language.
+pub async fn queue_submission(
+    client: &impl GenericClient,
+    id: Uuid,
+    prepared: &PreparedSubmission,
+) -> Result<()> {
+    let priority = prepared.new_submission.priority.unwrap_or_default();
+    let subject = submission_subject(priority, &prepared.language.name);
+    enqueue(client, id, &subject, &nats_submission(id, prepared)).await
+}
+
+/// Finishes a submission that could not be queued again, so that it does not
+/// stay queued forever.
+pub async fn fail_submission(client: &impl GenericClient, id: Uuid, message: &str) -> Result<()> {
+    let params: &[&(dyn ToSql + Sync)] = &[&id, &SubmissionStatus::InternalError, &message];
+    client
+        .execute(
+            "UPDATE submissions SET status = $2, message = $3, finished_at = now() WHERE id = $1",
+            params,
+        )
+        .await
+        .map_err(|_| InternalServerError(StringError::new("could not able to query")))?;
+    Ok(())
+}
+
+fn validate_test_groups(test_groups: &Option<Vec<TestGroup>>) -> Result<()> {
+    for group in test_groups.iter().flatten() {
+        if group.tests.is_empty() {
+            return Err(BadRequest(StringError::new(&format!(
+                "test group `{}` has no tests",
+                group.name
+            ))));
+        }
+        if !group.points.is_finite() || group.points < 0.0 {
+            return Err(BadRequest(StringError::new(&format!(
+                "test group `{}` has invalid points",
+                group.name
+            ))));
+        }
+        if group.tests.iter().any(|test| test.generator.is_some()) {
+            return Err(BadRequest(StringError::new(
+                "generated tests are only supported for stored problems",
+            )));
+        }
+    }
+    Ok(())
+}
+
+const MAX_TAGS: usize = 16;
+const MAX_TAG_LENGTH: usize = 64;
+
+fn validate_tags(tags: &Option<Vec<String>>) -> Result<()> {
+    let Some(tags) = tags else {
+        return Ok(());
+    };
+    if tags.len() > MAX_TAGS {
+        return Err(BadRequest(StringError::new(&format!(
+            "a submission has at most {} tags",
+            MAX_TAGS
+        ))));
+    }
+    // Commas separate tags in the listing filter.
+    if tags
+        .iter()
+        .any(|tag| tag.is_empty() || tag.len() > MAX_TAG_LENGTH || tag.contains(','))
+    {
+        return Err(BadRequest(StringError::new(&format!(
+            "tags must be 1 to {} characters without commas",
+            MAX_TAG_LENGTH
+        ))));
+    }
+    Ok(())
+}
+
+/// I/O files are created inside the box next to the program, so they must be
+/// plain file names that do not clash with the source, the run scripts or the
+/// files the language's commands name, such as the compiled binary.
+fn validate_io_files(new_submission: &NewSubmission, language: &Language) -> Result<()> {
+    let mut reserved = vec![language.source_file.as_str(), "compile.sh", "run.sh"];
+    let commands = language.compile_cmd.iter().chain([&language.run_cmd]);
+    reserved.extend(
+        commands
+            .flat_map(|command| command.split_whitespace())
+            .map(|word| word.trim_start_matches("./")),
+    );
+
+    let files = [&new_submission.input_file, &new_submission.output_file];
+    for name in files.into_iter().flatten() {
+        if name.is_empty()
+            || name == "."
+            || name == ".."
+            || name.contains(['/', '\\', '\0'])
+            || reserved.contains(&name.as_str())
+        {
+            return Err(BadRequest(StringError::new(&format!(
+                "invalid I/O file name `{}`",
+                name
+            ))));
+        }
+    }
+    if new_submission.input_file.is_some()
+        && new_submission.input_file == new_submission.output_file
+    {
+        return Err(BadRequest(StringError::new(
+            "input_file and output_file must differ",
+        )));
+    }
+    Ok(())
+}
+
+const DEFAULT_STRESS_ITERATIONS: i32 = 100;
+const MAX_STRESS_ITERATIONS: i32 = 1000;
+
+fn resolve_stress(
+    config: &AppConfig,
+    new_submission: &NewSubmission,
+) -> Result<Option<StressTest>> {
+    let options = match (
+        new_submission.mode.unwrap_or_default(),
+        &new_submission.stress,
+    ) {
+        (SubmissionMode::Standard, None) => return Ok(None),
+        (SubmissionMode::Standard, Some(_)) => {
+            return Err(BadRequest(StringError::new(
+                "stress options require mode `stress`",
+            )));
+        }
+        (SubmissionMode::Stress, None) => {
+            return Err(BadRequest(StringError::new(
+                "mode `stress` requires stress options",
+            )));
+        }
+        (SubmissionMode::Stress, Some(options)) => options,
+    };
+
+    if new_submission.test_groups.is_some() {
+        return Err(BadRequest(StringError::new(
+            "test_groups cannot be combined with mode `stress`",
+        )));
+    }
+    if new_submission.problem_id.is_some() {
+        return Err(BadRequest(StringError::new(
+            "problem_id cannot be combined with mode `stress`",
+        )));
+    }
+
+    let iterations = options.iterations.unwrap_or(DEFAULT_STRESS_ITERATIONS);
+    if !(1..=MAX_STRESS_ITERATIONS).contains(&iterations) {
+        return Err(BadRequest(StringError::new(&format!(
+            "stress iterations must be between 1 and {}",
+            MAX_STRESS_ITERATIONS
+        ))));
+    }
+
+    let program = |source_code: &String, language: &String| -> Result<Program> {
+        Ok(Program {
+            source_code: source_code.clone(),
+            language: config
+                .get_language(language)
+                .ok_or(BadRequest(StringError::new(&format!(
+                    "unregonised language `{}`",
+                    language
+                ))))?,
+        })
+    };
+
+    Ok(Some(StressTest {
+        generator: program(&options.generator_source, &options.generator_language)?,
+        reference: program(&options.reference_source, &options.reference_language)?,
+        iterations,
+    }))
+}
@@ -0,0 +1,38 @@
+use std::collections::HashSet;
+use std::time::{Duration, Instant};
+
+use common::{error::StringError, nats::NatsClient};
+use poem::{Result, error::ServiceUnavailable};
+use tokio::sync::Mutex;
+
+/// How long the advertised languages are reused before NATS is asked again.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The languages that live workers advertise, looked up lazily.
+#[derive(Default)]
+pub struct LiveWorkers {
+    languages: Mutex<Option<(Instant, HashSet<String>)>>,
+}
+
+impl LiveWorkers {
+    /// Fails with `503` unless a live worker runs `language`, since nothing
+    /// would take a submission queued for it.
+    pub async fn require(&self, nats: &NatsClient, language: &str) -> Result<()> {
+        let mut cached = self.languages.lock().await;
+        if !matches!(&*cached, Some((at, _)) if at.elapsed() < REFRESH_INTERVAL) {
+            let languages = nats.live_languages().await.map_err(|_| {
+                ServiceUnavailable(StringError::new("could not look up live workers"))
+            })?;
+            *cached = Some((Instant::now(), languages));
+        }
+
+        let (_, languages) = cached.as_ref().expect("looked up above");
+        if !languages.contains(language) {
+            return Err(ServiceUnavailable(StringError::new(&format!(
+                "no live worker runs language `{}`",
+                language
+            ))));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,236 @@
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use chrono::{DateTime, Utc};
+use poem::{
+    Result,
+    error::{BadRequest, Forbidden},
+};
+use poem_openapi::{Enum, Object};
+use postgres_types::{FromSql, ToSql};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio_postgres::Row;
+use uuid::Uuid;
+
+use crate::db::Db;
+use crate::error::StringError;
+use crate::model::{NewSubmission, SubmissionPriority};
+
+/// What an API key may do. `admin` implies the other scopes.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Enum, ToSql, FromSql)]
+#[serde(rename_all = "lowercase")]
+#[oai(rename_all = "lowercase")]
+#[postgres(name = "api_key_scope", rename_all = "lowercase")]
+pub enum ApiKeyScope {
+    /// Create and cancel submissions and run sessions.
+    Submit,
+    /// Read the key's own submissions.
+    Read,
+    /// Read and manage every submission and the API keys.
+    Admin,
+    /// Queue submissions at `high` priority.
+    Priority,
+}
+
+impl ApiKeyScope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ApiKeyScope::Submit => "submit",
+            ApiKeyScope::Read => "read",
+            ApiKeyScope::Admin => "admin",
+            ApiKeyScope::Priority => "priority",
+        }
+    }
+}
+
+impl std::str::FromStr for ApiKeyScope {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "submit" => ApiKeyScope::Submit,
+            "read" => ApiKeyScope::Read,
+            "admin" => ApiKeyScope::Admin,
+            "priority" => ApiKeyScope::Priority,
+            _ => return Err(()),
+        })
+    }
+}
+
+/// Limits on what an API key may submit. Every quota is unlimited when unset.
+#[derive(Debug, Clone, Default, Object)]
+pub struct ApiKeyQuotas {
+    /// Submissions the key may create within a minute.
+    pub submissions_per_minute: Option<i32>,
+    /// Submissions of the key that may be queued or running at once.
+    pub max_concurrent_submissions: Option<i32>,
+    pub max_cpu_time_limit: Option<f64>,
+    pub max_wall_time_limit: Option<f64>,
+    pub max_memory_limit: Option<f64>,
+}
+
+#[derive(Debug, Clone, Object)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    pub scopes: Vec<ApiKeyScope>,
+    #[oai(flatten)]
+    pub quotas: ApiKeyQuotas,
+    pub created_at: DateTime<Utc>,
+    #[oai(skip_serializing_if_is_none)]
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+impl From<Row> for ApiKey {
+    fn from(row: Row) -> Self {
+        ApiKey {
+            id: row.get::<_, Uuid>("id").to_string(),
+            name: row.get("name"),
+            scopes: row.get("scopes"),
+            quotas: ApiKeyQuotas {
+                submissions_per_minute: row.get("submissions_per_minute"),
+                max_concurrent_submissions: row.get("max_concurrent_submissions"),
+                max_cpu_time_limit: row.get("max_cpu_time_limit"),
+                max_wall_time_limit: row.get("max_wall_time_limit"),
+                max_memory_limit: row.get("max_memory_limit"),
+            },
+            created_at: row.get("created_at"),
+            revoked_at: row.get("revoked_at"),
+        }
+    }
+}
+
+impl ApiKey {
+    pub fn uuid(&self) -> Uuid {
+        Uuid::parse_str(&self.id).expect("ids are uuids")
+    }
+
+    pub fn has_scope(&self, scope: ApiKeyScope) -> bool {
+        self.scopes.contains(&scope) || self.scopes.contains(&ApiKeyScope::Admin)
+    }
+
+    /// Fails with `403` unless the key has `scope`.
+    pub fn require(&self, scope: ApiKeyScope) -> Result<()> {
+        if self.has_scope(scope) {
+            return Ok(());
+        }
+        Err(Forbidden(StringError::new(&format!(
+            "the API key lacks the `{}` scope",
+            scope.as_str()
+        ))))
+    }
+
+    /// The key whose submissions this key may see, or `None` for all of them.
+    pub fn owner(&self) -> Option<Uuid> {
+        match self.has_scope(ApiKeyScope::Admin) {
+            true => None,
+            false => Some(self.uuid()),
+        }
+    }
+
+    /// Checks the limits of a submission, with the defaults applied, against
+    /// the key's quotas, and that the key may queue it at its priority.
+    pub fn check_limits(&self, submission: &NewSubmission) -> Result<()> {
+        if submission.priority == Some(SubmissionPriority::High) {
+            self.require(ApiKeyScope::Priority)?;
+        }
+        let limits = [
+            (
+                "cpu_time_limit",
+                submission.cpu_time_limit,
+                self.quotas.max_cpu_time_limit,
+            ),
+            (
+                "wall_time_limit",
+                submission.wall_time_limit,
+                self.quotas.max_wall_time_limit,
+            ),
+            (
+                "memory_limit",
+                submission.memory_limit,
+                self.quotas.max_memory_limit,
+            ),
+        ];
+        for (name, value, max) in limits {
+            if let (Some(value), Some(max)) = (value, max)
+                && value > max
+            {
+                return Err(BadRequest(StringError::new(&format!(
+                    "{} is at most {} for this API key",
+                    name, max
+                ))));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A key to be created.
+#[derive(Debug, Clone, Object)]
+pub struct NewApiKey {
+    pub name: String,
+    pub scopes: Vec<ApiKeyScope>,
+    #[oai(flatten)]
+    pub quotas: ApiKeyQuotas,
+}
+
+/// Prefix of every key, to make them easy to spot in configs and logs.
+const KEY_PREFIX: &str = "ek_";
+
+/// Keys are only stored as their SHA-256. They are random enough that a slow
+/// password hash would add nothing.
+pub fn hash_key(key: &str) -> Vec<u8> {
+    Sha256::digest(key.as_bytes()).to_vec()
+}
+
+fn generate_key() -> String {
+    let bytes: [u8; 32] = rand::random();
+    format!("{}{}", KEY_PREFIX, URL_SAFE_NO_PAD.encode(bytes))
+}
+
+/// Stores a new key and returns it together with the key itself, which is
+/// not kept anywhere and cannot be shown again.
+pub async fn create_api_key(db: &Db, new_key: &NewApiKey) -> anyhow::Result<(ApiKey, String)> {
+    let key = generate_key();
+    let quotas = &new_key.quotas;
+    let params: &[&(dyn ToSql + Sync)] = &[
+        &new_key.name,
+        &hash_key(&key),
+        &new_key.scopes,
+        &quotas.submissions_per_minute,
+        &quotas.max_concurrent_submissions,
+        &quotas.max_cpu_time_limit,
+        &quotas.max_wall_time_limit,
+        &quotas.max_memory_limit,
+    ];
+    let row = db
+        .query_one(
+            r#"
+            INSERT INTO api_keys (
+                name,
+                key_hash,
+                scopes,
+                submissions_per_minute,
+                max_concurrent_submissions,
+                max_cpu_time_limit,
+                max_wall_time_limit,
+                max_memory_limit
+            ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+            RETURNING *
+            "#,
+            params,
+        )
+        .await?;
+    Ok((ApiKey::from(row), key))
+}
+
+/// Looks up the key presented with a request; revoked keys are not found.
+pub async fn find_api_key(db: &Db, key: &str) -> anyhow::Result<Option<ApiKey>> {
+    let params: &[&(dyn ToSql + Sync)] = &[&hash_key(key)];
+    let row = db
+        .query_opt(
+            "select * from api_keys where key_hash = $1 and revoked_at is null",
+            params,
+        )
+        .await?;
+    Ok(row.map(ApiKey::from))
+}
@@ -1,5 +1,7 @@
+pub mod auth;
 pub mod nats;
 pub mod db;
 pub mod model;
 pub mod  error;
-// mod redis;
\ No newline at end of file
+pub mod redis;
+pub mod text;
\ No newline at end of file
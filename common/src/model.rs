@@ -3,7 +3,11 @@ use poem_openapi::Enum;
 use poem_openapi::Object;
 use serde::{Deserialize, Serialize};
 use tokio_postgres::Row;
-use postgres_types::{ToSql, FromSql};
+use postgres_types::{ToSql, FromSql, Json};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+use crate::text::RawText;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Enum, ToSql, FromSql)]
 #[serde(rename_all = "lowercase")]
@@ -23,6 +27,14 @@ pub enum SubmissionStatus {
     RuntimeErrorOther,
     InternalError,
     ExecFormatError,
+    Cancelled,
+}
+
+impl SubmissionStatus {
+    /// Whether this is a final verdict rather than a queue or progress state.
+    pub fn is_finished(&self) -> bool {
+        !matches!(self, SubmissionStatus::InQueue | SubmissionStatus::Processing)
+    }
 }
 
 impl std::str::FromStr for SubmissionStatus {
@@ -45,20 +57,319 @@ impl std::str::FromStr for SubmissionStatus {
             "runtimeerrorother" => RuntimeErrorOther,
             "internalerror" => InternalError,
             "execformaterror" => ExecFormatError,
+            "cancelled" => Cancelled,
+            _ => return Err(()),
+        })
+    }
+}
+
+/// Step of judging that a submission has reached.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Enum)]
+#[serde(rename_all = "snake_case")]
+#[oai(rename_all = "snake_case")]
+pub enum SubmissionStage {
+    InQueue,
+    Processing,
+    Compiling,
+    Running,
+    Finished,
+}
+
+/// A progress update of a submission, published by the worker on its events
+/// subject. `test` is the 1-based number of the test being run and `status`
+/// the verdict once finished.
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+pub struct SubmissionEvent {
+    pub stage: SubmissionStage,
+    #[oai(skip_serializing_if_is_none)]
+    pub test: Option<i32>,
+    #[oai(skip_serializing_if_is_none)]
+    pub status: Option<SubmissionStatus>,
+}
+
+impl SubmissionEvent {
+    pub fn new(stage: SubmissionStage) -> Self {
+        Self {
+            stage,
+            test: None,
+            status: None,
+        }
+    }
+
+    /// The event matching a stored status, for clients that join late.
+    pub fn from_status(status: SubmissionStatus) -> Self {
+        match status {
+            SubmissionStatus::InQueue => Self::new(SubmissionStage::InQueue),
+            SubmissionStatus::Processing => Self::new(SubmissionStage::Processing),
+            status => Self {
+                stage: SubmissionStage::Finished,
+                test: None,
+                status: Some(status),
+            },
+        }
+    }
+}
+
+/// Sent by the API to the worker running an interactive session.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SessionInput {
+    Stdin { data: String },
+    /// Closes the program's stdin.
+    Eof,
+    Kill,
+}
+
+/// Sent by the worker running an interactive session and relayed to the client.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SessionOutput {
+    Compiled {
+        success: bool,
+        output: Option<String>,
+    },
+    Stdout {
+        data: String,
+    },
+    Stderr {
+        data: String,
+    },
+    /// Last message of a session that ran, with the isolate metadata of the run.
+    Exit {
+        status: SubmissionStatus,
+        metadata: HashMap<String, String>,
+    },
+    /// Last message of a session that could not run.
+    Error {
+        message: String,
+    },
+}
+
+impl SessionOutput {
+    pub fn is_last(&self) -> bool {
+        matches!(
+            self,
+            SessionOutput::Exit { .. }
+                | SessionOutput::Error { .. }
+                | SessionOutput::Compiled { success: false, .. }
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq, Enum)]
+#[serde(rename_all = "snake_case")]
+#[oai(rename_all = "snake_case")]
+pub enum ScoringPolicy {
+    /// Full points only if every test in the group passes.
+    AllOrNothing,
+    /// Points scaled by the lowest test score in the group.
+    Min,
+    /// Points split evenly between the tests of the group.
+    Sum,
+}
+
+impl ScoringPolicy {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScoringPolicy::AllOrNothing => "all_or_nothing",
+            ScoringPolicy::Min => "min",
+            ScoringPolicy::Sum => "sum",
+        }
+    }
+}
+
+impl std::str::FromStr for ScoringPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "all_or_nothing" => ScoringPolicy::AllOrNothing,
+            "min" => ScoringPolicy::Min,
+            "sum" => ScoringPolicy::Sum,
+            _ => return Err(()),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Enum)]
+#[serde(rename_all = "snake_case")]
+#[oai(rename_all = "snake_case")]
+pub enum Comparator {
+    /// Outputs match after trimming leading and trailing whitespace.
+    #[default]
+    Trimmed,
+    /// Outputs match byte for byte.
+    Exact,
+    /// Outputs match as sequences of whitespace-separated tokens.
+    Tokens,
+}
+
+impl Comparator {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Comparator::Trimmed => "trimmed",
+            Comparator::Exact => "exact",
+            Comparator::Tokens => "tokens",
+        }
+    }
+
+    pub fn matches(&self, expected: &str, actual: &str) -> bool {
+        match self {
+            Comparator::Trimmed => expected.trim() == actual.trim(),
+            Comparator::Exact => expected == actual,
+            Comparator::Tokens => expected.split_whitespace().eq(actual.split_whitespace()),
+        }
+    }
+}
+
+impl std::str::FromStr for Comparator {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "trimmed" => Comparator::Trimmed,
+            "exact" => Comparator::Exact,
+            "tokens" => Comparator::Tokens,
             _ => return Err(()),
         })
     }
 }
 
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Enum)]
+#[serde(rename_all = "snake_case")]
+#[oai(rename_all = "snake_case")]
+pub enum SubmissionMode {
+    /// Judge against the submission's tests.
+    #[default]
+    Standard,
+    /// Compare against a reference solution on generated inputs until they disagree.
+    Stress,
+}
+
+impl SubmissionMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SubmissionMode::Standard => "standard",
+            SubmissionMode::Stress => "stress",
+        }
+    }
+}
+
+impl std::str::FromStr for SubmissionMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "standard" => SubmissionMode::Standard,
+            "stress" => SubmissionMode::Stress,
+            _ => return Err(()),
+        })
+    }
+}
+
+/// Queue a submission waits in. Workers take higher priorities first but keep
+/// taking some lower ones, so that those still move while busy.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Enum)]
+#[serde(rename_all = "snake_case")]
+#[oai(rename_all = "snake_case")]
+pub enum SubmissionPriority {
+    /// E.g. submissions during a live contest.
+    High,
+    #[default]
+    Normal,
+    /// E.g. bulk imports; rejudges default to it.
+    Low,
+}
+
+impl SubmissionPriority {
+    /// Every priority, highest first.
+    pub const ALL: [SubmissionPriority; 3] = [
+        SubmissionPriority::High,
+        SubmissionPriority::Normal,
+        SubmissionPriority::Low,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SubmissionPriority::High => "high",
+            SubmissionPriority::Normal => "normal",
+            SubmissionPriority::Low => "low",
+        }
+    }
+}
+
+impl std::str::FromStr for SubmissionPriority {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "high" => SubmissionPriority::High,
+            "normal" => SubmissionPriority::Normal,
+            "low" => SubmissionPriority::Low,
+            _ => return Err(()),
+        })
+    }
+}
+
+/// Programs used by a stress submission. The generator is run with the seed as
+/// its only argument and the reference solution reads the generated input.
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+pub struct StressOptions {
+    pub generator_source: String,
+    pub generator_language: String,
+    pub reference_source: String,
+    pub reference_language: String,
+    pub iterations: Option<i32>,
+}
+
+/// Outcome of a stress submission. `seed`, `input` and the outputs are only set
+/// when the submission disagreed with the reference solution.
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+pub struct StressResult {
+    pub iterations_run: i32,
+    pub seed: Option<i32>,
+    pub input: Option<String>,
+    pub expected_output: Option<String>,
+    pub actual_output: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+pub struct TestCase {
+    pub stdin: Option<String>,
+    pub expected_output: Option<String>,
+    /// Name of the problem generator that produces `stdin` for this test.
+    pub generator: Option<String>,
+    pub generator_args: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+pub struct TestGroup {
+    pub name: String,
+    pub points: f64,
+    pub policy: ScoringPolicy,
+    pub tests: Vec<TestCase>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Object)]
+pub struct GroupResult {
+    pub name: String,
+    pub status: SubmissionStatus,
+    pub score: f64,
+    pub max_score: f64,
+    pub passed_tests: i32,
+    pub total_tests: i32,
+}
+
 #[derive(Debug, Clone, Object)]
 pub struct Submission {
     pub id: String,
-    pub source_code: String,
+    pub problem_id: Option<String>,
+    pub source_code: RawText,
     pub language: String,
     pub compiler_options: Option<String>,
     pub command_line_arguments: Option<String>,
-    pub stdin: Option<String>,
-    pub expected_output: Option<String>,
+    pub stdin: Option<RawText>,
+    pub expected_output: Option<RawText>,
     pub cpu_time_limit: Option<f64>,
     pub cpu_extra_time: Option<f64>,
     pub wall_time_limit: Option<f64>,
@@ -73,6 +384,14 @@ pub struct Submission {
     pub number_of_runs: Option<i32>,
     pub additional_files: Option<String>,
     pub callback_url: Option<String>,
+    pub test_groups: Option<Vec<TestGroup>>,
+    pub comparator: Option<Comparator>,
+    pub mode: SubmissionMode,
+    pub stress: Option<StressOptions>,
+    pub input_file: Option<String>,
+    pub output_file: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub priority: SubmissionPriority,
     pub stdout: Option<String>,
     pub stderr: Option<String>,
     pub compile_output: Option<String>,
@@ -82,21 +401,46 @@ pub struct Submission {
     pub status: SubmissionStatus,
     pub created_at: DateTime<Utc>,
     pub finished_at: Option<DateTime<Utc>>,
+    /// When the code, inputs and outputs were cleared by a deletion request.
+    pub redacted_at: Option<DateTime<Utc>>,
+    /// The identical earlier submission whose verdict was reused instead of
+    /// running this one.
+    pub cached_from: Option<String>,
     pub time: Option<f64>,
     pub wall_time: Option<f64>,
     pub memory: Option<f64>,
+    pub score: Option<f64>,
+    pub max_score: Option<f64>,
+    pub group_results: Option<Vec<GroupResult>>,
+    pub stress_result: Option<StressResult>,
 }
 
 impl From<Row> for Submission {
     fn from(row: Row) -> Self {
         Submission {
-            id: row.get("id"),
-            source_code: row.get("source_code"),
+            id: row.get::<_, Uuid>("id").to_string(),
+            problem_id: row
+                .try_get::<_, Option<Uuid>>("problem_id")
+                .ok()
+                .flatten()
+                .map(|id| id.to_string()),
+            source_code: row
+                .try_get::<_, Vec<u8>>("source_code")
+                .map(RawText)
+                .unwrap_or_default(),
             language: row.get("language"),
             compiler_options: row.try_get("compiler_options").ok().flatten(),
             command_line_arguments: row.try_get("command_line_arguments").ok().flatten(),
-            stdin: row.try_get("stdin").ok().flatten(),
-            expected_output: row.try_get("expected_output").ok().flatten(),
+            stdin: row
+                .try_get::<_, Option<Vec<u8>>>("stdin")
+                .ok()
+                .flatten()
+                .map(RawText),
+            expected_output: row
+                .try_get::<_, Option<Vec<u8>>>("expected_output")
+                .ok()
+                .flatten()
+                .map(RawText),
             cpu_time_limit: row.try_get("cpu_time_limit").ok().flatten(),
             cpu_extra_time: row.try_get("cpu_extra_time").ok().flatten(),
             wall_time_limit: row.try_get("wall_time_limit").ok().flatten(),
@@ -120,30 +464,134 @@ impl From<Row> for Submission {
             number_of_runs: row.try_get("number_of_runs").ok().flatten(),
             additional_files: row.try_get("additional_files").ok().flatten(),
             callback_url: row.try_get("callback_url").ok().flatten(),
-            stdout: row.try_get("stdout").ok().flatten(),
-            stderr: row.try_get("stderr").ok().flatten(),
-            compile_output: row.try_get("compile_output").ok().flatten(),
+            test_groups: row
+                .try_get::<_, Option<Json<Vec<TestGroup>>>>("test_groups")
+                .ok()
+                .flatten()
+                .map(|groups| groups.0),
+            comparator: row
+                .try_get::<_, Option<String>>("comparator")
+                .ok()
+                .flatten()
+                .and_then(|c| c.parse().ok()),
+            mode: row
+                .try_get::<_, String>("mode")
+                .ok()
+                .and_then(|m| m.parse().ok())
+                .unwrap_or_default(),
+            stress: row
+                .try_get::<_, Option<Json<StressOptions>>>("stress_options")
+                .ok()
+                .flatten()
+                .map(|options| options.0),
+            input_file: row.try_get("input_file").ok().flatten(),
+            output_file: row.try_get("output_file").ok().flatten(),
+            tags: row.try_get("tags").ok().flatten(),
+            priority: row
+                .try_get::<_, String>("priority")
+                .ok()
+                .and_then(|p| p.parse().ok())
+                .unwrap_or_default(),
+            stdout: row
+                .try_get::<_, Option<Vec<u8>>>("stdout")
+                .ok()
+                .flatten()
+                .map(|s| String::from_utf8_lossy(&s).into_owned()),
+            stderr: row
+                .try_get::<_, Option<Vec<u8>>>("stderr")
+                .ok()
+                .flatten()
+                .map(|s| String::from_utf8_lossy(&s).into_owned()),
+            compile_output: row
+                .try_get::<_, Option<Vec<u8>>>("compile_output")
+                .ok()
+                .flatten()
+                .map(|s| String::from_utf8_lossy(&s).into_owned()),
             message: row.try_get("message").ok().flatten(),
             exit_code: row.try_get("exit_code").ok().flatten(),
             exit_signal: row.try_get("exit_signal").ok().flatten(),
-            status: row.get::<_, String>("status").parse().unwrap(),
+            status: row.get("status"),
             created_at: row.get("created_at"),
             finished_at: row.try_get("finished_at").ok().flatten(),
+            redacted_at: row.try_get("redacted_at").ok().flatten(),
+            cached_from: row
+                .try_get::<_, Option<Uuid>>("cached_from")
+                .ok()
+                .flatten()
+                .map(|id| id.to_string()),
             time: row.try_get("time").ok().flatten(),
             wall_time: row.try_get("wall_time").ok().flatten(),
             memory: row.try_get("memory").ok().flatten(),
+            score: row.try_get("score").ok().flatten(),
+            max_score: row.try_get("max_score").ok().flatten(),
+            group_results: row
+                .try_get::<_, Option<Json<Vec<GroupResult>>>>("group_results")
+                .ok()
+                .flatten()
+                .map(|results| results.0),
+            stress_result: row
+                .try_get::<_, Option<Json<StressResult>>>("stress_result")
+                .ok()
+                .flatten()
+                .map(|result| result.0),
+        }
+    }
+}
+
+/// One run of a submission by a worker.
+#[derive(Debug, Clone, Object)]
+pub struct SubmissionAttempt {
+    pub id: i64,
+    pub worker_id: String,
+    pub box_id: i32,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub status: SubmissionStatus,
+    /// Raw isolate metadata of the run whose outputs are kept.
+    pub metadata: Option<HashMap<String, String>>,
+    pub stdout: Option<String>,
+    pub stderr: Option<String>,
+    pub compile_output: Option<String>,
+    pub message: Option<String>,
+}
+
+impl From<Row> for SubmissionAttempt {
+    fn from(row: Row) -> Self {
+        SubmissionAttempt {
+            id: row.get("id"),
+            worker_id: row.get("worker_id"),
+            box_id: row.get("box_id"),
+            started_at: row.get("started_at"),
+            finished_at: row.get("finished_at"),
+            status: row.get("status"),
+            metadata: row
+                .get::<_, Option<Json<HashMap<String, String>>>>("metadata")
+                .map(|metadata| metadata.0),
+            stdout: row
+                .get::<_, Option<Vec<u8>>>("stdout")
+                .map(|s| String::from_utf8_lossy(&s).into_owned()),
+            stderr: row
+                .get::<_, Option<Vec<u8>>>("stderr")
+                .map(|s| String::from_utf8_lossy(&s).into_owned()),
+            compile_output: row
+                .get::<_, Option<Vec<u8>>>("compile_output")
+                .map(|s| String::from_utf8_lossy(&s).into_owned()),
+            message: row.get("message"),
         }
     }
 }
 
 #[derive(Debug, Clone, Object)]
 pub struct NewSubmission {
-    pub source_code: String,
+    /// Judge against the tests and checker of a stored problem instead of
+    /// inline `test_groups`.
+    pub problem_id: Option<String>,
+    pub source_code: RawText,
     pub language: String,
     pub compiler_options: Option<String>,
     pub command_line_arguments: Option<String>,
-    pub stdin: Option<String>,
-    pub expected_output: Option<String>,
+    pub stdin: Option<RawText>,
+    pub expected_output: Option<RawText>,
     pub cpu_time_limit: Option<f64>,
     pub cpu_extra_time: Option<f64>,
     pub wall_time_limit: Option<f64>,
@@ -158,18 +606,114 @@ pub struct NewSubmission {
     pub number_of_runs: Option<i32>,
     pub additional_files: Option<String>,
     pub callback_url: Option<String>,
+    pub test_groups: Option<Vec<TestGroup>>,
+    /// How outputs are compared when the problem has no checker.
+    pub comparator: Option<Comparator>,
+    pub mode: Option<SubmissionMode>,
+    /// Required when `mode` is `stress`.
+    pub stress: Option<StressOptions>,
+    /// File in the working directory that receives the test input, for
+    /// programs that do not read stdin. Defaults to the problem's setting.
+    pub input_file: Option<String>,
+    /// File the program writes its answer to instead of stdout.
+    pub output_file: Option<String>,
+    /// Labels chosen by the client to find the submission again when listing.
+    pub tags: Option<Vec<String>>,
+    /// Queue to wait in, `normal` by default.
+    pub priority: Option<SubmissionPriority>,
+}
+
+/// The request a stored submission was created from, for running it again.
+impl From<Submission> for NewSubmission {
+    fn from(submission: Submission) -> Self {
+        Self {
+            problem_id: submission.problem_id,
+            source_code: submission.source_code,
+            language: submission.language,
+            compiler_options: submission.compiler_options,
+            command_line_arguments: submission.command_line_arguments,
+            stdin: submission.stdin,
+            expected_output: submission.expected_output,
+            cpu_time_limit: submission.cpu_time_limit,
+            cpu_extra_time: submission.cpu_extra_time,
+            wall_time_limit: submission.wall_time_limit,
+            memory_limit: submission.memory_limit,
+            stack_limit: submission.stack_limit,
+            max_processes_and_or_threads: submission.max_processes_and_or_threads,
+            enable_per_process_and_thread_time_limit: submission
+                .enable_per_process_and_thread_time_limit,
+            enable_per_process_and_thread_memory_limit: submission
+                .enable_per_process_and_thread_memory_limit,
+            max_file_size: submission.max_file_size,
+            redirect_stderr_to_stdout: submission.redirect_stderr_to_stdout,
+            enable_network: submission.enable_network,
+            number_of_runs: submission.number_of_runs,
+            additional_files: submission.additional_files,
+            callback_url: submission.callback_url,
+            test_groups: submission.test_groups,
+            comparator: submission.comparator,
+            mode: Some(submission.mode),
+            stress: submission.stress,
+            input_file: submission.input_file,
+            output_file: submission.output_file,
+            tags: submission.tags,
+            priority: Some(submission.priority),
+        }
+    }
+}
+
+/// A jury program such as a testlib checker, validator or generator.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Program {
+    pub source_code: String,
+    pub language: Language,
 }
 
+/// Stress programs with their languages resolved, as sent to the worker.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StressTest {
+    pub generator: Program,
+    pub reference: Program,
+    pub iterations: i32,
+}
+
+/// A test generator, referenced by `name` from the tests it produces.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Generator {
+    pub name: String,
+    pub program: Program,
+}
+
+/// The tests of a stored problem as of `version`, which changes with them so
+/// that workers know when their cached copy is stale.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ProblemVersion {
+    pub id: String,
+    pub version: i32,
+}
+
+/// Driver code spliced around a function-style submission.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Template {
+    pub template: String,
+    /// Hidden code that replaces the harness placeholder, if the template has one.
+    pub harness: Option<String>,
+}
+
+impl Template {
+    pub const USER_CODE: &'static str = "{{user_code}}";
+    pub const HARNESS: &'static str = "{{harness}}";
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct NatsSubmission{
     pub id:String,
-    pub source_code: String,
+    pub source_code: RawText,
     pub language: Language,
     pub compiler_options: Option<String>,
     pub command_line_arguments: Option<String>,
-    pub stdin: Option<String>,
-    pub expected_output: Option<String>,
+    pub stdin: Option<RawText>,
+    pub expected_output: Option<RawText>,
     pub cpu_time_limit: Option<f64>,
     pub cpu_extra_time: Option<f64>,
     pub wall_time_limit: Option<f64>,
@@ -184,13 +728,33 @@ pub struct NatsSubmission{
     pub number_of_runs: Option<i32>,
     pub additional_files: Option<String>,
     pub callback_url: Option<String>,
+    pub test_groups: Option<Vec<TestGroup>>,
+    /// Stored problem whose tests the worker judges against instead of
+    /// `test_groups`. They are fetched by the worker rather than sent along.
+    pub problem: Option<ProblemVersion>,
+    pub checker: Option<Program>,
+    /// Testlib interactor of an interactive problem, which talks to the
+    /// submission and decides the verdict of each test.
+    pub interactor: Option<Program>,
+    pub validator: Option<Program>,
+    pub generators: Option<Vec<Generator>>,
+    /// Main solution of the problem, which produces the answers of generated
+    /// tests.
+    pub solution: Option<Program>,
+    pub comparator: Option<Comparator>,
+    pub stress: Option<StressTest>,
+    pub input_file: Option<String>,
+    pub output_file: Option<String>,
+    pub template: Option<Template>,
+    /// Bytes of output a session may send before its program is killed.
+    pub session_output_limit: Option<u64>,
 }
 
 
 impl From<(String, NewSubmission,Language)> for NatsSubmission {
     fn from((id, ns,lang): (String, NewSubmission,Language)) -> Self {
         Self {
-            id: id,
+            id,
             source_code: ns.source_code,
             language: lang,
             compiler_options: ns.compiler_options,
@@ -211,6 +775,19 @@ impl From<(String, NewSubmission,Language)> for NatsSubmission {
             number_of_runs: ns.number_of_runs,
             additional_files: ns.additional_files,
             callback_url: ns.callback_url,
+            test_groups: ns.test_groups,
+            problem: None,
+            checker: None,
+            interactor: None,
+            validator: None,
+            generators: None,
+            solution: None,
+            comparator: ns.comparator,
+            stress: None,
+            input_file: ns.input_file,
+            output_file: ns.output_file,
+            template: None,
+            session_output_limit: None,
         }
     }
 }
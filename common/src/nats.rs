@@ -1,49 +1,253 @@
 use anyhow::{Result, anyhow};
+use async_nats::jetstream::ErrorCode;
 use async_nats::jetstream::consumer::{AckPolicy, DeliverPolicy, PullConsumer};
+use async_nats::jetstream::context::GetStreamErrorKind;
+use async_nats::jetstream::kv::{self, Operation};
+use async_nats::jetstream::stream::ConsumerErrorKind;
 use async_nats::jetstream::{self, Context, stream::RetentionPolicy, stream::StorageType};
-use futures::StreamExt;
+use async_nats::{Client, Subscriber};
+use futures::{StreamExt, future};
+use std::collections::HashSet;
+use std::future::Future;
+use std::time::Duration;
+
+use crate::model::SubmissionPriority;
+
+/// JetStream subject of queued submissions of `priority` in `language`; `*`
+/// matches every language.
+pub fn submission_subject(priority: SubmissionPriority, language: &str) -> String {
+    format!("submission.new.{}.{}", priority.as_str(), language)
+}
+
+/// Whether `language` can be the last token of a subject.
+pub fn is_subject_token(language: &str) -> bool {
+    !language.is_empty()
+        && !language
+            .chars()
+            .any(|c| c == '.' || c == '*' || c == '>' || c.is_whitespace())
+}
+
+/// JetStream stream holding the queued submissions of `priority`, in every
+/// language.
+pub fn submission_stream(priority: SubmissionPriority) -> String {
+    format!("SUBMISSIONS_{}", priority.as_str().to_uppercase())
+}
+
+/// Core subject on which the worker announces that a submission is finished.
+pub fn done_subject(id: &str) -> String {
+    format!("submission.done.{}", id)
+}
+
+/// Core subject carrying the progress events of a submission.
+pub fn events_subject(id: &str) -> String {
+    format!("submission.events.{}", id)
+}
+
+/// Core subject on which interactive sessions in `language` are requested from
+/// the workers running it.
+pub fn session_request_subject(language: &str) -> String {
+    format!("session.new.{}", language)
+}
+
+/// Core subject of one direction of an interactive session, `input` towards
+/// the worker and `output` towards the API.
+pub fn session_subject(id: &str, direction: &str) -> String {
+    format!("session.{}.{}", id, direction)
+}
+
+/// Key-value bucket whose keys are the ids of cancelled submissions.
+const CANCELLATIONS_BUCKET: &str = "cancellations";
+
+/// How long a cancellation is remembered, well past any queueing delay.
+const CANCELLATION_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Key-value bucket where every worker host advertises the languages it runs,
+/// keyed by its worker id.
+const WORKERS_BUCKET: &str = "workers";
+
+/// How long an advertisement lasts unless the worker renews it.
+pub const WORKER_TTL: Duration = Duration::from_secs(30);
 
 #[derive(Clone)]
 pub struct NatsClient {
+    pub client: Client,
     pub js: Context,
+    pub cancellations: kv::Store,
+    pub workers: kv::Store,
 }
 
 impl NatsClient {
     pub async fn new(url: &str) -> Result<Self> {
-        let connection = async_nats::connect(url).await?;
-        let js = jetstream::new(connection);
-        Ok(Self { js })
+        let client = async_nats::connect(url).await?;
+        let js = jetstream::new(client.clone());
+        let cancellations = js
+            .create_or_update_key_value(kv::Config {
+                bucket: CANCELLATIONS_BUCKET.to_string(),
+                max_age: CANCELLATION_TTL,
+                storage: StorageType::Memory,
+                ..Default::default()
+            })
+            .await?;
+        let workers = js
+            .create_or_update_key_value(kv::Config {
+                bucket: WORKERS_BUCKET.to_string(),
+                max_age: WORKER_TTL,
+                storage: StorageType::Memory,
+                ..Default::default()
+            })
+            .await?;
+        Ok(Self {
+            client,
+            js,
+            cancellations,
+            workers,
+        })
+    }
+
+    /// Flags submission `id` as cancelled for the workers.
+    pub async fn cancel(&self, id: &str) -> Result<()> {
+        self.cancellations.put(id, "1".into()).await?;
+        Ok(())
+    }
+
+    /// Withdraws the cancellation of submission `id` before it runs again.
+    pub async fn uncancel(&self, id: &str) -> Result<()> {
+        self.cancellations.purge(id).await?;
+        Ok(())
+    }
+
+    /// Returns a future that completes once submission `id` is cancelled,
+    /// immediately if it already is. It never completes if the watch fails.
+    pub async fn cancelled(&self, id: &str) -> Result<impl Future<Output = ()> + Send + use<>> {
+        let mut watch = self.cancellations.watch_with_history(id).await?;
+        Ok(async move {
+            while let Some(entry) = watch.next().await {
+                if entry.is_ok_and(|entry| entry.operation == Operation::Put) {
+                    return;
+                }
+            }
+            future::pending().await
+        })
     }
 
+    /// Advertises for [`WORKER_TTL`] that worker host `worker_id` runs
+    /// `languages`.
+    pub async fn advertise(&self, worker_id: &str, languages: &[String]) -> Result<()> {
+        // Keys allow fewer characters than host names.
+        let key: String = worker_id
+            .chars()
+            .map(|c| match c {
+                'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' => c,
+                _ => '_',
+            })
+            .collect();
+        self.workers
+            .put(key, serde_json::to_vec(languages)?.into())
+            .await?;
+        Ok(())
+    }
+
+    /// Languages advertised by worker hosts whose advertisement has not expired.
+    pub async fn live_languages(&self) -> Result<HashSet<String>> {
+        let mut languages = HashSet::new();
+        let mut keys = self.workers.keys().await?;
+        while let Some(key) = keys.next().await {
+            if let Some(entry) = self.workers.get(key?).await? {
+                languages.extend(serde_json::from_slice::<Vec<String>>(&entry)?);
+            }
+        }
+        Ok(languages)
+    }
+
+    /// Publishes to JetStream and waits until the stream stored the message.
     pub async fn publish(&self, subject: String, data: Vec<u8>) -> Result<()> {
-        self.js.publish(subject.clone(), data.into()).await?;
+        self.js.publish(subject, data.into()).await?.await?;
+        Ok(())
+    }
+
+    /// Stream `stream`, or `None` if it does not exist.
+    async fn existing_stream(&self, stream: &str) -> Result<Option<jetstream::stream::Stream>> {
+        match self.js.get_stream(stream).await {
+            Ok(stream) => Ok(Some(stream)),
+            Err(err) => match err.kind() {
+                GetStreamErrorKind::JetStream(err)
+                    if err.error_code() == ErrorCode::STREAM_NOT_FOUND =>
+                {
+                    Ok(None)
+                }
+                _ => Err(err.into()),
+            },
+        }
+    }
+
+    /// Deletes consumer `durable` of `stream` if both exist.
+    pub async fn delete_consumer(&self, stream: &str, durable: &str) -> Result<()> {
+        let Some(stream) = self.existing_stream(stream).await? else {
+            return Ok(());
+        };
+        match stream.delete_consumer(durable).await {
+            Ok(_) => Ok(()),
+            Err(err) => match err.kind() {
+                ConsumerErrorKind::JetStream(err)
+                    if err.error_code() == ErrorCode::CONSUMER_NOT_FOUND =>
+                {
+                    Ok(())
+                }
+                _ => Err(err.into()),
+            },
+        }
+    }
+
+    /// Deletes JetStream stream `stream` with the messages left in it.
+    pub async fn delete_stream(&self, stream: &str) -> Result<()> {
+        self.js.delete_stream(stream).await?;
+        Ok(())
+    }
+
+    /// Publishes on a core subject. Nothing is stored, so only current
+    /// subscribers receive the message.
+    pub async fn notify(&self, subject: String, data: Vec<u8>) -> Result<()> {
+        self.client.publish(subject, data.into()).await?;
         Ok(())
     }
+
+    pub async fn subscribe(&self, subject: String) -> Result<Subscriber> {
+        Ok(self.client.subscribe(subject).await?)
+    }
+
+    /// Subscribes as part of `group`, so each message reaches only one member.
+    pub async fn queue_subscribe(&self, subject: String, group: String) -> Result<Subscriber> {
+        Ok(self.client.queue_subscribe(subject, group).await?)
+    }
 }
 
 pub struct NatsConsumer {
-    js: Context,
-    stream: String,
-    subject: String,
     consumer: PullConsumer,
 }
 
 impl NatsConsumer {
+    /// Consumes the messages on `subject` from `stream`, which is created with
+    /// `stream_subject` if missing or updated to it. Consumers of a stream must
+    /// not overlap.
     pub async fn new(
         nats: &NatsClient,
         stream: &str,
+        stream_subject: &str,
         subject: &str,
         durable: &str,
     ) -> Result<Self> {
-        let consumer: PullConsumer = nats
-            .js
-            .get_or_create_stream(jetstream::stream::Config {
+        nats.js
+            .create_or_update_stream(jetstream::stream::Config {
                 name: stream.to_string(),
-                subjects: vec![subject.to_string()],
+                subjects: vec![stream_subject.to_string()],
                 storage: StorageType::Memory,
                 retention: RetentionPolicy::WorkQueue,
                 ..Default::default()
             })
+            .await?;
+        let consumer: PullConsumer = nats
+            .js
+            .get_stream(stream)
             .await?
             .get_or_create_consumer(
                 durable,
@@ -51,27 +255,69 @@ impl NatsConsumer {
                     durable_name: Some(durable.to_string()),
                     deliver_policy: DeliverPolicy::All,
                     ack_policy: AckPolicy::Explicit,
-                    // filter_subject: subject.to_string(),
+                    filter_subject: subject.to_string(),
                     max_ack_pending: 1,
                     ..Default::default()
                 },
             )
             .await?;
 
-        Ok(Self {
-            js: nats.js.clone(),
-            stream: stream.to_string(),
-            subject: subject.to_string(),
-            consumer,
-        })
+        Ok(Self { consumer })
+    }
+
+    /// Consumes every message of `stream` through `durable`, or returns `None`
+    /// if the stream does not exist, e.g. a queue of an earlier version.
+    pub async fn existing(nats: &NatsClient, stream: &str, durable: &str) -> Result<Option<Self>> {
+        let Some(stream) = nats.existing_stream(stream).await? else {
+            return Ok(None);
+        };
+        let consumer: PullConsumer = stream
+            .get_or_create_consumer(
+                durable,
+                jetstream::consumer::pull::Config {
+                    durable_name: Some(durable.to_string()),
+                    deliver_policy: DeliverPolicy::All,
+                    ack_policy: AckPolicy::Explicit,
+                    max_ack_pending: 1,
+                    ..Default::default()
+                },
+            )
+            .await?;
+
+        Ok(Some(Self { consumer }))
+    }
+
+    /// Publishes every waiting message again on the subject `subject` picks for
+    /// its payload, acknowledging each once stored, and returns how many were
+    /// moved. Messages it picks none for are dropped.
+    pub async fn forward(
+        &self,
+        nats: &NatsClient,
+        subject: impl Fn(&[u8]) -> Option<String>,
+    ) -> Result<usize> {
+        let mut moved = 0;
+        loop {
+            let mut messages = self.consumer.fetch().max_messages(1).messages().await?;
+            let Some(msg) = messages.next().await else {
+                return Ok(moved);
+            };
+            let msg = msg.map_err(|e| anyhow!("error fetching message: {}", e))?;
+            if let Some(subject) = subject(&msg.payload) {
+                nats.publish(subject, msg.payload.to_vec()).await?;
+                moved += 1;
+            }
+            msg.ack()
+                .await
+                .map_err(|e| anyhow!("error acknowledging message: {}", e))?;
+        }
     }
 
+    /// Takes the next message if one is waiting, without blocking.
     pub async fn pull(&self) -> Result<Option<Vec<u8>>> {
-        let mut messages = self.consumer.messages().await?.take(1);
+        let mut messages = self.consumer.fetch().max_messages(1).messages().await?;
         if let Some(Ok(msg)) = messages.next().await {
             let payload = msg.payload.to_vec();
-            let _ = msg
-                .ack()
+            msg.ack()
                 .await
                 .map_err(|e| anyhow!("error acknowledging message: {}", e))?;
             Ok(Some(payload))
@@ -0,0 +1,86 @@
+use anyhow::Result;
+use redis::Client;
+use redis::aio::MultiplexedConnection;
+use std::time::Duration;
+use tokio::sync::Mutex;
+
+/// Takes one token from the bucket at `KEYS[1]`, which holds at most
+/// `ARGV[2]` tokens and refills at `ARGV[1]` tokens per second. Returns
+/// whether a token was taken and, if not, in how many milliseconds one will
+/// be available. The clock is Redis' own so that every API instance agrees.
+const TOKEN_BUCKET_SCRIPT: &str = r#"
+local rate = tonumber(ARGV[1])
+local capacity = tonumber(ARGV[2])
+local time = redis.call('TIME')
+local now = tonumber(time[1]) + tonumber(time[2]) / 1000000
+
+local bucket = redis.call('HMGET', KEYS[1], 'tokens', 'at')
+local tokens = tonumber(bucket[1]) or capacity
+local at = tonumber(bucket[2]) or now
+tokens = math.min(capacity, tokens + math.max(0, now - at) * rate)
+
+local allowed = 0
+local retry_after = 0
+if tokens >= 1 then
+  tokens = tokens - 1
+  allowed = 1
+else
+  retry_after = math.ceil((1 - tokens) / rate * 1000)
+end
+
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'at', tostring(now))
+redis.call('EXPIRE', KEYS[1], math.ceil(capacity / rate) + 1)
+return {allowed, retry_after}
+"#;
+
+/// Redis connection shared by all requests, opened on first use and again
+/// after it failed.
+pub struct RedisClient {
+    client: Client,
+    connection: Mutex<Option<MultiplexedConnection>>,
+}
+
+impl RedisClient {
+    pub fn new(url: &str) -> Result<Self> {
+        Ok(Self {
+            client: Client::open(url)?,
+            connection: Mutex::new(None),
+        })
+    }
+
+    async fn connection(&self) -> Result<MultiplexedConnection> {
+        let mut connection = self.connection.lock().await;
+        if let Some(connection) = connection.as_ref() {
+            return Ok(connection.clone());
+        }
+        let connected = self.client.get_multiplexed_async_connection().await?;
+        *connection = Some(connected.clone());
+        Ok(connected)
+    }
+
+    /// Takes a token from the bucket `key`, see [`TOKEN_BUCKET_SCRIPT`].
+    /// Returns `None` if one was taken, or how long until one is available.
+    pub async fn take_token(&self, key: &str, rate: f64, burst: u32) -> Result<Option<Duration>> {
+        let mut connection = self.connection().await?;
+        let result: Result<(i64, u64), _> = redis::cmd("EVAL")
+            .arg(TOKEN_BUCKET_SCRIPT)
+            .arg(1)
+            .arg(key)
+            .arg(rate)
+            .arg(burst)
+            .query_async(&mut connection)
+            .await;
+        let (allowed, retry_after) = match result {
+            Ok(result) => result,
+            Err(err) => {
+                // Reconnect on the next call rather than reusing a broken connection.
+                *self.connection.lock().await = None;
+                return Err(err.into());
+            }
+        };
+        match allowed {
+            1 => Ok(None),
+            _ => Ok(Some(Duration::from_millis(retry_after))),
+        }
+    }
+}
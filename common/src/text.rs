@@ -0,0 +1,119 @@
+use std::borrow::Cow;
+use std::ops::Deref;
+
+use poem_openapi::{
+    registry::{MetaSchema, MetaSchemaRef},
+    types::{ParseError, ParseFromJSON, ParseResult, ToJSON, Type},
+};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+/// Text that is kept as raw bytes, such as a program's input, since it need
+/// not be valid UTF-8. The API reads and writes it as a plain string, with
+/// invalid UTF-8 replaced on the way out. Queue messages carry it as a string
+/// too unless it is not UTF-8, and then as an array of bytes.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RawText(pub Vec<u8>);
+
+impl RawText {
+    /// The text with invalid UTF-8 replaced.
+    pub fn to_text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(&self.0)
+    }
+}
+
+impl Deref for RawText {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for RawText {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<String> for RawText {
+    fn from(text: String) -> Self {
+        RawText(text.into_bytes())
+    }
+}
+
+impl From<Vec<u8>> for RawText {
+    fn from(bytes: Vec<u8>) -> Self {
+        RawText(bytes)
+    }
+}
+
+impl Type for RawText {
+    const IS_REQUIRED: bool = true;
+
+    type RawValueType = Self;
+
+    type RawElementValueType = Self;
+
+    fn name() -> Cow<'static, str> {
+        "string".into()
+    }
+
+    fn schema_ref() -> MetaSchemaRef {
+        MetaSchemaRef::Inline(Box::new(MetaSchema::new("string")))
+    }
+
+    fn as_raw_value(&self) -> Option<&Self::RawValueType> {
+        Some(self)
+    }
+
+    fn raw_element_iter<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = &'a Self::RawElementValueType> + 'a> {
+        Box::new(self.as_raw_value().into_iter())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl ParseFromJSON for RawText {
+    fn parse_from_json(value: Option<Value>) -> ParseResult<Self> {
+        match value.unwrap_or_default() {
+            Value::String(text) => Ok(RawText::from(text)),
+            value => Err(ParseError::expected_type(value)),
+        }
+    }
+}
+
+impl ToJSON for RawText {
+    fn to_json(&self) -> Option<Value> {
+        Some(Value::String(self.to_text().into_owned()))
+    }
+}
+
+impl Serialize for RawText {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match std::str::from_utf8(&self.0) {
+            Ok(text) => serializer.serialize_str(text),
+            Err(_) => self.0.serialize(serializer),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for RawText {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Encoded {
+            Text(String),
+            Bytes(Vec<u8>),
+        }
+
+        Ok(match Encoded::deserialize(deserializer)? {
+            Encoded::Text(text) => RawText::from(text),
+            Encoded::Bytes(bytes) => RawText(bytes),
+        })
+    }
+}
@@ -0,0 +1,240 @@
+use anyhow::{Result, anyhow};
+use common::{
+    auth::{ApiKeyQuotas, ApiKeyScope, NewApiKey, create_api_key},
+    db::Db,
+    model::{ScoringPolicy, Template},
+};
+use std::path::PathBuf;
+use uuid::Uuid;
+
+mod polygon;
+
+/// Source code of a jury program together with its language name.
+pub struct Program {
+    pub source_code: String,
+    pub language: String,
+}
+
+/// A problem read from a package, ready to be written to the database.
+pub struct ImportedProblem {
+    pub name: String,
+    pub time_limit: Option<f64>,
+    pub memory_limit: Option<f64>,
+    pub checker: Option<Program>,
+    /// Talks to the submission in interactive problems.
+    pub interactor: Option<Program>,
+    pub validator: Option<Program>,
+    /// Produces the answers of generated tests.
+    pub main_solution: Option<Program>,
+    pub input_file: Option<String>,
+    pub output_file: Option<String>,
+    pub generators: Vec<(String, Program)>,
+    pub test_groups: Vec<ImportedGroup>,
+}
+
+/// A test group of an imported problem.
+pub struct ImportedGroup {
+    pub name: String,
+    pub points: f64,
+    pub policy: ScoringPolicy,
+    pub tests: Vec<ImportedTest>,
+}
+
+/// Inputs and answers are kept as bytes, tests need not be text.
+pub struct ImportedTest {
+    pub input: Option<Vec<u8>>,
+    pub answer: Option<Vec<u8>>,
+    pub generator: Option<String>,
+    pub generator_args: Option<String>,
+}
+
+const USAGE: &str = "usage: importer polygon <package.zip>
+       importer template <problem_id> <language> <template> [harness]
+       importer api-key <name> <scope,...>";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL not found");
+
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        ["polygon", package] => {
+            let problem = polygon::import_package(&PathBuf::from(package))?;
+            let db = Db::init(&database_url).await?;
+            let id = store_problem(&db, &problem).await?;
+            println!("{}", id);
+        }
+        ["template", problem_id, language, template] => {
+            let db = Db::init(&database_url).await?;
+            store_template(&db, problem_id, language, template, None).await?;
+        }
+        ["template", problem_id, language, template, harness] => {
+            let db = Db::init(&database_url).await?;
+            store_template(&db, problem_id, language, template, Some(harness)).await?;
+        }
+        ["api-key", name, scopes] => {
+            let db = Db::init(&database_url).await?;
+            let key = store_api_key(&db, name, scopes).await?;
+            println!("{}", key);
+        }
+        _ => return Err(anyhow!(USAGE)),
+    }
+
+    Ok(())
+}
+
+/// Creates an API key without quotas, e.g. the first admin key, and returns
+/// the key itself.
+async fn store_api_key(db: &Db, name: &str, scopes: &str) -> Result<String> {
+    let scopes = scopes
+        .split(',')
+        .map(|scope| {
+            scope
+                .trim()
+                .parse::<ApiKeyScope>()
+                .map_err(|_| anyhow!("unknown scope `{}`", scope))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let new_key = NewApiKey {
+        name: name.to_string(),
+        scopes,
+        quotas: ApiKeyQuotas::default(),
+    };
+    let (_, key) = create_api_key(db, &new_key).await?;
+    Ok(key)
+}
+
+/// Adds or replaces the driver code of a function-style problem for `language`.
+async fn store_template(
+    db: &Db,
+    problem_id: &str,
+    language: &str,
+    template: &str,
+    harness: Option<&str>,
+) -> Result<()> {
+    let problem_id = Uuid::parse_str(problem_id)?;
+    let template = Template {
+        template: std::fs::read_to_string(template)?,
+        harness: harness.map(std::fs::read_to_string).transpose()?,
+    };
+    if !template.template.contains(Template::USER_CODE) {
+        return Err(anyhow!(
+            "template has no `{}` placeholder",
+            Template::USER_CODE
+        ));
+    }
+
+    db.execute(
+        r#"
+        INSERT INTO problem_templates (problem_id, language, template, harness)
+        VALUES ($1, $2, $3, $4)
+        ON CONFLICT (problem_id, language)
+        DO UPDATE SET template = EXCLUDED.template, harness = EXCLUDED.harness
+        "#,
+        &[
+            &problem_id,
+            &language,
+            &template.template,
+            &template.harness,
+        ],
+    )
+    .await?;
+    Ok(())
+}
+
+async fn store_problem(db: &Db, problem: &ImportedProblem) -> Result<Uuid> {
+    let mut client = db.pool.get().await?;
+    let tx = client.transaction().await?;
+
+    let row = tx
+        .query_one(
+            r#"
+        INSERT INTO problems (
+            name,
+            time_limit,
+            memory_limit,
+            checker_source,
+            checker_language,
+            interactor_source,
+            interactor_language,
+            validator_source,
+            validator_language,
+            main_solution_source,
+            main_solution_language,
+            input_file,
+            output_file
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)
+        RETURNING id
+        "#,
+            &[
+                &problem.name,
+                &problem.time_limit,
+                &problem.memory_limit,
+                &problem.checker.as_ref().map(|c| &c.source_code),
+                &problem.checker.as_ref().map(|c| &c.language),
+                &problem.interactor.as_ref().map(|i| &i.source_code),
+                &problem.interactor.as_ref().map(|i| &i.language),
+                &problem.validator.as_ref().map(|v| &v.source_code),
+                &problem.validator.as_ref().map(|v| &v.language),
+                &problem.main_solution.as_ref().map(|s| &s.source_code),
+                &problem.main_solution.as_ref().map(|s| &s.language),
+                &problem.input_file,
+                &problem.output_file,
+            ],
+        )
+        .await?;
+    let id: Uuid = row.get("id");
+
+    for (name, generator) in &problem.generators {
+        tx.execute(
+            r#"
+        INSERT INTO problem_generators (problem_id, name, source, language)
+        VALUES ($1, $2, $3, $4)
+        "#,
+            &[&id, name, &generator.source_code, &generator.language],
+        )
+        .await?;
+    }
+
+    let mut test_position = 0i32;
+    for (group_position, group) in problem.test_groups.iter().enumerate() {
+        tx.execute(
+            r#"
+        INSERT INTO problem_test_groups (problem_id, name, position, points, policy)
+        VALUES ($1, $2, $3, $4, $5)
+        "#,
+            &[
+                &id,
+                &group.name,
+                &(group_position as i32),
+                &group.points,
+                &group.policy.as_str(),
+            ],
+        )
+        .await?;
+
+        for test in &group.tests {
+            test_position += 1;
+            tx.execute(
+                r#"
+        INSERT INTO problem_tests (
+            problem_id, group_name, position, input, generator, generator_args, answer
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7)
+        "#,
+                &[
+                    &id,
+                    &group.name,
+                    &test_position,
+                    &test.input,
+                    &test.generator,
+                    &test.generator_args,
+                    &test.answer,
+                ],
+            )
+            .await?;
+        }
+    }
+
+    tx.commit().await?;
+    Ok(id)
+}
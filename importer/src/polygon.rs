@@ -0,0 +1,436 @@
+use anyhow::{Context, Result, anyhow};
+use common::model::ScoringPolicy;
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use uuid::Uuid;
+
+use crate::{ImportedGroup, ImportedProblem, ImportedTest, Program};
+
+#[derive(Debug, Deserialize)]
+struct ProblemXml {
+    #[serde(rename = "@short-name")]
+    short_name: String,
+    #[serde(default)]
+    names: Names,
+    judging: Judging,
+    #[serde(default)]
+    files: Files,
+    #[serde(default)]
+    assets: Assets,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct Files {
+    #[serde(default)]
+    executables: Executables,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct Executables {
+    #[serde(rename = "executable", default)]
+    executables: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct Names {
+    #[serde(rename = "name", default)]
+    names: Vec<Name>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Name {
+    #[serde(rename = "@language")]
+    language: String,
+    #[serde(rename = "@value")]
+    value: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Judging {
+    /// Empty when the problem uses stdin.
+    #[serde(rename = "@input-file", default)]
+    input_file: String,
+    /// Empty when the problem uses stdout.
+    #[serde(rename = "@output-file", default)]
+    output_file: String,
+    #[serde(rename = "testset", default)]
+    testsets: Vec<Testset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Testset {
+    #[serde(rename = "@name")]
+    name: String,
+    #[serde(rename = "time-limit")]
+    time_limit: Option<u64>,
+    #[serde(rename = "memory-limit")]
+    memory_limit: Option<u64>,
+    #[serde(rename = "input-path-pattern")]
+    input_path_pattern: String,
+    #[serde(rename = "answer-path-pattern")]
+    answer_path_pattern: Option<String>,
+    #[serde(default)]
+    tests: Tests,
+    #[serde(default)]
+    groups: Groups,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct Tests {
+    #[serde(rename = "test", default)]
+    tests: Vec<Test>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Test {
+    #[serde(rename = "@method")]
+    method: Option<String>,
+    #[serde(rename = "@cmd")]
+    cmd: Option<String>,
+    #[serde(rename = "@group")]
+    group: Option<String>,
+    #[serde(rename = "@points")]
+    points: Option<f64>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct Groups {
+    #[serde(rename = "group", default)]
+    groups: Vec<Group>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Group {
+    #[serde(rename = "@name")]
+    name: String,
+    #[serde(rename = "@points")]
+    points: Option<f64>,
+    #[serde(rename = "@points-policy")]
+    points_policy: Option<String>,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct Assets {
+    checker: Option<Asset>,
+    interactor: Option<Asset>,
+    #[serde(default)]
+    validators: Validators,
+    #[serde(default)]
+    solutions: Solutions,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct Solutions {
+    #[serde(rename = "solution", default)]
+    solutions: Vec<Solution>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Solution {
+    #[serde(rename = "@tag")]
+    tag: String,
+    source: Source,
+}
+
+#[derive(Debug, Deserialize, Default)]
+struct Validators {
+    #[serde(rename = "validator", default)]
+    validators: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Asset {
+    source: Source,
+}
+
+#[derive(Debug, Deserialize)]
+struct Source {
+    #[serde(rename = "@path")]
+    path: String,
+    #[serde(rename = "@type")]
+    kind: String,
+}
+
+/// Name of the group used for tests that do not belong to any Polygon group.
+const DEFAULT_GROUP: &str = "tests";
+
+/// Reads a Polygon package zip into a problem ready to be stored.
+pub fn import_package(package: &Path) -> Result<ImportedProblem> {
+    let dir = std::env::temp_dir().join(format!("polygon-{}", Uuid::new_v4()));
+    let status = Command::new("unzip")
+        .args(["-q", "-o"])
+        .arg(package)
+        .arg("-d")
+        .arg(&dir)
+        .status()
+        .context("Failed to run unzip")?;
+
+    if !status.success() {
+        let _ = fs::remove_dir_all(&dir);
+        return Err(anyhow!("Failed to extract {}", package.display()));
+    }
+
+    let problem = read_package(&dir);
+    let _ = fs::remove_dir_all(&dir);
+    problem
+}
+
+fn read_package(root: &Path) -> Result<ImportedProblem> {
+    let xml = fs::read_to_string(root.join("problem.xml")).context("Missing problem.xml")?;
+    let problem: ProblemXml = quick_xml::de::from_str(&xml).context("Invalid problem.xml")?;
+
+    let testset = problem
+        .judging
+        .testsets
+        .iter()
+        .find(|t| t.name == "tests")
+        .or(problem.judging.testsets.first())
+        .ok_or(anyhow!("problem.xml has no testset"))?;
+
+    let name = problem
+        .names
+        .names
+        .iter()
+        .find(|n| n.language == "english")
+        .or(problem.names.names.first())
+        .map(|n| n.value.clone())
+        .unwrap_or(problem.short_name.clone());
+
+    let generators = read_generators(root, &problem.files.executables.executables, testset)?;
+    let main_solution = problem
+        .assets
+        .solutions
+        .solutions
+        .iter()
+        .find(|s| s.tag == "main")
+        .map(|solution| read_program(root, &solution.source))
+        .transpose()?;
+    if !generators.is_empty() && main_solution.is_none() {
+        return Err(anyhow!(
+            "Generated tests need a main solution for their answers"
+        ));
+    }
+
+    Ok(ImportedProblem {
+        name,
+        time_limit: testset.time_limit.map(|ms| ms as f64 / 1000.0),
+        memory_limit: testset.memory_limit.map(|bytes| (bytes / 1024) as f64),
+        checker: problem
+            .assets
+            .checker
+            .as_ref()
+            .map(|asset| read_program(root, &asset.source))
+            .transpose()?,
+        interactor: problem
+            .assets
+            .interactor
+            .as_ref()
+            .map(|asset| read_program(root, &asset.source))
+            .transpose()?,
+        validator: problem
+            .assets
+            .validators
+            .validators
+            .first()
+            .map(|asset| read_program(root, &asset.source))
+            .transpose()?,
+        main_solution,
+        input_file: Some(problem.judging.input_file.clone()).filter(|f| !f.is_empty()),
+        output_file: Some(problem.judging.output_file.clone()).filter(|f| !f.is_empty()),
+        generators,
+        test_groups: read_test_groups(root, testset)?,
+    })
+}
+
+/// Reads the executables that generate tests not stored in the package.
+fn read_generators(
+    root: &Path,
+    executables: &[Asset],
+    testset: &Testset,
+) -> Result<Vec<(String, Program)>> {
+    let mut generators: Vec<(String, Program)> = Vec::new();
+
+    for (index, test) in testset.tests.tests.iter().enumerate() {
+        let input_path = root.join(format_pattern(&testset.input_path_pattern, index + 1));
+        let Some((name, _)) = generator_call(test) else {
+            continue;
+        };
+        if input_path.is_file() || generators.iter().any(|(g, _)| g == name) {
+            continue;
+        }
+
+        let executable = executables
+            .iter()
+            .find(|e| executable_name(&e.source.path) == name)
+            .ok_or(anyhow!("Generator `{}` for test {} not found", name, index + 1))?;
+        generators.push((name.to_string(), read_program(root, &executable.source)?));
+    }
+
+    Ok(generators)
+}
+
+/// Splits the `cmd` of a generated test into the generator name and its arguments.
+fn generator_call(test: &Test) -> Option<(&str, &str)> {
+    if test.method.as_deref() != Some("generated") {
+        return None;
+    }
+    let cmd = test.cmd.as_deref()?.trim();
+    Some(cmd.split_once(' ').unwrap_or((cmd, "")))
+}
+
+fn executable_name(path: &str) -> &str {
+    Path::new(path)
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or(path)
+}
+
+fn read_test_groups(root: &Path, testset: &Testset) -> Result<Vec<ImportedGroup>> {
+    let mut groups: Vec<ImportedGroup> = Vec::new();
+    // Sum of the per-test points of each group, in the same order as `groups`.
+    let mut test_points: Vec<f64> = Vec::new();
+
+    for (index, test) in testset.tests.tests.iter().enumerate() {
+        let number = index + 1;
+        let input_path = root.join(format_pattern(&testset.input_path_pattern, number));
+        // Generated tests missing from the package are generated by the worker.
+        let (input, generator) = match (fs::read(&input_path), generator_call(test)) {
+            (Ok(input), _) => (Some(input), None),
+            (Err(_), Some((name, args))) => (None, Some((name.to_string(), args.to_string()))),
+            (Err(err), None) => {
+                return Err(err).with_context(|| {
+                    format!("Missing input for test {} at {}", number, input_path.display())
+                });
+            }
+        };
+        let answer = testset
+            .answer_path_pattern
+            .as_ref()
+            .and_then(|pattern| fs::read(root.join(format_pattern(pattern, number))).ok());
+
+        let group_name = test.group.clone().unwrap_or(DEFAULT_GROUP.to_string());
+        let position = match groups.iter().position(|g| g.name == group_name) {
+            Some(position) => position,
+            None => {
+                let definition = testset.groups.groups.iter().find(|g| g.name == group_name);
+                let policy = match definition.and_then(|g| g.points_policy.as_deref()) {
+                    Some("each-test") => ScoringPolicy::Sum,
+                    Some(_) => ScoringPolicy::AllOrNothing,
+                    None if test.points.is_some() => ScoringPolicy::Sum,
+                    None => ScoringPolicy::AllOrNothing,
+                };
+                groups.push(ImportedGroup {
+                    name: group_name,
+                    points: definition.and_then(|g| g.points).unwrap_or(0.0),
+                    policy,
+                    tests: Vec::new(),
+                });
+                test_points.push(0.0);
+                groups.len() - 1
+            }
+        };
+
+        test_points[position] += test.points.unwrap_or(0.0);
+        let (generator, generator_args) = generator.unzip();
+        groups[position].tests.push(ImportedTest {
+            input,
+            answer,
+            generator,
+            generator_args,
+        });
+    }
+
+    // Polygon puts the points of `each-test` groups on the tests rather than
+    // on the group itself.
+    for (group, points) in groups.iter_mut().zip(test_points) {
+        if group.policy == ScoringPolicy::Sum || group.points == 0.0 {
+            group.points = points;
+        }
+    }
+
+    Ok(groups)
+}
+
+fn read_program(root: &Path, source: &Source) -> Result<Program> {
+    let path = root.join(&source.path);
+    let mut seen = HashSet::new();
+    Ok(Program {
+        source_code: inline_includes(root, &path, &mut seen)?,
+        language: language_name(&source.kind),
+    })
+}
+
+/// Replaces `#include "..."` lines with the included file so that checkers
+/// built against `testlib.h` compile from a single source file.
+fn inline_includes(root: &Path, path: &Path, seen: &mut HashSet<PathBuf>) -> Result<String> {
+    let source =
+        fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+    let dir = path.parent().unwrap_or(root);
+    let mut inlined = String::with_capacity(source.len());
+
+    for line in source.lines() {
+        let included = line
+            .trim()
+            .strip_prefix("#include")
+            .map(str::trim)
+            .and_then(|rest| rest.strip_prefix('"'))
+            .and_then(|rest| rest.strip_suffix('"'));
+
+        let header = included.and_then(|name| {
+            [dir.join(name), root.join("files").join(name)]
+                .into_iter()
+                .find(|candidate| candidate.is_file())
+        });
+
+        match header {
+            Some(header) => {
+                if seen.insert(header.clone()) {
+                    inlined.push_str(&inline_includes(root, &header, seen)?);
+                    inlined.push('\n');
+                }
+            }
+            None => {
+                inlined.push_str(line);
+                inlined.push('\n');
+            }
+        }
+    }
+
+    Ok(inlined)
+}
+
+/// Maps a Polygon source type such as `cpp.g++17` to an executor language name.
+fn language_name(kind: &str) -> String {
+    if kind.starts_with("cpp") {
+        "cpp".to_string()
+    } else if kind.starts_with("python") {
+        "python".to_string()
+    } else if kind.starts_with("java") {
+        "java".to_string()
+    } else {
+        kind.split('.').next().unwrap_or(kind).to_string()
+    }
+}
+
+/// Expands the printf-style `%d` / `%02d` placeholder of a Polygon path pattern.
+fn format_pattern(pattern: &str, number: usize) -> String {
+    let Some(start) = pattern.find('%') else {
+        return pattern.to_string();
+    };
+    let Some(end) = pattern[start..].find('d').map(|i| start + i) else {
+        return pattern.to_string();
+    };
+
+    let spec = &pattern[start + 1..end];
+    let width = spec.trim_start_matches('0').parse::<usize>().unwrap_or(0);
+    let number = if spec.starts_with('0') {
+        format!("{:0width$}", number, width = width)
+    } else {
+        format!("{:width$}", number, width = width)
+    };
+
+    format!("{}{}{}", &pattern[..start], number, &pattern[end + 1..])
+}
@@ -1,6 +1,18 @@
 -- Drop the table first since it depends on the enum types
+DROP TABLE IF EXISTS idempotency_keys;
+DROP TABLE IF EXISTS submission_outbox;
+DROP TABLE IF EXISTS submission_deletions;
+DROP TABLE IF EXISTS submission_verdicts;
+DROP TABLE IF EXISTS submission_attempts;
 DROP TABLE IF EXISTS submissions;
+DROP TABLE IF EXISTS problem_templates;
+DROP TABLE IF EXISTS problem_tests;
+DROP TABLE IF EXISTS problem_generators;
+DROP TABLE IF EXISTS problem_test_groups;
+DROP TABLE IF EXISTS problems;
+DROP TABLE IF EXISTS api_keys;
 
 -- Then drop the enum types
 DROP TYPE IF EXISTS submission_status;
+DROP TYPE IF EXISTS api_key_scope;
 DROP TYPE IF EXISTS language;
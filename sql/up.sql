@@ -15,20 +15,127 @@ BEGIN
       'runtimeerrornzec',
       'runtimeerrorother',
       'internalerror',
-      'execformaterror'
+      'execformaterror',
+      'cancelled'
     );
   END IF;
 END$$;
 
+-- Values added since the first version of the type
+ALTER TYPE submission_status ADD VALUE IF NOT EXISTS 'cancelled';
+
+DO $$
+BEGIN
+  IF NOT EXISTS (SELECT 1 FROM pg_type WHERE typname = 'api_key_scope') THEN
+    CREATE TYPE api_key_scope AS ENUM (
+      'submit',
+      'read',
+      'admin',
+      'priority'
+    );
+  END IF;
+END$$;
+
+ALTER TYPE api_key_scope ADD VALUE IF NOT EXISTS 'priority';
+
+CREATE TABLE IF NOT EXISTS api_keys (
+  id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+  name TEXT NOT NULL,
+  -- SHA-256 of the key, which itself is only shown when it is created
+  key_hash BYTEA NOT NULL UNIQUE,
+  scopes api_key_scope[] NOT NULL,
+
+  -- Quotas, unlimited when NULL
+  submissions_per_minute INTEGER,
+  max_concurrent_submissions INTEGER,
+  max_cpu_time_limit DOUBLE PRECISION,
+  max_wall_time_limit DOUBLE PRECISION,
+  max_memory_limit DOUBLE PRECISION,
+
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  revoked_at TIMESTAMPTZ
+);
+
+CREATE TABLE IF NOT EXISTS problems (
+  id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+  name TEXT NOT NULL,
+  -- Bump whenever the tests change, workers cache them per version
+  version INTEGER NOT NULL DEFAULT 1,
+
+  time_limit DOUBLE PRECISION,
+  memory_limit DOUBLE PRECISION,
+
+  checker_source TEXT,
+  checker_language TEXT,
+  interactor_source TEXT,
+  interactor_language TEXT,
+  validator_source TEXT,
+  validator_language TEXT,
+  -- Produces the answers of generated tests
+  main_solution_source TEXT,
+  main_solution_language TEXT,
+
+  -- Files used instead of stdin/stdout, e.g. input.txt and output.txt
+  input_file TEXT,
+  output_file TEXT,
+
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS problem_test_groups (
+  problem_id UUID NOT NULL REFERENCES problems(id) ON DELETE CASCADE,
+  name TEXT NOT NULL,
+  position INTEGER NOT NULL,
+  points DOUBLE PRECISION NOT NULL,
+  policy TEXT NOT NULL,
+  PRIMARY KEY (problem_id, name)
+);
+
+CREATE TABLE IF NOT EXISTS problem_generators (
+  problem_id UUID NOT NULL REFERENCES problems(id) ON DELETE CASCADE,
+  name TEXT NOT NULL,
+  source TEXT NOT NULL,
+  language TEXT NOT NULL,
+  PRIMARY KEY (problem_id, name)
+);
+
+CREATE TABLE IF NOT EXISTS problem_tests (
+  problem_id UUID NOT NULL REFERENCES problems(id) ON DELETE CASCADE,
+  group_name TEXT NOT NULL,
+  position INTEGER NOT NULL,
+  -- Either the stored input or the generator call that produces it
+  input BYTEA,
+  generator TEXT,
+  generator_args TEXT,
+  answer BYTEA,
+  PRIMARY KEY (problem_id, position),
+  FOREIGN KEY (problem_id, group_name) REFERENCES problem_test_groups(problem_id, name),
+  FOREIGN KEY (problem_id, generator) REFERENCES problem_generators(problem_id, name),
+  CHECK (input IS NOT NULL OR generator IS NOT NULL)
+);
+
+-- Driver code for function-style problems, one per language
+CREATE TABLE IF NOT EXISTS problem_templates (
+  problem_id UUID NOT NULL REFERENCES problems(id) ON DELETE CASCADE,
+  language TEXT NOT NULL,
+  template TEXT NOT NULL,
+  harness TEXT,
+  PRIMARY KEY (problem_id, language)
+);
+
 CREATE TABLE IF NOT EXISTS submissions (
   id UUID PRIMARY KEY DEFAULT gen_random_uuid(),
+  problem_id UUID REFERENCES problems(id),
+  -- The key that created the submission and may read it
+  api_key_id UUID REFERENCES api_keys(id),
 
-  source_code TEXT NOT NULL,
+  -- Raw code and input, which need not be valid UTF-8
+  source_code BYTEA NOT NULL,
   language TEXT NOT NULL,
   compiler_options TEXT,
   command_line_arguments TEXT,
-  stdin TEXT,
-  expected_output TEXT,
+  stdin BYTEA,
+  expected_output BYTEA,
 
   cpu_time_limit DOUBLE PRECISION,
   cpu_extra_time DOUBLE PRECISION,
@@ -46,10 +153,20 @@ CREATE TABLE IF NOT EXISTS submissions (
 
   additional_files TEXT,
   callback_url TEXT,
+  test_groups JSONB,
+  comparator TEXT,
+  mode TEXT NOT NULL DEFAULT 'standard',
+  stress_options JSONB,
+  input_file TEXT,
+  output_file TEXT,
+  tags TEXT[],
+  -- Queue the submission waits in: 'high', 'normal' or 'low'
+  priority TEXT NOT NULL DEFAULT 'normal',
 
-  stdout TEXT,
-  stderr TEXT,
-  compile_output TEXT,
+  -- Raw program output, which need not be valid UTF-8
+  stdout BYTEA,
+  stderr BYTEA,
+  compile_output BYTEA,
   message TEXT,
 
   exit_code INTEGER,
@@ -57,9 +174,215 @@ CREATE TABLE IF NOT EXISTS submissions (
   status submission_status NOT NULL,
 
   created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  -- When the submission was last queued, on creation or by a rejudge
+  queued_at TIMESTAMPTZ NOT NULL DEFAULT now(),
   finished_at TIMESTAMPTZ,
+  -- Set when the code, inputs and outputs were cleared on request
+  redacted_at TIMESTAMPTZ,
+
+  -- Hash of everything the submission is judged by, to reuse the verdict of
+  -- identical submissions; NULL when the result may not be reused
+  content_hash BYTEA,
+  -- The submission whose verdict was reused
+  cached_from UUID REFERENCES submissions(id) ON DELETE SET NULL,
 
   time DOUBLE PRECISION,
   wall_time DOUBLE PRECISION,
-  memory DOUBLE PRECISION
+  memory DOUBLE PRECISION,
+
+  score DOUBLE PRECISION,
+  max_score DOUBLE PRECISION,
+  group_results JSONB,
+  stress_result JSONB
 );
+
+-- Columns added since the first version of the table, ahead of the indexes
+-- on them, for databases created before
+ALTER TABLE submissions
+  ADD COLUMN IF NOT EXISTS problem_id UUID REFERENCES problems(id),
+  ADD COLUMN IF NOT EXISTS api_key_id UUID REFERENCES api_keys(id),
+  ADD COLUMN IF NOT EXISTS test_groups JSONB,
+  ADD COLUMN IF NOT EXISTS comparator TEXT,
+  ADD COLUMN IF NOT EXISTS mode TEXT NOT NULL DEFAULT 'standard',
+  ADD COLUMN IF NOT EXISTS stress_options JSONB,
+  ADD COLUMN IF NOT EXISTS input_file TEXT,
+  ADD COLUMN IF NOT EXISTS output_file TEXT,
+  ADD COLUMN IF NOT EXISTS tags TEXT[],
+  ADD COLUMN IF NOT EXISTS priority TEXT NOT NULL DEFAULT 'normal',
+  ADD COLUMN IF NOT EXISTS queued_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  ADD COLUMN IF NOT EXISTS redacted_at TIMESTAMPTZ,
+  ADD COLUMN IF NOT EXISTS content_hash BYTEA,
+  ADD COLUMN IF NOT EXISTS cached_from UUID REFERENCES submissions(id) ON DELETE SET NULL,
+  ADD COLUMN IF NOT EXISTS score DOUBLE PRECISION,
+  ADD COLUMN IF NOT EXISTS max_score DOUBLE PRECISION,
+  ADD COLUMN IF NOT EXISTS group_results JSONB,
+  ADD COLUMN IF NOT EXISTS stress_result JSONB;
+
+-- Listing pages through submissions newest first and filters by tags
+CREATE INDEX IF NOT EXISTS submissions_created_at_idx ON submissions (created_at DESC, id DESC);
+CREATE INDEX IF NOT EXISTS submissions_tags_idx ON submissions USING GIN (tags);
+
+-- Identical submissions look up the latest reusable verdict
+CREATE INDEX IF NOT EXISTS submissions_content_hash_idx ON submissions (content_hash, finished_at DESC)
+  WHERE content_hash IS NOT NULL;
+
+-- Per-key quotas count recent and unfinished submissions of a key
+CREATE INDEX IF NOT EXISTS submissions_api_key_idx ON submissions (api_key_id, created_at DESC);
+CREATE INDEX IF NOT EXISTS submissions_api_key_unfinished_idx ON submissions (api_key_id)
+  WHERE status IN ('inqueue', 'processing');
+
+-- Verdicts that submissions had before they were rejudged
+CREATE TABLE IF NOT EXISTS submission_verdicts (
+  id BIGSERIAL PRIMARY KEY,
+  submission_id UUID NOT NULL REFERENCES submissions(id) ON DELETE CASCADE,
+  status submission_status NOT NULL,
+  stdout BYTEA,
+  stderr BYTEA,
+  compile_output BYTEA,
+  message TEXT,
+  exit_code INTEGER,
+  exit_signal INTEGER,
+  time DOUBLE PRECISION,
+  wall_time DOUBLE PRECISION,
+  memory DOUBLE PRECISION,
+  score DOUBLE PRECISION,
+  max_score DOUBLE PRECISION,
+  group_results JSONB,
+  stress_result JSONB,
+  finished_at TIMESTAMPTZ,
+  rejudged_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Every time a worker ran a submission, including redeliveries and rejudges
+CREATE TABLE IF NOT EXISTS submission_attempts (
+  id BIGSERIAL PRIMARY KEY,
+  submission_id UUID NOT NULL REFERENCES submissions(id) ON DELETE CASCADE,
+  worker_id TEXT NOT NULL,
+  box_id INTEGER NOT NULL,
+  started_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  -- Renewed by the worker while it runs the submission
+  heartbeat_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  finished_at TIMESTAMPTZ,
+  status submission_status NOT NULL,
+  -- Raw isolate metadata of the run whose outputs are kept
+  metadata JSONB,
+  stdout BYTEA,
+  stderr BYTEA,
+  compile_output BYTEA,
+  message TEXT
+);
+
+CREATE INDEX IF NOT EXISTS submission_attempts_submission_idx ON submission_attempts (submission_id);
+
+-- Queue messages, written in the transaction that queues their submission and
+-- published to NATS by the relay, so that a failed publish is retried
+CREATE TABLE IF NOT EXISTS submission_outbox (
+  id BIGSERIAL PRIMARY KEY,
+  submission_id UUID NOT NULL REFERENCES submissions(id) ON DELETE CASCADE,
+  subject TEXT NOT NULL,
+  payload BYTEA NOT NULL,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  -- NULL until NATS acknowledged the message
+  sent_at TIMESTAMPTZ,
+  -- Set while a relay publishes the message, so that no other relay takes it
+  claimed_until TIMESTAMPTZ,
+  attempts INTEGER NOT NULL DEFAULT 0,
+  last_error TEXT
+);
+
+CREATE INDEX IF NOT EXISTS submission_outbox_pending_idx ON submission_outbox (id)
+  WHERE sent_at IS NULL;
+CREATE INDEX IF NOT EXISTS submission_outbox_submission_idx ON submission_outbox (submission_id);
+
+-- Queued submissions are checked for a lost queue message
+CREATE INDEX IF NOT EXISTS submissions_queued_idx ON submissions (queued_at)
+  WHERE status = 'inqueue';
+
+-- Responses of requests sent with an Idempotency-Key, per API key
+CREATE TABLE IF NOT EXISTS idempotency_keys (
+  api_key_id UUID NOT NULL REFERENCES api_keys(id) ON DELETE CASCADE,
+  key TEXT NOT NULL,
+  request_hash BYTEA NOT NULL,
+  -- NULL while the request is running
+  response JSONB,
+  created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  PRIMARY KEY (api_key_id, key)
+);
+
+-- Audit trail of deleted and redacted submissions, kept after the rows are gone
+CREATE TABLE IF NOT EXISTS submission_deletions (
+  id BIGSERIAL PRIMARY KEY,
+  submission_id UUID NOT NULL,
+  action TEXT NOT NULL,
+  reason TEXT,
+  deleted_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Interactive sessions of keys with submission quotas, which count towards
+-- them like submissions
+CREATE TABLE IF NOT EXISTS api_key_sessions (
+  id UUID PRIMARY KEY,
+  api_key_id UUID NOT NULL REFERENCES api_keys(id),
+  started_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+  -- The latest the session can end, in case its end is never recorded
+  expires_at TIMESTAMPTZ NOT NULL,
+  finished_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS api_key_sessions_key_idx ON api_key_sessions (api_key_id, started_at DESC);
+
+-- Upgrades of databases created by earlier versions of this schema, which the
+-- statements above leave as they are
+ALTER TABLE problems ADD COLUMN IF NOT EXISTS version INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE problems ADD COLUMN IF NOT EXISTS main_solution_source TEXT;
+ALTER TABLE problems ADD COLUMN IF NOT EXISTS main_solution_language TEXT;
+
+DO $$
+BEGIN
+  IF (SELECT data_type FROM information_schema.columns
+      WHERE table_name = 'problem_tests' AND column_name = 'input') = 'text' THEN
+    ALTER TABLE problem_tests
+      ALTER COLUMN input TYPE BYTEA USING convert_to(input, 'UTF8'),
+      ALTER COLUMN answer TYPE BYTEA USING convert_to(answer, 'UTF8');
+  END IF;
+END$$;
+
+-- Code, input and output that were stored as text are kept as raw bytes
+DO $$
+DECLARE
+  col RECORD;
+BEGIN
+  FOR col IN
+    SELECT table_name, column_name FROM information_schema.columns
+    WHERE table_schema = current_schema() AND data_type = 'text'
+    AND (table_name, column_name) IN (
+      ('submissions', 'source_code'),
+      ('submissions', 'stdin'),
+      ('submissions', 'expected_output'),
+      ('submissions', 'stdout'),
+      ('submissions', 'stderr'),
+      ('submissions', 'compile_output'),
+      ('submission_verdicts', 'stdout'),
+      ('submission_verdicts', 'stderr'),
+      ('submission_verdicts', 'compile_output'),
+      ('submission_attempts', 'stdout'),
+      ('submission_attempts', 'stderr'),
+      ('submission_attempts', 'compile_output')
+    )
+  LOOP
+    EXECUTE format(
+      'ALTER TABLE %I ALTER COLUMN %I TYPE BYTEA USING convert_to(%I, ''UTF8'')',
+      col.table_name, col.column_name, col.column_name
+    );
+  END LOOP;
+END$$;
+
+-- Queue messages on the single subjects of earlier versions move to the
+-- subject of their priority and language
+UPDATE submission_outbox
+SET subject = format(
+  'submission.new.%s.%s',
+  CASE subject WHEN 'submission.rejudge' THEN 'low' ELSE 'normal' END,
+  convert_from(payload, 'UTF8')::jsonb -> 'language' ->> 'name'
+)
+WHERE sent_at IS NULL AND subject IN ('submission.new', 'submission.rejudge');
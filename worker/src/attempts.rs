@@ -0,0 +1,78 @@
+use anyhow::Result;
+use common::model::SubmissionStatus;
+use tokio_postgres::types::{Json, ToSql};
+use uuid::Uuid;
+
+use crate::AppState;
+use crate::judge::Verdict;
+
+/// Records that `worker_id` started running submission `id` in box `box_id`
+/// and returns the id of the attempt.
+pub async fn start_attempt(
+    app_state: &AppState,
+    id: Uuid,
+    worker_id: &str,
+    box_id: u32,
+) -> Result<i64> {
+    let box_id = box_id as i32;
+    let params: &[&(dyn ToSql + Sync)] = &[&id, &worker_id, &box_id, &SubmissionStatus::Processing];
+    let row = app_state
+        .db
+        .query_one(
+            "INSERT INTO submission_attempts (submission_id, worker_id, box_id, status)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id",
+            params,
+        )
+        .await?;
+    Ok(row.get("id"))
+}
+
+/// Renews the heartbeat of a running attempt, which tells the API that its
+/// worker is still alive.
+pub async fn touch_attempt(app_state: &AppState, attempt: i64) -> Result<()> {
+    app_state
+        .db
+        .execute(
+            "UPDATE submission_attempts SET heartbeat_at = now() WHERE id = $1",
+            &[&attempt],
+        )
+        .await?;
+    Ok(())
+}
+
+/// Stores the outcome of an attempt.
+pub async fn finish_attempt(app_state: &AppState, attempt: i64, verdict: &Verdict) -> Result<()> {
+    let metadata = verdict.metadata.as_ref().map(Json);
+    let compile_output = verdict.compile_output.as_deref().map(str::as_bytes);
+    let params: &[&(dyn ToSql + Sync)] = &[
+        &attempt,
+        &verdict.status,
+        &metadata,
+        &verdict.stdout,
+        &verdict.stderr,
+        &compile_output,
+        &verdict.message,
+    ];
+    app_state
+        .db
+        .execute(
+            r#"
+        UPDATE submission_attempts SET
+            status = $2,
+            metadata = $3,
+            stdout = $4,
+            stderr = $5,
+            compile_output = $6,
+            message = $7,
+            finished_at = now()
+        WHERE id = $1 AND NOT EXISTS (
+            SELECT 1 FROM submissions
+            WHERE submissions.id = submission_id AND redacted_at IS NOT NULL
+        )
+        "#,
+            params,
+        )
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,51 @@
+use anyhow::Result;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::time::interval;
+
+use crate::AppState;
+
+/// How often the test cache is swept.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Marks an entry of the test cache as used, so that eviction keeps it.
+pub fn touch(entry: &Path) {
+    let _ = std::fs::File::open(entry).and_then(|file| file.set_modified(SystemTime::now()));
+}
+
+/// Every sweep interval, removes the entries of `cache_dir` that have not
+/// been used for `cache_max_age` seconds, including files left behind by
+/// workers that stopped while caching.
+pub async fn evict_cache(app_state: Arc<AppState>) {
+    let max_age = Duration::from_secs(app_state.config.cache_max_age);
+    let mut ticks = interval(SWEEP_INTERVAL);
+    loop {
+        ticks.tick().await;
+        match evict(&app_state.config.cache_dir, max_age).await {
+            Ok(0) => {}
+            Ok(evicted) => println!("🧹 Evicted {evicted} unused cached tests"),
+            Err(err) => eprintln!("❌ Cache eviction error: {err}"),
+        }
+    }
+}
+
+async fn evict(cache_dir: &Path, max_age: Duration) -> Result<usize> {
+    let mut entries = tokio::fs::read_dir(cache_dir).await?;
+    let mut evicted = 0;
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        if metadata.modified()?.elapsed().unwrap_or_default() < max_age {
+            continue;
+        }
+        let removed = if metadata.is_dir() {
+            tokio::fs::remove_dir_all(entry.path()).await
+        } else {
+            tokio::fs::remove_file(entry.path()).await
+        };
+        if removed.is_ok() {
+            evicted += 1;
+        }
+    }
+    Ok(evicted)
+}
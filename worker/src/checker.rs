@@ -0,0 +1,71 @@
+use anyhow::{Result, anyhow};
+use common::model::Program;
+use std::path::Path;
+
+use crate::program::CompiledProgram;
+use crate::workers::JudgeStatus;
+
+/// Exit code of testlib's `quitp`, which prints the points it awards first on
+/// stdout.
+const POINTS_EXIT_CODE: i32 = 7;
+
+/// Maps the exit code of a testlib jury program to the status of the test and
+/// its share of the test's points, in `0.0..=1.0`: 0 is OK, 1 and 2 are wrong
+/// answer and presentation error, and 7 awards the points printed on `stdout`.
+/// Returns `None` for any other code, which is a failure of the program.
+pub fn testlib_verdict(code: i32, stdout: &str) -> Option<(JudgeStatus, f64)> {
+    match code {
+        0 => Some((JudgeStatus::Accepted, 1.0)),
+        1 | 2 => Some((JudgeStatus::WrongAnswer, 0.0)),
+        POINTS_EXIT_CODE => {
+            let points: f64 = stdout.split_whitespace().next()?.parse().ok()?;
+            let score = points.clamp(0.0, 1.0);
+            match score >= 1.0 {
+                true => Some((JudgeStatus::Accepted, score)),
+                false => Some((JudgeStatus::WrongAnswer, score)),
+            }
+        }
+        _ => None,
+    }
+}
+
+/// A compiled testlib checker, run as `checker input.txt output.txt answer.txt`.
+pub struct CheckerBox {
+    program: CompiledProgram,
+}
+
+impl CheckerBox {
+    pub async fn prepare(box_id: u32, checker: &Program) -> Result<Self> {
+        Ok(Self {
+            program: CompiledProgram::prepare(box_id, checker, "checker").await?,
+        })
+    }
+
+    /// Judges the contestant `output` for `input` against the jury `answer`
+    /// and returns the status and score of the test, see [`testlib_verdict`].
+    pub async fn check(
+        &self,
+        input: &Path,
+        output: &[u8],
+        answer: Option<&[u8]>,
+    ) -> Result<(JudgeStatus, f64)> {
+        let boxdir = &self.program.sandbox.boxdir;
+        tokio::fs::copy(input, boxdir.join("input.txt")).await?;
+        tokio::fs::write(boxdir.join("output.txt"), output).await?;
+        tokio::fs::write(boxdir.join("answer.txt"), answer.unwrap_or_default()).await?;
+
+        let code = self
+            .program
+            .run("input.txt output.txt answer.txt", None)
+            .await?;
+        testlib_verdict(code, &self.program.output().await).ok_or(anyhow!(
+            "checker failed with exit code {}: {}",
+            code,
+            self.program.report().await
+        ))
+    }
+
+    pub async fn cleanup(&self) -> Result<()> {
+        self.program.cleanup().await
+    }
+}
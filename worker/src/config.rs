@@ -1,3 +1,4 @@
+use common::model::SubmissionPriority;
 use config::{Config, File};
 use serde::Deserialize;
 use std::env;
@@ -7,11 +8,67 @@ use std::path::PathBuf;
 #[serde(default)]
 pub struct AppConfig {
     pub num_workers: i32,
+    /// Directory where generated test inputs are cached by content hash and
+    /// the tests of stored problems by version.
+    pub cache_dir: PathBuf,
+    /// Seconds a cached test may go unused before it is evicted.
+    pub cache_max_age: u64,
+    /// Interactive sessions this worker runs at once, on top of `num_workers`.
+    pub max_sessions: usize,
+    /// Name of this worker host in the recorded execution attempts.
+    pub worker_id: String,
+    /// Seconds between heartbeats of a running submission. The API requeues
+    /// submissions whose heartbeat stopped.
+    pub heartbeat_interval: u64,
+    pub priority_weights: PriorityWeights,
+    /// Names of the languages whose toolchains this host has, as configured
+    /// in the API. Only submissions in them are taken.
+    pub languages: Vec<String>,
+}
+
+/// How many of every `high + normal + low` pulls start at each priority.
+/// Pulls fall through to the other priorities when theirs is empty, so the
+/// weights only matter while several priorities are waiting.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(default)]
+pub struct PriorityWeights {
+    pub high: u32,
+    pub normal: u32,
+    pub low: u32,
+}
+
+impl PriorityWeights {
+    pub fn get(&self, priority: SubmissionPriority) -> u32 {
+        match priority {
+            SubmissionPriority::High => self.high,
+            SubmissionPriority::Normal => self.normal,
+            SubmissionPriority::Low => self.low,
+        }
+    }
+}
+
+impl Default for PriorityWeights {
+    fn default() -> Self {
+        Self {
+            high: 8,
+            normal: 3,
+            low: 1,
+        }
+    }
 }
 
 impl Default for AppConfig {
     fn default() -> Self {
-        Self { num_workers: -1 }
+        Self {
+            num_workers: -1,
+            cache_dir: env::temp_dir().join("executor-tests"),
+            cache_max_age: 7 * 24 * 60 * 60,
+            max_sessions: 4,
+            worker_id: env::var("HOSTNAME").unwrap_or_else(|_| "worker".to_string()),
+            heartbeat_interval: 10,
+            priority_weights: PriorityWeights::default(),
+            languages: Vec::new(),
+        }
     }
 }
 
@@ -0,0 +1,38 @@
+use common::model::{SubmissionEvent, SubmissionStage, SubmissionStatus};
+use common::nats::{NatsClient, events_subject};
+
+/// Publishes the progress of one submission on its events subject. Events are
+/// best effort: nobody may be listening, so failures to publish are ignored.
+pub struct Progress<'a> {
+    nats: &'a NatsClient,
+    subject: String,
+}
+
+impl<'a> Progress<'a> {
+    pub fn new(nats: &'a NatsClient, id: &str) -> Self {
+        Self {
+            nats,
+            subject: events_subject(id),
+        }
+    }
+
+    pub async fn stage(&self, stage: SubmissionStage) {
+        self.send(SubmissionEvent::new(stage)).await;
+    }
+
+    pub async fn running(&self, test: i32) {
+        let mut event = SubmissionEvent::new(SubmissionStage::Running);
+        event.test = Some(test);
+        self.send(event).await;
+    }
+
+    pub async fn finished(&self, status: SubmissionStatus) {
+        self.send(SubmissionEvent::from_status(status)).await;
+    }
+
+    async fn send(&self, event: SubmissionEvent) {
+        if let Ok(data) = serde_json::to_vec(&event) {
+            let _ = self.nats.notify(self.subject.clone(), data).await;
+        }
+    }
+}
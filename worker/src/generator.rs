@@ -0,0 +1,192 @@
+use anyhow::{Result, anyhow};
+use common::model::{Generator, NatsSubmission, Program};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+use crate::BoxIdManager;
+use crate::cache::touch;
+use crate::program::CompiledProgram;
+
+/// Produces the input and answer of generated tests on demand. Generators,
+/// the validator and the main solution are only compiled once a test needs
+/// them, and every validated input is cached under `cache_dir`, together with
+/// the answer of the main solution, by the hash of what produced them.
+pub struct TestInputs<'a> {
+    boxes: &'a BoxIdManager,
+    cache_dir: &'a Path,
+    generators: &'a [Generator],
+    validator: Option<&'a Program>,
+    solution: Option<&'a Program>,
+    compiled_generators: HashMap<String, CompiledProgram>,
+    compiled_validator: Option<CompiledProgram>,
+    compiled_solution: Option<CompiledProgram>,
+}
+
+/// Files of a generated test in the cache.
+pub struct GeneratedTest {
+    pub input: PathBuf,
+    /// Output of the main solution, unless the problem has none.
+    pub answer: Option<PathBuf>,
+}
+
+impl<'a> TestInputs<'a> {
+    pub fn new(boxes: &'a BoxIdManager, cache_dir: &'a Path, sub: &'a NatsSubmission) -> Self {
+        Self {
+            boxes,
+            cache_dir,
+            generators: sub.generators.as_deref().unwrap_or_default(),
+            validator: sub.validator.as_ref(),
+            solution: sub.solution.as_ref(),
+            compiled_generators: HashMap::new(),
+            compiled_validator: None,
+            compiled_solution: None,
+        }
+    }
+
+    /// Returns the test that generator `name` produces for `args`, generating,
+    /// validating and solving it first unless it is already cached.
+    pub async fn generated_test(&mut self, name: &str, args: &str) -> Result<GeneratedTest> {
+        let generator = self
+            .generators
+            .iter()
+            .find(|g| g.name == name)
+            .ok_or(anyhow!("unknown generator `{}`", name))?;
+
+        let key = cache_key(&generator.program, args, self.solution);
+        let cached = self.cache_dir.join(&key);
+        if tokio::fs::try_exists(&cached).await? {
+            touch(&cached);
+            return Ok(self.cached_test(cached));
+        }
+
+        if !self.compiled_generators.contains_key(name) {
+            let box_id = self.boxes.get_next_id().await;
+            let program = CompiledProgram::prepare(box_id, &generator.program, "generator").await?;
+            self.compiled_generators.insert(name.to_string(), program);
+        }
+
+        let program = &self.compiled_generators[name];
+        let code = program.run(args, None).await?;
+        if code != 0 {
+            return Err(anyhow!(
+                "generator `{}` failed with exit code {}: {}",
+                name,
+                code,
+                program.report().await
+            ));
+        }
+
+        // Written under a unique name and renamed once valid and solved so
+        // that concurrent workers never read a partial test.
+        let pending = self.cache_dir.join(format!("{}.{}.tmp", key, Uuid::new_v4()));
+        tokio::fs::create_dir(&pending).await?;
+        let input = pending.join("input");
+        tokio::fs::copy(&program.sandbox.stdout_file, &input).await?;
+        let produced = match self.validate(&input).await {
+            Ok(()) => self.solve(&input, &pending.join("answer")).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = produced {
+            let _ = tokio::fs::remove_dir_all(&pending).await;
+            return Err(err);
+        }
+        if tokio::fs::rename(&pending, &cached).await.is_err() {
+            // Another worker cached the same test first.
+            let _ = tokio::fs::remove_dir_all(&pending).await;
+        }
+
+        Ok(self.cached_test(cached))
+    }
+
+    fn cached_test(&self, dir: PathBuf) -> GeneratedTest {
+        GeneratedTest {
+            input: dir.join("input"),
+            answer: self.solution.map(|_| dir.join("answer")),
+        }
+    }
+
+    async fn validate(&mut self, input: &Path) -> Result<()> {
+        let Some(validator) = self.validator else {
+            return Ok(());
+        };
+
+        if self.compiled_validator.is_none() {
+            let box_id = self.boxes.get_next_id().await;
+            let program = CompiledProgram::prepare(box_id, validator, "validator").await?;
+            self.compiled_validator = Some(program);
+        }
+
+        let program = self.compiled_validator.as_ref().unwrap();
+        match program.run("", Some(input)).await? {
+            0 => Ok(()),
+            code => Err(anyhow!(
+                "validator rejected generated input with exit code {}: {}",
+                code,
+                program.report().await
+            )),
+        }
+    }
+
+    /// Writes the output of the main solution for `input` to `answer`.
+    async fn solve(&mut self, input: &Path, answer: &Path) -> Result<()> {
+        let Some(solution) = self.solution else {
+            return Ok(());
+        };
+
+        if self.compiled_solution.is_none() {
+            let box_id = self.boxes.get_next_id().await;
+            let program = CompiledProgram::prepare(box_id, solution, "main solution").await?;
+            self.compiled_solution = Some(program);
+        }
+
+        let program = self.compiled_solution.as_ref().unwrap();
+        match program.run("", Some(input)).await? {
+            0 => {
+                tokio::fs::copy(&program.sandbox.stdout_file, answer).await?;
+                Ok(())
+            }
+            code => Err(anyhow!(
+                "main solution failed on generated input with exit code {}: {}",
+                code,
+                program.report().await
+            )),
+        }
+    }
+
+    pub async fn cleanup(&self) -> Result<()> {
+        for program in self.compiled_generators.values() {
+            program.cleanup().await?;
+        }
+        if let Some(program) = &self.compiled_validator {
+            program.cleanup().await?;
+        }
+        if let Some(program) = &self.compiled_solution {
+            program.cleanup().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Hash of everything that determines a generated input and its answer.
+fn cache_key(generator: &Program, args: &str, solution: Option<&Program>) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(generator.language.name.as_bytes());
+    hasher.update([0]);
+    hasher.update(generator.source_code.as_bytes());
+    hasher.update([0]);
+    hasher.update(args.as_bytes());
+    if let Some(solution) = solution {
+        hasher.update([0]);
+        hasher.update(solution.language.name.as_bytes());
+        hasher.update([0]);
+        hasher.update(solution.source_code.as_bytes());
+    }
+
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
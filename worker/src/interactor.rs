@@ -0,0 +1,87 @@
+use anyhow::{Result, anyhow};
+use common::model::{NatsSubmission, Program};
+use std::path::Path;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+
+use crate::checker::testlib_verdict;
+use crate::isolate::Sandbox;
+use crate::program::CompiledProgram;
+use crate::workers::JudgeStatus;
+
+/// A compiled testlib interactor, run as `interactor input.txt output.txt
+/// answer.txt` with its stdin and stdout crossed with those of the submission.
+pub struct InteractorBox {
+    program: CompiledProgram,
+}
+
+impl InteractorBox {
+    pub async fn prepare(box_id: u32, interactor: &Program) -> Result<Self> {
+        Ok(Self {
+            program: CompiledProgram::prepare(box_id, interactor, "interactor").await?,
+        })
+    }
+
+    /// Runs the submission in `iso` against the interactor on `input` and the
+    /// jury `answer`, and returns the interactor's exit code once both exited.
+    /// The submission's metadata is left in `iso` as after [`Sandbox::run`].
+    pub async fn interact(
+        &self,
+        iso: &Sandbox,
+        sub: &NatsSubmission,
+        input: &Path,
+        answer: Option<&[u8]>,
+    ) -> Result<i32> {
+        let boxdir = &self.program.sandbox.boxdir;
+        tokio::fs::copy(input, boxdir.join("input.txt")).await?;
+        tokio::fs::write(boxdir.join("answer.txt"), answer.unwrap_or_default()).await?;
+        let _ = tokio::fs::remove_file(boxdir.join("output.txt")).await;
+
+        let mut interactor = self.program.spawn("input.txt output.txt answer.txt")?;
+        let mut solution = iso.spawn_crossed(sub)?;
+        let (Some(to_interactor), Some(from_interactor)) =
+            (interactor.stdin.take(), interactor.stdout.take())
+        else {
+            return Err(anyhow!("interactor is not piped"));
+        };
+        let (Some(to_solution), Some(from_solution)) =
+            (solution.stdin.take(), solution.stdout.take())
+        else {
+            return Err(anyhow!("submission is not piped"));
+        };
+
+        // Either side ends the other's input by exiting. Both are bounded by
+        // their wall time limits.
+        let (_, _, solution_status, interactor_status) = tokio::join!(
+            cross(from_solution, to_interactor),
+            cross(from_interactor, to_solution),
+            solution.wait(),
+            interactor.wait(),
+        );
+        solution_status?;
+        interactor_status?;
+
+        self.program.exit_code().await
+    }
+
+    /// Maps the exit code of the interactor like a checker's, see
+    /// [`testlib_verdict`].
+    pub async fn verdict(&self, code: i32) -> Result<(JudgeStatus, f64)> {
+        testlib_verdict(code, &self.program.output().await).ok_or(anyhow!(
+            "interactor failed with exit code {}: {}",
+            code,
+            self.program.report().await
+        ))
+    }
+
+    pub async fn cleanup(&self) -> Result<()> {
+        self.program.cleanup().await
+    }
+}
+
+/// Copies what one side writes to the other until it closes its output, then
+/// closes the other's input.
+async fn cross(mut from: impl AsyncRead + Unpin, mut to: impl AsyncWrite + Unpin) {
+    // The other side may exit without reading everything.
+    let _ = tokio::io::copy(&mut from, &mut to).await;
+    let _ = to.shutdown().await;
+}
@@ -1,10 +1,14 @@
 use anyhow::{Result, anyhow};
-use common::model::NatsSubmission;
-use std::fs::File;
-use std::io::Write;
-use std::path::PathBuf;
+use common::model::{Language, NatsSubmission};
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
 use std::process::Stdio;
-use tokio::process::Command;
+use tokio::process::{Child, Command};
+
+/// Uid of the processes in box 0; isolate's default `first_uid`.
+const ISOLATE_FIRST_UID: u32 = 60000;
 
 pub struct Sandbox {
     pub box_id: u32,
@@ -12,7 +16,6 @@ pub struct Sandbox {
     pub boxdir: PathBuf,
     pub tmpdir: PathBuf,
     pub metadata_file: PathBuf,
-    pub source_file: PathBuf,
     pub stdin_file: PathBuf,
     pub stdout_file: PathBuf,
     pub stderr_file: PathBuf,
@@ -20,6 +23,7 @@ pub struct Sandbox {
 
 use std::collections::HashMap;
 use tokio::fs;
+use tokio::io::AsyncReadExt;
 
 impl Sandbox {
     pub fn new(box_id: u32) -> Result<Self> {
@@ -44,20 +48,21 @@ impl Sandbox {
             workdir: workdir.clone(),
             tmpdir: tmpdir.clone(),
             metadata_file: workdir.join("metadata.txt"),
-            source_file: boxdir.join("source"),
             stdin_file: workdir.join("stdin.txt"),
             stdout_file: workdir.join("stdout.txt"),
             stderr_file: workdir.join("stderr.txt"),
         })
     }
 
-    pub async fn compile(&self, submission: &NatsSubmission) -> Result<bool> {
-        if let Some(compile_cmd) = &submission.language.compile_cmd {
+    pub async fn compile(
+        &self,
+        language: &Language,
+        compiler_options: Option<&str>,
+    ) -> Result<CompileOutput> {
+        if let Some(compile_cmd) = &language.compile_cmd {
             let compile_script = self.boxdir.join("compile.sh");
             let mut file = File::create(&compile_script)?;
-            let sanitized = submission
-                .compiler_options
-                .clone()
+            let sanitized = compiler_options
                 .unwrap_or_default()
                 .replace(['$', '&', ';', '<', '>', '|', '`'], "");
             writeln!(file, "{}", compile_cmd.replace("%s", &sanitized))?;
@@ -94,16 +99,86 @@ impl Sandbox {
                 .output()
                 .await?;
 
-            let success = output.status.success();
-            Ok(success)
+            let output_text = String::from_utf8_lossy(&output.stdout).to_string();
+            Ok(CompileOutput {
+                success: output.status.success(),
+                output: if output_text.trim().is_empty() {
+                    None
+                } else {
+                    Some(output_text)
+                },
+            })
         } else {
-            Ok(true)
+            Ok(CompileOutput {
+                success: true,
+                output: None,
+            })
         }
     }
 
     pub async fn run(&self, submission: &NatsSubmission) -> Result<()> {
-        let run_script = self.boxdir.join("run.sh");
-        let mut file = File::create(&run_script)?;
+        let mut command = self.limited_command(submission, &["--stderr-to-stdout"])?;
+
+        command
+            .stdin(Stdio::from(File::open(&self.stdin_file)?))
+            .stdout(Stdio::from(File::create(&self.stdout_file)?))
+            .stderr(Stdio::from(File::create(&self.stderr_file)?))
+            .current_dir(&self.boxdir);
+
+        command.kill_on_drop(true);
+
+        let _status = command.output().await?;
+
+        Ok(())
+    }
+
+    /// Starts the submission under the same limits as [`Sandbox::run`], with
+    /// stdin, stdout and stderr piped to the caller for an interactive session.
+    pub fn spawn_interactive(&self, submission: &NatsSubmission) -> Result<Child> {
+        let mut command = self.limited_command(submission, &[])?;
+
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .current_dir(&self.boxdir)
+            .kill_on_drop(true);
+
+        Ok(command.spawn()?)
+    }
+
+    /// Starts the submission under the same limits as [`Sandbox::run`], with
+    /// stdin and stdout piped to the caller to be crossed with those of an
+    /// interactor. Its stderr lands in `stderr_file`.
+    pub fn spawn_crossed(&self, submission: &NatsSubmission) -> Result<Child> {
+        let mut command = self.limited_command(submission, &[])?;
+
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::from(File::create(&self.stderr_file)?))
+            .current_dir(&self.boxdir)
+            .kill_on_drop(true);
+
+        Ok(command.spawn()?)
+    }
+
+    /// Kills every process running inside this box.
+    pub async fn kill(&self) -> Result<()> {
+        let uid = ISOLATE_FIRST_UID + self.box_id;
+        Command::new("sudo")
+            .args(["pkill", "-KILL", "-U", &uid.to_string()])
+            .status()
+            .await?;
+        Ok(())
+    }
+
+    /// Writes `run.sh` and builds the isolate command that runs it with the
+    /// submission's limits. `flags` are extra isolate options.
+    fn limited_command(&self, submission: &NatsSubmission, flags: &[&str]) -> Result<Command> {
+        // The submission may have run in this box before and left anything
+        // in place of the script.
+        let mut file = self.create_box_file("run.sh")?;
         let args = submission
             .command_line_arguments
             .clone()
@@ -111,6 +186,101 @@ impl Sandbox {
             .replace(['$', '&', ';', '<', '>', '|', '`'], "");
         writeln!(file, "{} {}", submission.language.run_cmd, args)?;
 
+        let mut command = Command::new("sudo");
+        command
+            .args([
+                "isolate",
+                "--cg",
+                "--silent",
+                "-b",
+                &self.box_id.to_string(),
+                "-M",
+                self.metadata_file.to_str().unwrap(),
+            ])
+            .args(flags)
+            .args([
+                "-t",
+                &submission.cpu_time_limit.unwrap_or(2.0).to_string(),
+                "-x",
+                &submission.cpu_extra_time.unwrap_or(1.0).to_string(),
+                "-w",
+                &submission.wall_time_limit.unwrap_or(4.0).to_string(),
+                "-k",
+                &submission.stack_limit.unwrap_or(67108864).to_string(),
+                &format!(
+                    "-p{}",
+                    submission.max_processes_and_or_threads.unwrap_or(50)
+                ),
+                "-m",
+                &submission.memory_limit.unwrap_or(262144.0).to_string(),
+                "-f",
+                &submission.max_file_size.unwrap_or(1024).to_string(),
+                "--run",
+                "--",
+                "/bin/bash",
+                "run.sh",
+            ]);
+
+        Ok(command)
+    }
+
+    /// Runs a jury program (checker, validator, generator) as `<run_cmd> <args>`
+    /// with fixed, generous limits. Its stdout and stderr land in the usual
+    /// output files of this box.
+    pub async fn run_tool(
+        &self,
+        language: &Language,
+        args: &str,
+        stdin: Option<&Path>,
+    ) -> Result<()> {
+        let mut command = self.tool_command(language, args)?;
+
+        let stdin = match stdin {
+            Some(path) => Stdio::from(File::open(path)?),
+            None => Stdio::null(),
+        };
+
+        command
+            .stdin(stdin)
+            .stdout(Stdio::from(File::create(&self.stdout_file)?))
+            .stderr(Stdio::from(File::create(&self.stderr_file)?))
+            .current_dir(&self.boxdir);
+
+        command.kill_on_drop(true);
+
+        let _status = command.output().await?;
+
+        Ok(())
+    }
+
+    /// Starts a jury program like [`Sandbox::run_tool`], with stdin and stdout
+    /// piped to the caller, e.g. an interactor talking to a submission. Its
+    /// stderr lands in `stderr_file`.
+    pub fn spawn_tool(&self, language: &Language, args: &str) -> Result<Child> {
+        let mut command = self.tool_command(language, args)?;
+
+        command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::from(File::create(&self.stderr_file)?))
+            .current_dir(&self.boxdir)
+            .kill_on_drop(true);
+
+        Ok(command.spawn()?)
+    }
+
+    /// Writes `run.sh` and builds the isolate command that runs a jury program
+    /// with the limits of [`Sandbox::run_tool`].
+    fn tool_command(&self, language: &Language, args: &str) -> Result<Command> {
+        let run_script = self.boxdir.join("run.sh");
+        let mut file = File::create(&run_script)?;
+        writeln!(
+            file,
+            "{} {}",
+            language.run_cmd,
+            args.replace(['$', '&', ';', '<', '>', '|', '`'], "")
+        )?;
+
         let mut command = Command::new("sudo");
         command.args([
             "isolate",
@@ -120,40 +290,21 @@ impl Sandbox {
             &self.box_id.to_string(),
             "-M",
             self.metadata_file.to_str().unwrap(),
-            "--stderr-to-stdout",
             "-t",
-            &submission.cpu_time_limit.unwrap_or(2.0).to_string(),
-            "-x",
-            &submission.cpu_extra_time.unwrap_or(1.0).to_string(),
+            "10",
             "-w",
-            &submission.wall_time_limit.unwrap_or(4.0).to_string(),
-            "-k",
-            &submission.stack_limit.unwrap_or(67108864).to_string(),
-            &format!(
-                "-p{}",
-                submission.max_processes_and_or_threads.unwrap_or(50)
-            ),
+            "20",
             "-m",
-            &submission.memory_limit.unwrap_or(262144.0).to_string(),
+            "524288",
             "-f",
-            &submission.max_file_size.unwrap_or(1024).to_string(),
+            "262144",
             "--run",
             "--",
             "/bin/bash",
             "run.sh",
         ]);
 
-        command
-            .stdin(Stdio::from(File::open(&self.stdin_file)?))
-            .stdout(Stdio::from(File::create(&self.stdout_file)?))
-            .stderr(Stdio::from(File::create(&self.stderr_file)?))
-            .current_dir(&self.boxdir);
-
-        command.kill_on_drop(true);
-
-        let _status = command.output().await?;
-
-        Ok(())
+        Ok(command)
     }
 
     pub async fn read_metadata(&self) -> Result<HashMap<String, String>> {
@@ -169,12 +320,58 @@ impl Sandbox {
         Ok(map)
     }
 
-    pub async fn read_output(&self) -> Result<ProgramOutput> {
-        let stdout = tokio::fs::read_to_string(&self.stdout_file).await.ok();
-        let stderr = tokio::fs::read_to_string(&self.stderr_file).await.ok();
+    /// Creates the file `name` in `boxdir` for writing, first removing
+    /// whatever a program run in the box left there. A symlink planted in its
+    /// place is removed rather than followed.
+    pub fn create_box_file(&self, name: &str) -> Result<File> {
+        let path = self.boxdir.join(name);
+        match std::fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        // Fails instead of following anything that took its place meanwhile.
+        Ok(OpenOptions::new().write(true).create_new(true).open(path)?)
+    }
 
-        let stdout = stdout.and_then(|s| if s.trim().is_empty() { None } else { Some(s) });
-        let stderr = stderr.and_then(|s| if s.trim().is_empty() { None } else { Some(s) });
+    /// Reads the program's answer: the named file in `boxdir` for file-based
+    /// I/O, `stdout_file` otherwise. The box is under the program's control,
+    /// so a box file only counts if it is a regular file of its own, never a
+    /// symlink or hard link through which the worker would read host files.
+    /// Anything else, like a missing file, is an empty answer.
+    pub async fn read_answer(&self, output_file: Option<&str>) -> Result<Vec<u8>> {
+        let Some(name) = output_file else {
+            return Ok(fs::read(&self.stdout_file).await.unwrap_or_default());
+        };
+        let path = self.boxdir.join(name);
+        let metadata = match fs::symlink_metadata(&path).await {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err.into()),
+        };
+        if !metadata.is_file() || metadata.nlink() != 1 {
+            return Ok(Vec::new());
+        }
+        let mut file = fs::File::open(&path).await?;
+        // The file that was opened must be the one that was checked.
+        let opened = file.metadata().await?;
+        if (opened.dev(), opened.ino()) != (metadata.dev(), metadata.ino()) {
+            return Ok(Vec::new());
+        }
+        let mut answer = Vec::new();
+        file.read_to_end(&mut answer).await?;
+        Ok(answer)
+    }
+
+    /// The program's `answer` along with its stderr. Both are kept as raw
+    /// bytes since programs may write anything.
+    pub async fn read_output(&self, answer: &[u8]) -> Result<ProgramOutput> {
+        let stdout = Some(answer.to_vec());
+        let stderr = tokio::fs::read(&self.stderr_file).await.ok();
+
+        let is_blank = |s: &Vec<u8>| s.iter().all(u8::is_ascii_whitespace);
+        let stdout = stdout.filter(|s| !is_blank(s));
+        let stderr = stderr.filter(|s| !is_blank(s));
 
         Ok(ProgramOutput { stdout, stderr })
     }
@@ -204,8 +401,23 @@ impl Sandbox {
     }
 }
 
+#[derive(Debug)]
+pub struct CompileOutput {
+    pub success: bool,
+    pub output: Option<String>,
+}
+
 #[derive(Debug)]
 pub struct ProgramOutput {
-    pub stdout: Option<String>,
-    pub stderr: Option<String>,
+    pub stdout: Option<Vec<u8>>,
+    pub stderr: Option<Vec<u8>>,
+}
+
+impl ProgramOutput {
+    /// The answer as text for comparing, with invalid UTF-8 replaced.
+    pub fn stdout_text(&self) -> Option<String> {
+        self.stdout
+            .as_deref()
+            .map(|s| String::from_utf8_lossy(s).into_owned())
+    }
 }
@@ -0,0 +1,456 @@
+use anyhow::{Result, anyhow, bail};
+use common::model::{
+    GroupResult, NatsSubmission, ScoringPolicy, StressResult, SubmissionStage, SubmissionStatus,
+    TestGroup,
+};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::AppState;
+use crate::checker::CheckerBox;
+use crate::events::Progress;
+use crate::generator::TestInputs;
+use crate::interactor::InteractorBox;
+use crate::isolate::{ProgramOutput, Sandbox};
+use crate::problem::{StoredGroup, StoredTest, load_tests};
+use crate::stress::stress_test;
+use crate::template::splice;
+use crate::workers::{JudgeStatus, determine_status, write_source, write_stdin};
+
+/// Final result of judging a submission against all of its tests.
+#[derive(Debug)]
+pub struct Verdict {
+    pub status: SubmissionStatus,
+    pub stdout: Option<Vec<u8>>,
+    pub stderr: Option<Vec<u8>>,
+    pub compile_output: Option<String>,
+    pub message: Option<String>,
+    pub exit_code: Option<i32>,
+    pub exit_signal: Option<i32>,
+    pub time: Option<f64>,
+    pub wall_time: Option<f64>,
+    pub memory: Option<f64>,
+    pub score: Option<f64>,
+    pub max_score: Option<f64>,
+    pub group_results: Option<Vec<GroupResult>>,
+    pub stress_result: Option<StressResult>,
+    /// Isolate metadata of the run whose outputs are kept.
+    pub metadata: Option<HashMap<String, String>>,
+}
+
+impl Verdict {
+    pub fn new(status: SubmissionStatus) -> Self {
+        Self {
+            status,
+            stdout: None,
+            stderr: None,
+            compile_output: None,
+            message: None,
+            exit_code: None,
+            exit_signal: None,
+            time: None,
+            wall_time: None,
+            memory: None,
+            score: None,
+            max_score: None,
+            group_results: None,
+            stress_result: None,
+            metadata: None,
+        }
+    }
+
+    pub fn internal_error(err: anyhow::Error) -> Self {
+        let mut verdict = Self::new(SubmissionStatus::InternalError);
+        verdict.message = Some(err.to_string());
+        verdict
+    }
+
+    /// Folds a single test run into the verdict. Time and memory keep the
+    /// maximum over all tests, outputs are kept from the first failing test.
+    pub fn record(&mut self, run: TestRun) {
+        self.time = max_metric(self.time, &run.metadata, "time");
+        self.wall_time = max_metric(self.wall_time, &run.metadata, "time-wall");
+        self.memory = max_metric(self.memory, &run.metadata, "cg-mem");
+
+        if self.status == SubmissionStatus::Accepted {
+            self.status = run.status;
+            self.stdout = run.output.stdout;
+            self.stderr = run.output.stderr;
+            self.message = run.metadata.get("message").cloned();
+            self.exit_code = run.metadata.get("exitcode").and_then(|s| s.parse().ok());
+            self.exit_signal = run.metadata.get("exitsig").and_then(|s| s.parse().ok());
+            self.metadata = Some(run.metadata);
+        }
+    }
+}
+
+fn max_metric(current: Option<f64>, meta: &HashMap<String, String>, key: &str) -> Option<f64> {
+    let value = meta.get(key).and_then(|s| s.parse::<f64>().ok());
+    match (current, value) {
+        (Some(a), Some(b)) => Some(a.max(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// A test as the worker runs it.
+pub enum Test<'a> {
+    /// Input and answer sent with the submission or produced by a stress run.
+    Inline {
+        input: &'a [u8],
+        answer: Option<&'a [u8]>,
+    },
+    /// A test of a stored problem, read from the test cache.
+    Stored(&'a StoredTest),
+}
+
+/// A test group with its tests as they are run.
+struct Group<'a> {
+    name: &'a str,
+    points: f64,
+    policy: ScoringPolicy,
+    tests: Vec<Test<'a>>,
+}
+
+impl<'a> Group<'a> {
+    fn inline(group: &'a TestGroup) -> Self {
+        Self {
+            name: &group.name,
+            points: group.points,
+            policy: group.policy,
+            tests: group
+                .tests
+                .iter()
+                .map(|test| Test::Inline {
+                    input: test.stdin.as_deref().unwrap_or_default().as_bytes(),
+                    answer: test.expected_output.as_deref().map(str::as_bytes),
+                })
+                .collect(),
+        }
+    }
+
+    fn stored(group: &'a StoredGroup) -> Self {
+        Self {
+            name: &group.name,
+            points: group.points,
+            policy: group.policy,
+            tests: group.tests.iter().map(Test::Stored).collect(),
+        }
+    }
+}
+
+pub struct TestRun {
+    pub status: SubmissionStatus,
+    /// Share of the test's points earned, in `0.0..=1.0`.
+    pub score: f64,
+    pub output: ProgramOutput,
+    pub metadata: HashMap<String, String>,
+}
+
+/// Jury-side programs shared by all tests of a submission, along with the
+/// progress reporting of those tests.
+pub struct Jury<'a> {
+    checker: Option<CheckerBox>,
+    interactor: Option<InteractorBox>,
+    inputs: TestInputs<'a>,
+    progress: &'a Progress<'a>,
+    tests_run: i32,
+    /// Set once the submission is cancelled, so that no further test starts.
+    cancelled: &'a AtomicBool,
+}
+
+impl Jury<'_> {
+    async fn cleanup(&self) -> Result<()> {
+        if let Some(checker) = &self.checker {
+            checker.cleanup().await?;
+        }
+        if let Some(interactor) = &self.interactor {
+            interactor.cleanup().await?;
+        }
+        self.inputs.cleanup().await
+    }
+}
+
+pub async fn run_test(
+    iso: &Sandbox,
+    sub: &NatsSubmission,
+    jury: &mut Jury<'_>,
+    test: &Test<'_>,
+) -> Result<TestRun> {
+    if jury.cancelled.load(Ordering::Relaxed) {
+        bail!("submission was cancelled");
+    }
+    jury.tests_run += 1;
+    jury.progress.running(jury.tests_run).await;
+
+    let expected: Option<Cow<[u8]>> = match test {
+        Test::Inline { input, answer } => {
+            write_stdin(&iso.workdir, input).await?;
+            answer.map(Cow::Borrowed)
+        }
+        Test::Stored(test) => {
+            let (input, answer) = match (&test.generator, &test.input) {
+                (Some(generator), _) => {
+                    let args = test.generator_args.as_deref().unwrap_or_default();
+                    let generated = jury.inputs.generated_test(generator, args).await?;
+                    (generated.input, generated.answer.or(test.answer.clone()))
+                }
+                (None, Some(input)) => (input.clone(), test.answer.clone()),
+                (None, None) => return Err(anyhow!("test has neither input nor generator")),
+            };
+            tokio::fs::copy(input, &iso.stdin_file).await?;
+            match answer {
+                Some(answer) => Some(Cow::Owned(tokio::fs::read(answer).await?)),
+                None => None,
+            }
+        }
+    };
+    // File-based problems get the same input as a file in the box and have
+    // their answer read back from the output file instead of stdout.
+    if let Some(name) = &sub.input_file {
+        let mut input = std::fs::File::open(&iso.stdin_file)?;
+        std::io::copy(&mut input, &mut iso.create_box_file(name)?)?;
+    }
+    if let Some(name) = &sub.output_file {
+        let _ = tokio::fs::remove_file(iso.boxdir.join(name)).await;
+    }
+
+    let expected = expected.as_deref();
+    let (output, metadata, checked) = match &jury.interactor {
+        Some(interactor) => {
+            let code = interactor
+                .interact(iso, sub, &iso.stdin_file, expected)
+                .await?;
+            let metadata = iso.read_metadata().await?;
+            // Its output went to the interactor, only stderr is left.
+            let output = iso.read_output(&[]).await?;
+            let checked = match metadata.get("status") {
+                None => Some(interactor.verdict(code).await?),
+                Some(_) => None,
+            };
+            (output, metadata, checked)
+        }
+        None => {
+            iso.run(sub).await?;
+            let metadata = iso.read_metadata().await?;
+            let answer = iso.read_answer(sub.output_file.as_deref()).await?;
+            let output = iso.read_output(&answer).await?;
+            let checked = match (&jury.checker, metadata.get("status")) {
+                (Some(checker), None) => {
+                    Some(checker.check(&iso.stdin_file, &answer, expected).await?)
+                }
+                _ => None,
+            };
+            (output, metadata, checked)
+        }
+    };
+    let (status, score) = match checked {
+        Some(checked) => checked,
+        None => {
+            let status = determine_status(
+                sub.comparator.unwrap_or_default(),
+                expected.map(String::from_utf8_lossy).as_deref(),
+                &output,
+                &metadata,
+            );
+            let score = match status {
+                JudgeStatus::Accepted => 1.0,
+                _ => 0.0,
+            };
+            (status, score)
+        }
+    };
+
+    Ok(TestRun {
+        status: status.into(),
+        score,
+        output,
+        metadata,
+    })
+}
+
+/// Compiles the submission and runs it against its tests, those of its stored
+/// problem or its own `test_groups`. Submissions without either are judged
+/// against their own `stdin` and `expected_output`.
+pub async fn judge_submission(
+    app_state: &AppState,
+    iso: &Sandbox,
+    sub: &NatsSubmission,
+    progress: &Progress<'_>,
+    cancelled: &AtomicBool,
+) -> Result<Verdict> {
+    let spliced = sub
+        .template
+        .as_ref()
+        .map(|template| splice(template, &sub.source_code.to_text()));
+    let source_code = spliced
+        .as_ref()
+        .map_or(&*sub.source_code, |spliced| spliced.source_code.as_bytes());
+    write_source(&iso.boxdir, source_code, &sub.language).await?;
+
+    let stored = match &sub.problem {
+        Some(problem) => {
+            Some(load_tests(&app_state.db, &app_state.config.cache_dir, problem).await?)
+        }
+        None => None,
+    };
+    let groups: Option<Vec<Group>> = match (&stored, &sub.test_groups) {
+        (Some(stored), _) => Some(stored.iter().map(Group::stored).collect()),
+        (None, Some(groups)) => Some(groups.iter().map(Group::inline).collect()),
+        (None, None) => None,
+    };
+
+    progress.stage(SubmissionStage::Compiling).await;
+    let mut compiled = iso
+        .compile(&sub.language, sub.compiler_options.as_deref())
+        .await?;
+    if let (Some(spliced), Some(output)) = (&spliced, &compiled.output) {
+        compiled.output = Some(spliced.remap_lines(output, &sub.language.source_file));
+    }
+    if !compiled.success {
+        let mut verdict = Verdict::new(SubmissionStatus::CompilationError);
+        verdict.compile_output = compiled.output;
+        if let Some(groups) = &groups {
+            verdict.score = Some(0.0);
+            verdict.max_score = Some(groups.iter().map(|g| g.points).sum());
+        }
+        return Ok(verdict);
+    }
+
+    let mut verdict = Verdict::new(SubmissionStatus::Accepted);
+    verdict.compile_output = compiled.output;
+
+    let checker = match &sub.checker {
+        Some(checker) => {
+            let box_id = app_state.box_counter.get_next_id().await;
+            Some(CheckerBox::prepare(box_id, checker).await?)
+        }
+        None => None,
+    };
+    let interactor = match &sub.interactor {
+        Some(interactor) => {
+            let box_id = app_state.box_counter.get_next_id().await;
+            match InteractorBox::prepare(box_id, interactor).await {
+                Ok(interactor) => Some(interactor),
+                Err(err) => {
+                    if let Some(checker) = &checker {
+                        let _ = checker.cleanup().await;
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        None => None,
+    };
+    let mut jury = Jury {
+        checker,
+        interactor,
+        inputs: TestInputs::new(&app_state.box_counter, &app_state.config.cache_dir, sub),
+        progress,
+        tests_run: 0,
+        cancelled,
+    };
+
+    let judged = match (&sub.stress, &groups) {
+        (Some(stress), _) => {
+            stress_test(app_state, iso, sub, stress, &mut jury, &mut verdict).await
+        }
+        (None, Some(groups)) => judge_groups(iso, sub, &mut jury, groups, &mut verdict).await,
+        (None, None) => {
+            let test = Test::Inline {
+                input: sub.stdin.as_deref().unwrap_or_default(),
+                answer: sub.expected_output.as_deref(),
+            };
+            run_test(iso, sub, &mut jury, &test)
+                .await
+                .map(|run| verdict.record(run))
+        }
+    };
+
+    jury.cleanup().await?;
+    judged?;
+
+    Ok(verdict)
+}
+
+async fn judge_groups(
+    iso: &Sandbox,
+    sub: &NatsSubmission,
+    jury: &mut Jury<'_>,
+    groups: &[Group<'_>],
+    verdict: &mut Verdict,
+) -> Result<()> {
+    let mut results = Vec::with_capacity(groups.len());
+
+    for group in groups {
+        let mut status = SubmissionStatus::Accepted;
+        let mut test_scores = Vec::with_capacity(group.tests.len());
+
+        for test in &group.tests {
+            let run = run_test(iso, sub, jury, test).await?;
+            let passed = run.status == SubmissionStatus::Accepted;
+            if !passed && status == SubmissionStatus::Accepted {
+                status = run.status.clone();
+            }
+            let score = run.score;
+            test_scores.push(score);
+            verdict.record(run);
+
+            // Nothing left to earn in this group once a test has failed, or
+            // scored nothing under `min`.
+            let exhausted = match group.policy {
+                ScoringPolicy::AllOrNothing => !passed,
+                ScoringPolicy::Min => score <= 0.0,
+                ScoringPolicy::Sum => false,
+            };
+            if exhausted {
+                break;
+            }
+        }
+
+        results.push(GroupResult {
+            name: group.name.to_string(),
+            status,
+            score: group_score(group.policy, group.points, &test_scores, group.tests.len()),
+            max_score: group.points,
+            passed_tests: test_scores.iter().filter(|s| **s >= 1.0).count() as i32,
+            total_tests: group.tests.len() as i32,
+        });
+    }
+
+    verdict.score = Some(results.iter().map(|r| r.score).sum());
+    verdict.max_score = Some(results.iter().map(|r| r.max_score).sum());
+    verdict.group_results = Some(results);
+    Ok(())
+}
+
+/// Scores a group from the per-test scores (each in `0.0..=1.0`) of the tests
+/// that were run. Tests skipped after an early failure count as zero.
+pub fn group_score(
+    policy: ScoringPolicy,
+    points: f64,
+    test_scores: &[f64],
+    total_tests: usize,
+) -> f64 {
+    if total_tests == 0 {
+        return 0.0;
+    }
+
+    match policy {
+        ScoringPolicy::AllOrNothing => {
+            if test_scores.len() == total_tests && test_scores.iter().all(|s| *s >= 1.0) {
+                points
+            } else {
+                0.0
+            }
+        }
+        ScoringPolicy::Min => {
+            if test_scores.len() < total_tests {
+                0.0
+            } else {
+                points * test_scores.iter().copied().fold(1.0, f64::min)
+            }
+        }
+        ScoringPolicy::Sum => points * test_scores.iter().sum::<f64>() / total_tests as f64,
+    }
+}
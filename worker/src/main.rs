@@ -1,14 +1,37 @@
 use std::sync::Arc;
 
-use common::nats::{NatsClient, NatsConsumer};
+use common::{
+    db::Db,
+    nats::{NatsClient, is_subject_token},
+};
 use tokio::sync::Mutex;
 
-use crate::{config::load_config, workers::spawn_workers};
+use crate::{
+    cache::evict_cache,
+    config::{AppConfig, load_config},
+    queues::{PriorityQueues, advertise_languages, drain_legacy_queues},
+    session::serve_sessions,
+    workers::spawn_workers,
+};
 
+mod attempts;
+mod cache;
+mod checker;
 mod config;
+mod events;
+mod generator;
+mod interactor;
 mod isolate;
+mod judge;
+mod problem;
+mod program;
+mod queues;
+mod session;
+mod stress;
+mod template;
 mod workers;
 
+#[derive(Default)]
 pub struct BoxIdManager {
     next: Mutex<u32>,
 }
@@ -30,7 +53,10 @@ impl BoxIdManager {
 
 struct AppState {
     box_counter: BoxIdManager,
-    queue:NatsConsumer
+    queues: PriorityQueues,
+    nats: NatsClient,
+    db: Db,
+    config: AppConfig,
 }
 
 impl AppState {}
@@ -38,20 +64,37 @@ impl AppState {}
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config = load_config();
+    std::fs::create_dir_all(&config.cache_dir)?;
+    let database_url = std::env::var("DATABASE_URL").expect("DATABASE_URL not found");
+    if config.languages.is_empty() {
+        return Err("list the languages this worker runs in `languages`".into());
+    }
+    if let Some(language) = config.languages.iter().find(|l| !is_subject_token(l)) {
+        return Err(format!("language `{}` is not a valid NATS subject token", language).into());
+    }
     let client = NatsClient::new("localhost").await?;
-    let queue = NatsConsumer::new(
-        &client,
-        "SUBMISSIONS",
-        "submission.new",
-        "submissions",
-    )
-    .await?;
+    let queues = PriorityQueues::new(&client, &config.languages, &config.priority_weights).await?;
 
     let app_state = Arc::new(AppState {
         box_counter: BoxIdManager::new(),
-        queue
+        queues,
+        nats: client,
+        db: Db::init(&database_url).await.expect("couldnot init db"),
+        config,
+    });
+
+    tokio::spawn(advertise_languages(app_state.clone()));
+    tokio::spawn(drain_legacy_queues(app_state.nats.clone()));
+    tokio::spawn(evict_cache(app_state.clone()));
+
+    let session_state = app_state.clone();
+    tokio::spawn(async move {
+        if let Err(err) = serve_sessions(session_state).await {
+            eprintln!("❌ Session server error: {err}");
+        }
     });
 
-    spawn_workers(app_state, config.num_workers as usize).await?;
+    let num_workers = app_state.config.num_workers as usize;
+    spawn_workers(app_state, num_workers).await?;
     Ok(())
 }
@@ -0,0 +1,155 @@
+use anyhow::{Result, anyhow};
+use common::{
+    db::Db,
+    model::{ProblemVersion, ScoringPolicy},
+};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+use crate::cache::touch;
+
+/// Name of the file in a cached problem directory that lists its groups.
+const INDEX_FILE: &str = "tests.json";
+
+/// A test group of a stored problem as cached on this host.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredGroup {
+    pub name: String,
+    pub points: f64,
+    pub policy: ScoringPolicy,
+    pub tests: Vec<StoredTest>,
+}
+
+/// A test of a stored problem. `input` and `answer` are files in the cache,
+/// relative to the problem directory until loaded.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct StoredTest {
+    pub input: Option<PathBuf>,
+    pub answer: Option<PathBuf>,
+    pub generator: Option<String>,
+    pub generator_args: Option<String>,
+}
+
+/// Returns the test groups of `problem`, fetching them from the database into
+/// `cache_dir` the first time a version is needed on this host. Should the
+/// tests have changed since the submission was queued, it is judged against
+/// the current ones, which are cached under their own version.
+pub async fn load_tests(
+    db: &Db,
+    cache_dir: &Path,
+    problem: &ProblemVersion,
+) -> Result<Vec<StoredGroup>> {
+    let id = Uuid::parse_str(&problem.id)?;
+    let dir = problem_dir(cache_dir, id, problem.version);
+    if let Some(groups) = read_cached(&dir).await? {
+        return Ok(groups);
+    }
+
+    // A single statement, so that the version matches the tests.
+    let rows = db
+        .query(
+            r#"
+        SELECT
+            p.version,
+            g.name,
+            g.points,
+            g.policy,
+            t.position,
+            t.input,
+            t.answer,
+            t.generator,
+            t.generator_args
+        FROM problems p
+        JOIN problem_test_groups g ON g.problem_id = p.id
+        LEFT JOIN problem_tests t ON t.problem_id = p.id AND t.group_name = g.name
+        WHERE p.id = $1
+        ORDER BY g.position, t.position
+        "#,
+            &[&id],
+        )
+        .await?;
+    let Some(first) = rows.first() else {
+        return Err(anyhow!("problem {} has no tests", problem.id));
+    };
+    let version: i32 = first.get("version");
+    let dir = problem_dir(cache_dir, id, version);
+    if let Some(groups) = read_cached(&dir).await? {
+        return Ok(groups);
+    }
+
+    // Written under a unique name and renamed once complete so that
+    // concurrent workers never read a partial directory.
+    let pending = cache_dir.join(format!("{}.{}.{}.tmp", id, version, Uuid::new_v4()));
+    tokio::fs::create_dir_all(&pending).await?;
+    let mut groups: Vec<StoredGroup> = Vec::new();
+    for row in &rows {
+        let name: String = row.get("name");
+        if groups.last().is_none_or(|group| group.name != name) {
+            groups.push(StoredGroup {
+                name,
+                points: row.get("points"),
+                policy: row
+                    .get::<_, String>("policy")
+                    .parse()
+                    .unwrap_or(ScoringPolicy::AllOrNothing),
+                tests: Vec::new(),
+            });
+        }
+        let Some(position) = row.get::<_, Option<i32>>("position") else {
+            continue;
+        };
+        let test = StoredTest {
+            input: write_test_file(&pending, format!("{}.in", position), row.get("input")).await?,
+            answer: write_test_file(&pending, format!("{}.ans", position), row.get("answer"))
+                .await?,
+            generator: row.get("generator"),
+            generator_args: row.get("generator_args"),
+        };
+        groups.last_mut().unwrap().tests.push(test);
+    }
+    tokio::fs::write(pending.join(INDEX_FILE), serde_json::to_vec(&groups)?).await?;
+
+    if tokio::fs::rename(&pending, &dir).await.is_err() {
+        // Another worker cached the same version first.
+        let _ = tokio::fs::remove_dir_all(&pending).await;
+    }
+    read_cached(&dir).await?.ok_or(anyhow!(
+        "could not cache the tests of problem {}",
+        problem.id
+    ))
+}
+
+fn problem_dir(cache_dir: &Path, id: Uuid, version: i32) -> PathBuf {
+    cache_dir.join(format!("{}.{}", id, version))
+}
+
+/// Writes `data`, if any, to `file` in `dir` and returns the file name.
+async fn write_test_file(
+    dir: &Path,
+    file: String,
+    data: Option<Vec<u8>>,
+) -> Result<Option<PathBuf>> {
+    let Some(data) = data else {
+        return Ok(None);
+    };
+    tokio::fs::write(dir.join(&file), data).await?;
+    Ok(Some(PathBuf::from(file)))
+}
+
+/// Reads the groups of a cached problem directory, with the paths of their
+/// files made absolute, or `None` if the directory is not cached.
+async fn read_cached(dir: &Path) -> Result<Option<Vec<StoredGroup>>> {
+    let index = match tokio::fs::read(dir.join(INDEX_FILE)).await {
+        Ok(index) => index,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    touch(dir);
+    let mut groups: Vec<StoredGroup> = serde_json::from_slice(&index)?;
+    for test in groups.iter_mut().flat_map(|group| &mut group.tests) {
+        test.input = test.input.as_ref().map(|file| dir.join(file));
+        test.answer = test.answer.as_ref().map(|file| dir.join(file));
+    }
+    Ok(Some(groups))
+}
@@ -0,0 +1,94 @@
+use anyhow::{Result, anyhow};
+use common::model::Program;
+use std::collections::HashMap;
+use std::path::Path;
+use tokio::process::Child;
+
+use crate::isolate::Sandbox;
+use crate::workers::write_source;
+
+/// A jury program compiled once into its own isolate box and reused for the
+/// lifetime of a submission.
+pub struct CompiledProgram {
+    pub sandbox: Sandbox,
+    program: Program,
+}
+
+impl CompiledProgram {
+    /// Compiles `program`; `role` names it in error messages.
+    pub async fn prepare(box_id: u32, program: &Program, role: &str) -> Result<Self> {
+        let prepared = Self {
+            sandbox: Sandbox::new(box_id)?,
+            program: program.clone(),
+        };
+
+        write_source(
+            &prepared.sandbox.boxdir,
+            program.source_code.as_bytes(),
+            &program.language,
+        )
+        .await?;
+        let compiled = prepared.sandbox.compile(&program.language, None).await?;
+        if !compiled.success {
+            prepared.cleanup().await?;
+            return Err(anyhow!(
+                "{} failed to compile: {}",
+                role,
+                compiled.output.unwrap_or_default()
+            ));
+        }
+
+        Ok(prepared)
+    }
+
+    /// Runs the program and returns its exit code. Time limits, signals and
+    /// sandbox failures are reported as errors since jury programs must not fail.
+    pub async fn run(&self, args: &str, stdin: Option<&Path>) -> Result<i32> {
+        self.sandbox
+            .run_tool(&self.program.language, args, stdin)
+            .await?;
+        self.exit_code().await
+    }
+
+    /// Starts the program with its stdin and stdout piped to the caller. Once
+    /// it exited, [`CompiledProgram::exit_code`] tells how.
+    pub fn spawn(&self, args: &str) -> Result<Child> {
+        self.sandbox.spawn_tool(&self.program.language, args)
+    }
+
+    /// Exit code of the last run, failing like [`CompiledProgram::run`].
+    pub async fn exit_code(&self) -> Result<i32> {
+        let metadata = self.sandbox.read_metadata().await?;
+        exit_code(&metadata)
+    }
+
+    /// Standard output of the last run.
+    pub async fn output(&self) -> String {
+        tokio::fs::read_to_string(&self.sandbox.stdout_file)
+            .await
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default()
+    }
+
+    /// Standard error of the last run, which is where testlib reports go.
+    pub async fn report(&self) -> String {
+        tokio::fs::read_to_string(&self.sandbox.stderr_file)
+            .await
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default()
+    }
+
+    pub async fn cleanup(&self) -> Result<()> {
+        self.sandbox.cleanup().await
+    }
+}
+
+fn exit_code(metadata: &HashMap<String, String>) -> Result<i32> {
+    match metadata.get("status").map(|s| s.as_str()) {
+        None | Some("RE") => Ok(metadata
+            .get("exitcode")
+            .and_then(|s| s.parse::<i32>().ok())
+            .unwrap_or(0)),
+        Some(status) => Err(anyhow!("jury program did not finish: {}", status)),
+    }
+}
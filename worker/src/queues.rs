@@ -0,0 +1,155 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use anyhow::Result;
+use common::{
+    model::{NatsSubmission, SubmissionPriority},
+    nats::{NatsClient, NatsConsumer, WORKER_TTL, submission_stream, submission_subject},
+};
+use tokio::time::interval;
+
+use crate::AppState;
+use crate::config::PriorityWeights;
+
+/// The submission queues of every priority in the languages this host runs,
+/// shared by its workers.
+pub struct PriorityQueues {
+    /// Per priority, highest first, one consumer per language.
+    consumers: Vec<Vec<NatsConsumer>>,
+    /// Index into `consumers` that each pull starts at, taken in turn.
+    schedule: Vec<usize>,
+    turn: AtomicUsize,
+}
+
+impl PriorityQueues {
+    pub async fn new(
+        nats: &NatsClient,
+        languages: &[String],
+        weights: &PriorityWeights,
+    ) -> Result<Self> {
+        let mut consumers = Vec::with_capacity(SubmissionPriority::ALL.len());
+        let mut schedule = Vec::new();
+        for (index, priority) in SubmissionPriority::ALL.into_iter().enumerate() {
+            // Earlier versions took every language through one durable, which
+            // would overlap with the ones per language.
+            let stale = format!("submissions-{}", priority.as_str());
+            nats.delete_consumer(&submission_stream(priority), &stale)
+                .await?;
+            let mut per_language = Vec::with_capacity(languages.len());
+            for language in languages {
+                // Shared by every host running the language.
+                let durable = format!("submissions-{}-{}", priority.as_str(), language);
+                let consumer = NatsConsumer::new(
+                    nats,
+                    &submission_stream(priority),
+                    &submission_subject(priority, "*"),
+                    &submission_subject(priority, language),
+                    &durable,
+                )
+                .await?;
+                per_language.push(consumer);
+            }
+            consumers.push(per_language);
+            schedule.extend(std::iter::repeat_n(index, weights.get(priority) as usize));
+        }
+        if schedule.is_empty() {
+            schedule.push(0);
+        }
+
+        Ok(Self {
+            consumers,
+            schedule,
+            turn: AtomicUsize::new(0),
+        })
+    }
+
+    /// Takes the next waiting submission, without blocking. Each pull starts at
+    /// the priority its turn in the weighted schedule names, so lower
+    /// priorities get their share while higher ones are busy, and falls back
+    /// to the others, highest first, when that queue is empty.
+    pub async fn pull(&self) -> Result<Option<Vec<u8>>> {
+        let turn = self.turn.fetch_add(1, Ordering::Relaxed);
+        let first = self.schedule[turn % self.schedule.len()];
+        if let Some(payload) = self.pull_priority(first, turn).await? {
+            return Ok(Some(payload));
+        }
+        for index in 0..self.consumers.len() {
+            if index == first {
+                continue;
+            }
+            if let Some(payload) = self.pull_priority(index, turn).await? {
+                return Ok(Some(payload));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Takes a submission of the priority at `index`, trying the languages
+    /// from a different one each turn so that none is starved.
+    async fn pull_priority(&self, index: usize, turn: usize) -> Result<Option<Vec<u8>>> {
+        let consumers = &self.consumers[index];
+        for offset in 0..consumers.len() {
+            let consumer = &consumers[(turn + offset) % consumers.len()];
+            if let Some(payload) = consumer.pull().await? {
+                return Ok(Some(payload));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Streams and durables of earlier versions, which queued every submission on
+/// one subject, with the priority their submissions move to.
+const LEGACY_QUEUES: [(&str, &str, SubmissionPriority); 2] = [
+    ("SUBMISSIONS", "submissions", SubmissionPriority::Normal),
+    ("REJUDGES", "rejudges", SubmissionPriority::Low),
+];
+
+/// Moves the submissions still waiting in the queues of earlier versions to
+/// the subjects of their priority and language, and deletes those queues once
+/// empty. Any host may do it, the others then find nothing left.
+pub async fn drain_legacy_queues(nats: NatsClient) {
+    for (stream, durable, priority) in LEGACY_QUEUES {
+        let consumer = match NatsConsumer::existing(&nats, stream, durable).await {
+            Ok(Some(consumer)) => consumer,
+            Ok(None) => continue,
+            Err(err) => {
+                eprintln!("❌ Could not open legacy queue {stream}: {err}");
+                continue;
+            }
+        };
+        let subject = |payload: &[u8]| match serde_json::from_slice::<NatsSubmission>(payload) {
+            Ok(sub) => Some(submission_subject(priority, &sub.language.name)),
+            Err(err) => {
+                eprintln!("❌ Dropping unreadable message of legacy queue {stream}: {err}");
+                None
+            }
+        };
+        match consumer.forward(&nats, subject).await {
+            Ok(moved) => {
+                println!("📦 Moved {moved} submissions out of legacy queue {stream}");
+                if let Err(err) = nats.delete_stream(stream).await {
+                    eprintln!("❌ Could not delete legacy queue {stream}: {err}");
+                }
+            }
+            Err(err) => eprintln!("❌ Could not drain legacy queue {stream}: {err}"),
+        }
+    }
+}
+
+/// Renews, well within [`WORKER_TTL`], the advertisement of the languages this
+/// host runs, by which the API tells whether a language has a live worker.
+pub async fn advertise_languages(app_state: Arc<AppState>) {
+    let mut ticks = interval(WORKER_TTL / 3);
+    loop {
+        ticks.tick().await;
+        let config = &app_state.config;
+        if let Err(err) = app_state
+            .nats
+            .advertise(&config.worker_id, &config.languages)
+            .await
+        {
+            eprintln!("❌ Could not advertise languages: {err}");
+        }
+    }
+}
@@ -0,0 +1,229 @@
+use anyhow::{Result, anyhow};
+use async_nats::Subscriber;
+use common::model::{NatsSubmission, SessionInput, SessionOutput, SubmissionStatus};
+use common::nats::{NatsClient, session_request_subject, session_subject};
+use futures::{StreamExt, stream};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::sync::{Notify, Semaphore};
+use tokio::task::JoinHandle;
+
+use crate::AppState;
+use crate::isolate::Sandbox;
+use crate::workers::{JudgeStatus, run_status, write_source};
+
+/// Queue group shared by all workers so that each session starts only once.
+const SESSION_GROUP: &str = "workers";
+
+/// Accepts interactive session requests in the languages this host runs and
+/// runs each in its own sandbox, up to `max_sessions` at a time.
+pub async fn serve_sessions(app_state: Arc<AppState>) -> Result<()> {
+    let slots = Arc::new(Semaphore::new(app_state.config.max_sessions));
+    let mut subscribers = Vec::with_capacity(app_state.config.languages.len());
+    for language in &app_state.config.languages {
+        let subscriber = app_state
+            .nats
+            .queue_subscribe(session_request_subject(language), SESSION_GROUP.to_string())
+            .await?;
+        subscribers.push(subscriber);
+    }
+    let mut requests = stream::select_all(subscribers);
+
+    while let Some(request) = requests.next().await {
+        let Ok(sub) = serde_json::from_slice::<NatsSubmission>(&request.payload) else {
+            continue;
+        };
+        let output = session_subject(&sub.id, "output");
+
+        let Ok(slot) = slots.clone().try_acquire_owned() else {
+            let busy = SessionOutput::Error {
+                message: "no free session slot".to_string(),
+            };
+            send(&app_state.nats, &output, &busy).await;
+            continue;
+        };
+
+        let state = app_state.clone();
+        tokio::spawn(async move {
+            if let Err(err) = run_session(&state, &sub, &output).await {
+                let error = SessionOutput::Error {
+                    message: err.to_string(),
+                };
+                send(&state.nats, &output, &error).await;
+            }
+            drop(slot);
+        });
+    }
+
+    Ok(())
+}
+
+async fn run_session(app_state: &AppState, sub: &NatsSubmission, output: &str) -> Result<()> {
+    // Subscribed before the first reply so that no input sent after it is lost.
+    let mut input = app_state
+        .nats
+        .subscribe(session_subject(&sub.id, "input"))
+        .await?;
+
+    let box_id = app_state.box_counter.get_next_id().await;
+    let iso = Sandbox::new(box_id)?;
+    let result = relay(app_state, &iso, sub, &mut input, output).await;
+    iso.cleanup().await?;
+    result
+}
+
+async fn relay(
+    app_state: &AppState,
+    iso: &Sandbox,
+    sub: &NatsSubmission,
+    input: &mut Subscriber,
+    output: &str,
+) -> Result<()> {
+    let nats = &app_state.nats;
+
+    write_source(&iso.boxdir, &sub.source_code, &sub.language).await?;
+    let compiled = iso
+        .compile(&sub.language, sub.compiler_options.as_deref())
+        .await?;
+    let success = compiled.success;
+    let message = SessionOutput::Compiled {
+        success,
+        output: compiled.output,
+    };
+    send(nats, output, &message).await;
+    if !success {
+        return Ok(());
+    }
+
+    let mut child = iso.spawn_interactive(sub)?;
+    let mut stdin = child.stdin.take();
+    let stdout = child.stdout.take().ok_or(anyhow!("stdout not piped"))?;
+    let stderr = child.stderr.take().ok_or(anyhow!("stderr not piped"))?;
+    let budget = Arc::new(OutputBudget {
+        left: AtomicU64::new(sub.session_output_limit.unwrap_or(u64::MAX)),
+        exceeded: Notify::new(),
+    });
+    let pumps = [
+        pump(
+            nats.clone(),
+            output.to_string(),
+            stdout,
+            false,
+            budget.clone(),
+        ),
+        pump(
+            nats.clone(),
+            output.to_string(),
+            stderr,
+            true,
+            budget.clone(),
+        ),
+    ];
+
+    // The isolate wall time limit bounds how long this loop can run.
+    let mut exceeded = false;
+    loop {
+        tokio::select! {
+            _ = child.wait() => break,
+            _ = budget.exceeded.notified() => {
+                iso.kill().await?;
+                child.wait().await?;
+                exceeded = true;
+                break;
+            }
+            message = input.next() => {
+                let Some(message) = message else {
+                    // Nobody can talk to the program any more.
+                    iso.kill().await?;
+                    child.wait().await?;
+                    break;
+                };
+                match serde_json::from_slice(&message.payload) {
+                    Ok(SessionInput::Stdin { data }) => {
+                        if let Some(pipe) = stdin.as_mut() {
+                            // The program may already have closed its stdin.
+                            let _ = pipe.write_all(data.as_bytes()).await;
+                            let _ = pipe.flush().await;
+                        }
+                    }
+                    Ok(SessionInput::Eof) => stdin = None,
+                    Ok(SessionInput::Kill) => iso.kill().await?,
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+
+    for pump in pumps {
+        pump.await?;
+    }
+    if exceeded {
+        let error = SessionOutput::Error {
+            message: "output limit exceeded".to_string(),
+        };
+        send(nats, output, &error).await;
+        return Ok(());
+    }
+
+    let metadata = iso.read_metadata().await?;
+    let status: SubmissionStatus = run_status(&metadata)
+        .unwrap_or(JudgeStatus::Accepted)
+        .into();
+    send(nats, output, &SessionOutput::Exit { status, metadata }).await;
+    Ok(())
+}
+
+/// Bytes of output a session may still send, shared by its pipes.
+struct OutputBudget {
+    left: AtomicU64,
+    /// Notified once a pipe wrote more than is left.
+    exceeded: Notify,
+}
+
+impl OutputBudget {
+    /// Takes `n` bytes, or reports that they exceed what is left.
+    fn take(&self, n: u64) -> bool {
+        self.left
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |left| {
+                left.checked_sub(n)
+            })
+            .is_ok()
+    }
+}
+
+/// Forwards a pipe of the program to the session output until it closes or
+/// the output limit is reached.
+fn pump(
+    nats: NatsClient,
+    subject: String,
+    mut pipe: impl AsyncRead + Unpin + Send + 'static,
+    is_stderr: bool,
+    budget: Arc<OutputBudget>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut buf = vec![0u8; 4096];
+        while let Ok(n) = pipe.read(&mut buf).await {
+            if n == 0 {
+                break;
+            }
+            if !budget.take(n as u64) {
+                budget.exceeded.notify_one();
+                break;
+            }
+            let data = String::from_utf8_lossy(&buf[..n]).into_owned();
+            let message = if is_stderr {
+                SessionOutput::Stderr { data }
+            } else {
+                SessionOutput::Stdout { data }
+            };
+            send(&nats, &subject, &message).await;
+        }
+    })
+}
+
+async fn send(nats: &NatsClient, subject: &str, message: &SessionOutput) {
+    if let Ok(data) = serde_json::to_vec(message) {
+        let _ = nats.notify(subject.to_string(), data).await;
+    }
+}
@@ -0,0 +1,101 @@
+use anyhow::{Result, anyhow};
+use common::model::{NatsSubmission, StressResult, StressTest, SubmissionStatus};
+
+use crate::AppState;
+use crate::isolate::Sandbox;
+use crate::judge::{Jury, Test, Verdict, run_test};
+use crate::program::CompiledProgram;
+
+/// Runs the submission and the reference solution on generated inputs with
+/// increasing seeds until their outputs disagree or the iterations run out.
+pub async fn stress_test(
+    app_state: &AppState,
+    iso: &Sandbox,
+    sub: &NatsSubmission,
+    stress: &StressTest,
+    jury: &mut Jury<'_>,
+    verdict: &mut Verdict,
+) -> Result<()> {
+    let box_id = app_state.box_counter.get_next_id().await;
+    let generator = CompiledProgram::prepare(box_id, &stress.generator, "generator").await?;
+
+    let box_id = app_state.box_counter.get_next_id().await;
+    let reference =
+        match CompiledProgram::prepare(box_id, &stress.reference, "reference solution").await {
+            Ok(reference) => reference,
+            Err(err) => {
+                generator.cleanup().await?;
+                return Err(err);
+            }
+        };
+
+    let result = run_seeds(iso, sub, stress, jury, &generator, &reference, verdict).await;
+    generator.cleanup().await?;
+    reference.cleanup().await?;
+
+    verdict.stress_result = Some(result?);
+    Ok(())
+}
+
+async fn run_seeds(
+    iso: &Sandbox,
+    sub: &NatsSubmission,
+    stress: &StressTest,
+    jury: &mut Jury<'_>,
+    generator: &CompiledProgram,
+    reference: &CompiledProgram,
+    verdict: &mut Verdict,
+) -> Result<StressResult> {
+    for seed in 1..=stress.iterations {
+        let code = generator.run(&seed.to_string(), None).await?;
+        if code != 0 {
+            return Err(anyhow!(
+                "generator failed on seed {} with exit code {}: {}",
+                seed,
+                code,
+                generator.report().await
+            ));
+        }
+        let input = tokio::fs::read(&generator.sandbox.stdout_file).await?;
+
+        let code = reference
+            .run("", Some(&generator.sandbox.stdout_file))
+            .await?;
+        if code != 0 {
+            return Err(anyhow!(
+                "reference solution failed on seed {} with exit code {}: {}",
+                seed,
+                code,
+                reference.report().await
+            ));
+        }
+        let expected = tokio::fs::read(&reference.sandbox.stdout_file).await?;
+
+        let test = Test::Inline {
+            input: &input,
+            answer: Some(&expected),
+        };
+        let run = run_test(iso, sub, jury, &test).await?;
+
+        if run.status != SubmissionStatus::Accepted {
+            let actual_output = run.output.stdout_text();
+            verdict.record(run);
+            return Ok(StressResult {
+                iterations_run: seed,
+                seed: Some(seed),
+                input: Some(String::from_utf8_lossy(&input).into_owned()),
+                expected_output: Some(String::from_utf8_lossy(&expected).into_owned()),
+                actual_output,
+            });
+        }
+        verdict.record(run);
+    }
+
+    Ok(StressResult {
+        iterations_run: stress.iterations,
+        seed: None,
+        input: None,
+        expected_output: None,
+        actual_output: None,
+    })
+}
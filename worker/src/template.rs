@@ -0,0 +1,49 @@
+use common::model::Template;
+use regex::{Captures, Regex};
+
+/// A function-style submission wrapped in its problem's driver code.
+pub struct Spliced {
+    pub source_code: String,
+    /// Number of template lines before the first line of user code.
+    offset: usize,
+    /// Number of lines of user code.
+    lines: usize,
+}
+
+/// Replaces the placeholders of `template` with the user code and harness.
+pub fn splice(template: &Template, user_code: &str) -> Spliced {
+    let harness = template.harness.as_deref().unwrap_or_default();
+    let (before, after) = template
+        .template
+        .split_once(Template::USER_CODE)
+        .unwrap_or((&template.template, ""));
+    let before = before.replace(Template::HARNESS, harness);
+    let after = after.replace(Template::HARNESS, harness);
+
+    Spliced {
+        offset: before.matches('\n').count(),
+        lines: user_code.lines().count().max(1),
+        source_code: format!("{}{}{}", before, user_code, after),
+    }
+}
+
+impl Spliced {
+    /// Rewrites `<source_file>:<line>` (gcc, javac) and `"<source_file>", line
+    /// <line>` (python) references so that they point into the user's code.
+    /// References to template or harness lines are left untouched.
+    pub fn remap_lines(&self, output: &str, source_file: &str) -> String {
+        let file = regex::escape(source_file);
+        let pattern = Regex::new(&format!(r#"({file}:|{file}", line )(\d+)"#)).unwrap();
+
+        pattern
+            .replace_all(output, |caps: &Captures| {
+                let line: usize = caps[2].parse().unwrap_or(0);
+                if line > self.offset && line <= self.offset + self.lines {
+                    format!("{}{}", &caps[1], line - self.offset)
+                } else {
+                    caps[0].to_string()
+                }
+            })
+            .into_owned()
+    }
+}
@@ -1,14 +1,20 @@
 use anyhow::Result;
-use common::model::{Language, NatsSubmission};
-use common::nats::NatsConsumer;
+use common::model::{Comparator, Language, NatsSubmission, SubmissionStage, SubmissionStatus};
+use common::nats::done_subject;
 use std::collections::HashMap;
 use std::path::Path;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use tokio::task;
-use tokio::time::{Duration, sleep};
+use tokio::time::{Duration, interval, sleep};
+use tokio_postgres::types::{Json, ToSql};
+use uuid::Uuid;
 
 use crate::AppState;
+use crate::attempts::{finish_attempt, start_attempt, touch_attempt};
+use crate::events::Progress;
 use crate::isolate::{ProgramOutput, Sandbox};
+use crate::judge::{Verdict, judge_submission};
 
 pub async fn spawn_workers(app_state: Arc<AppState>, worker_count: usize) -> Result<()> {
     let mut handles = Vec::with_capacity(worker_count);
@@ -30,10 +36,12 @@ pub async fn spawn_workers(app_state: Arc<AppState>, worker_count: usize) -> Res
 
 async fn worker_loop(app_state: Arc<AppState>, id: usize) -> Result<()> {
     loop {
-        match app_state.queue.pull().await {
+        match app_state.queues.pull().await {
             Ok(Some(payload)) => {
                 let submission: NatsSubmission = serde_json::from_slice(&payload)?;
-                execute_submission(app_state.clone(), submission).await?;
+                if let Err(err) = execute_submission(app_state.clone(), submission, id).await {
+                    eprintln!("❌ Worker #{id} failed to execute submission: {err}");
+                }
             }
             Ok(None) => {
                 println!("🧵 Worker #{id} no job yet");
@@ -48,46 +56,165 @@ async fn worker_loop(app_state: Arc<AppState>, id: usize) -> Result<()> {
 }
 
 /// Writes the source code to the appropriate file inside the box directory.
-pub async fn write_source(boxdir: &Path, source_code: &str, lang: &Language) -> Result<()> {
+pub async fn write_source(boxdir: &Path, source_code: &[u8], lang: &Language) -> Result<()> {
     let path = boxdir.join(&lang.source_file);
     tokio::fs::write(&path, source_code).await?;
     Ok(())
 }
 
-/// Writes stdin content to `stdin.txt` in the workdir.
-pub async fn write_stdin(workdir: &Path, stdin: &Option<String>) -> Result<()> {
-    if let Some(input) = stdin {
-        let path = workdir.join("stdin.txt");
-        tokio::fs::write(&path, input).await?;
-    }
+/// Writes stdin content to `stdin.txt` in the workdir. The file is always
+/// created so that tests without input still get an empty stdin.
+pub async fn write_stdin(workdir: &Path, stdin: &[u8]) -> Result<()> {
+    let path = workdir.join("stdin.txt");
+    tokio::fs::write(&path, stdin).await?;
     Ok(())
 }
 
-pub async fn execute_submission(app_state: Arc<AppState>, sub: NatsSubmission) -> Result<()> {
+/// Judges a queued submission on worker task `worker` and stores the verdict.
+pub async fn execute_submission(
+    app_state: Arc<AppState>,
+    sub: NatsSubmission,
+    worker: usize,
+) -> Result<()> {
+    let submission_id = Uuid::parse_str(&sub.id)?;
+    // Watched from before the claim so that no cancellation goes unseen.
+    let cancelled = app_state.nats.cancelled(&sub.id).await?;
+    let claimed = app_state
+        .db
+        .execute(
+            "UPDATE submissions SET status = $2
+            WHERE id = $1 AND redacted_at IS NULL AND status = 'inqueue'",
+            &[&submission_id, &SubmissionStatus::Processing],
+        )
+        .await?;
+    if claimed == 0 {
+        // Deleted, redacted or cancelled while it was queued, or a duplicate
+        // message of a submission that another delivery already claimed. The
+        // message is acknowledged already, so it is dropped.
+        return Ok(());
+    }
+
+    let progress = Progress::new(&app_state.nats, &sub.id);
+    progress.stage(SubmissionStage::Processing).await;
+
     let id = app_state.box_counter.get_next_id().await;
-    let iso = Sandbox::new(id)?;
-    write_source(&iso.boxdir, &sub.source_code, &sub.language).await?;
-    write_stdin(&iso.workdir, &sub.stdin).await?;
-
-    if sub.language.compile_cmd.is_some() {
-        if !iso.compile(&sub).await? {
-            iso.cleanup().await?;
-            return Ok(());
+    let worker_id = format!("{}#{}", app_state.config.worker_id, worker);
+    let attempt = start_attempt(&app_state, submission_id, &worker_id, id).await?;
+    let verdict = match Sandbox::new(id) {
+        Ok(iso) => {
+            let cancel_requested = AtomicBool::new(false);
+            let judged = judge_submission(&app_state, &iso, &sub, &progress, &cancel_requested);
+            tokio::pin!(judged);
+            tokio::pin!(cancelled);
+            let mut heartbeat = interval(Duration::from_secs(
+                app_state.config.heartbeat_interval.max(1),
+            ));
+            // On cancellation the running program is killed and the judge is
+            // left to stop before the next test and clean up after itself.
+            let verdict = loop {
+                tokio::select! {
+                    verdict = &mut judged => break verdict,
+                    _ = &mut cancelled, if !cancel_requested.load(Ordering::Relaxed) => {
+                        cancel_requested.store(true, Ordering::Relaxed);
+                        // The cancellation is recorded even if the program
+                        // could not be killed and runs to its limits.
+                        if let Err(err) = iso.kill().await {
+                            eprintln!("❌ Worker #{worker} could not kill cancelled program: {err}");
+                        }
+                    }
+                    _ = heartbeat.tick() => {
+                        if let Err(err) = touch_attempt(&app_state, attempt).await {
+                            eprintln!("❌ Worker #{worker} could not renew heartbeat: {err}");
+                        }
+                    }
+                }
+            };
+            let verdict = if cancel_requested.load(Ordering::Relaxed) {
+                Verdict::new(SubmissionStatus::Cancelled)
+            } else {
+                verdict.unwrap_or_else(Verdict::internal_error)
+            };
+            // The verdict stands even if the box could not be cleaned up.
+            if let Err(err) = iso.cleanup().await {
+                eprintln!("❌ Worker #{worker} could not clean up box: {err}");
+            }
+            verdict
         }
-    }
+        Err(err) => Verdict::internal_error(err),
+    };
 
-    iso.run(&sub).await?;
-    let metadata = iso.read_metadata().await?;
-    let output = iso.read_output().await?;
+    let saved = save_verdict(&app_state, submission_id, attempt, &verdict).await?;
+    finish_attempt(&app_state, attempt, &verdict).await?;
+    if !saved {
+        // Redacted, or taken over by a later attempt after this one was given
+        // up, which reports its own verdict.
+        return Ok(());
+    }
+    progress.finished(verdict.status.clone()).await;
 
-    let status = determine_status(&sub, &output, &metadata);
+    let status = serde_json::to_vec(&verdict.status)?;
+    app_state.nats.notify(done_subject(&sub.id), status).await
+}
 
-    println!("{:?}", status);
-    println!("{:?}", output);
-    println!("{:?}", metadata);
+/// Stores the verdict of `attempt` as that of the submission and reports
+/// whether it did, which it does not once the attempt was given up.
+async fn save_verdict(
+    app_state: &AppState,
+    id: Uuid,
+    attempt: i64,
+    verdict: &Verdict,
+) -> Result<bool> {
+    let group_results = verdict.group_results.as_ref().map(Json);
+    let stress_result = verdict.stress_result.as_ref().map(Json);
+    let compile_output = verdict.compile_output.as_deref().map(str::as_bytes);
+    let params: &[&(dyn ToSql + Sync)] = &[
+        &id,
+        &verdict.status,
+        &verdict.stdout,
+        &verdict.stderr,
+        &compile_output,
+        &verdict.message,
+        &verdict.exit_code,
+        &verdict.exit_signal,
+        &verdict.time,
+        &verdict.wall_time,
+        &verdict.memory,
+        &verdict.score,
+        &verdict.max_score,
+        &group_results,
+        &stress_result,
+        &attempt,
+    ];
 
-    iso.cleanup().await?;
-    Ok(())
+    let saved = app_state
+        .db
+        .execute(
+            r#"
+        UPDATE submissions SET
+            status = $2,
+            stdout = $3,
+            stderr = $4,
+            compile_output = $5,
+            message = $6,
+            exit_code = $7,
+            exit_signal = $8,
+            time = $9,
+            wall_time = $10,
+            memory = $11,
+            score = $12,
+            max_score = $13,
+            group_results = $14,
+            stress_result = $15,
+            finished_at = now()
+        WHERE id = $1 AND redacted_at IS NULL AND status = 'processing'
+        AND EXISTS (
+            SELECT 1 FROM submission_attempts WHERE id = $16 AND finished_at IS NULL
+        )
+        "#,
+            params,
+        )
+        .await?;
+    Ok(saved > 0)
 }
 
 #[derive(Debug)]
@@ -100,30 +227,54 @@ pub enum JudgeStatus {
     BoxError,
 }
 
+impl From<JudgeStatus> for SubmissionStatus {
+    fn from(status: JudgeStatus) -> Self {
+        match status {
+            JudgeStatus::Accepted => SubmissionStatus::Accepted,
+            JudgeStatus::WrongAnswer => SubmissionStatus::WrongAnswer,
+            JudgeStatus::TimeLimitExceeded => SubmissionStatus::TimeLimitExceeded,
+            JudgeStatus::RuntimeError => SubmissionStatus::RuntimeErrorNzec,
+            JudgeStatus::SignalError(11) => SubmissionStatus::RuntimeErrorSigsegv,
+            JudgeStatus::SignalError(25) => SubmissionStatus::RuntimeErrorSigxfsz,
+            JudgeStatus::SignalError(8) => SubmissionStatus::RuntimeErrorSigfpe,
+            JudgeStatus::SignalError(6) => SubmissionStatus::RuntimeErrorSigabrt,
+            JudgeStatus::SignalError(_) => SubmissionStatus::RuntimeErrorOther,
+            JudgeStatus::BoxError => SubmissionStatus::InternalError,
+        }
+    }
+}
+
 pub fn determine_status(
-    sub: &NatsSubmission,
+    comparator: Comparator,
+    expected_output: Option<&str>,
     output: &ProgramOutput,
     meta: &HashMap<String, String>,
 ) -> JudgeStatus {
+    run_status(meta).unwrap_or_else(|| {
+        let expected = expected_output.unwrap_or("");
+        let actual = output.stdout_text().unwrap_or_default();
+        if comparator.matches(expected, &actual) {
+            JudgeStatus::Accepted
+        } else {
+            JudgeStatus::WrongAnswer
+        }
+    })
+}
+
+/// Status of a run from its isolate metadata alone; `None` when the program
+/// exited normally and its output decides.
+pub fn run_status(meta: &HashMap<String, String>) -> Option<JudgeStatus> {
     match meta.get("status").map(|s| s.as_str()) {
-        Some("TO") => JudgeStatus::TimeLimitExceeded,
+        Some("TO") => Some(JudgeStatus::TimeLimitExceeded),
         Some("SG") => {
             let sig = meta
                 .get("exitsig")
                 .and_then(|s| s.parse().ok())
                 .unwrap_or(0);
-            JudgeStatus::SignalError(sig)
-        }
-        Some("RE") => JudgeStatus::RuntimeError,
-        Some("XX") => JudgeStatus::BoxError,
-        _ => {
-            let expected = sub.expected_output.as_deref().unwrap_or("").trim();
-            let actual = output.stdout.as_deref().unwrap_or("").trim();
-            if expected == actual {
-                JudgeStatus::Accepted
-            } else {
-                JudgeStatus::WrongAnswer
-            }
+            Some(JudgeStatus::SignalError(sig))
         }
+        Some("RE") => Some(JudgeStatus::RuntimeError),
+        Some("XX") => Some(JudgeStatus::BoxError),
+        _ => None,
     }
 }